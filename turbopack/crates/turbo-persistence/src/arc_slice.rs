@@ -10,7 +10,16 @@ use std::{
 #[derive(Clone)]
 pub struct ArcSlice<T> {
     data: *const [T],
-    arc: Arc<[T]>,
+    owner: Owner<T>,
+}
+
+/// The allocation that keeps the data of an `ArcSlice` alive. It's only compared and dropped.
+#[derive(Clone)]
+enum Owner<T> {
+    /// A reference counted slice on the heap.
+    Arc(Arc<[T]>),
+    /// Any other storage, e.g. a memory mapping.
+    Other(Arc<dyn Send + Sync>),
 }
 
 unsafe impl<T> Send for ArcSlice<T> {}
@@ -20,7 +29,7 @@ impl<T> From<Arc<[T]>> for ArcSlice<T> {
     fn from(arc: Arc<[T]>) -> Self {
         Self {
             data: &*arc as *const [T],
-            arc,
+            owner: Owner::Arc(arc),
         }
     }
 }
@@ -73,12 +82,38 @@ impl<T> ArcSlice<T> {
     /// The caller must ensure that the pointer is pointing to a valid slice that is kept alive by
     /// the `Arc`.
     pub unsafe fn new_unchecked(data: *const [T], arc: Arc<[T]>) -> Self {
-        Self { data, arc }
+        Self {
+            data,
+            owner: Owner::Arc(arc),
+        }
+    }
+
+    /// Creates a new `ArcSlice` that keeps the given storage alive, e.g. a memory mapping.
+    pub fn from_owner<O: Deref<Target = [T]> + Send + Sync + 'static>(owner: O) -> Self {
+        let owner = Arc::new(owner);
+        Self {
+            data: &**owner as *const [T],
+            owner: Owner::Other(owner),
+        }
     }
 
-    /// Get the backing arc
-    pub fn full_arc(this: &ArcSlice<T>) -> Arc<[T]> {
-        this.arc.clone()
+    /// Returns true if both slices are kept alive by the same allocation, e. g. small values that
+    /// were read from the same value block.
+    pub fn shares_allocation(&self, other: &ArcSlice<T>) -> bool {
+        match (&self.owner, &other.owner) {
+            (Owner::Arc(a), Owner::Arc(b)) => Arc::ptr_eq(a, b),
+            (Owner::Other(a), Owner::Other(b)) => Arc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+
+    /// Returns a weak reference to the allocation of this slice. It doesn't keep the data alive.
+    #[cfg(feature = "leak_check")]
+    pub(crate) fn downgrade(&self) -> WeakArcSlice<T> {
+        WeakArcSlice(match &self.owner {
+            Owner::Arc(arc) => WeakOwner::Arc(Arc::downgrade(arc)),
+            Owner::Other(other) => WeakOwner::Other(Arc::downgrade(other)),
+        })
     }
 
     /// Returns a new `ArcSlice` that points to a slice of the current slice.
@@ -87,7 +122,28 @@ impl<T> ArcSlice<T> {
         let data = &data[range] as *const [T];
         Self {
             data,
-            arc: self.arc,
+            owner: self.owner,
+        }
+    }
+}
+
+/// A weak reference to the allocation of an `ArcSlice`, see [ArcSlice::downgrade].
+#[cfg(feature = "leak_check")]
+pub(crate) struct WeakArcSlice<T>(WeakOwner<T>);
+
+#[cfg(feature = "leak_check")]
+enum WeakOwner<T> {
+    Arc(std::sync::Weak<[T]>),
+    Other(std::sync::Weak<dyn Send + Sync>),
+}
+
+#[cfg(feature = "leak_check")]
+impl<T> WeakArcSlice<T> {
+    /// Returns the number of `ArcSlice`s that keep the allocation alive.
+    pub(crate) fn strong_count(&self) -> usize {
+        match &self.0 {
+            WeakOwner::Arc(weak) => weak.strong_count(),
+            WeakOwner::Other(weak) => weak.strong_count(),
         }
     }
 }
@@ -0,0 +1,206 @@
+use std::{
+    io::{self, Read},
+    ops::Deref,
+};
+
+use crate::static_sorted_file::StaticSortedFileError;
+
+/// The maximum match offset of LZ4. This much decompressed data is kept as history.
+const WINDOW_SIZE: usize = 64 * 1024;
+/// The number of bytes that are decompressed at once.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The position in the LZ4 sequence that is decoded next.
+enum State {
+    /// A token that starts a new sequence.
+    Token,
+    /// Literals of the current sequence. The token is kept for the match length.
+    Literals { remaining: usize, token: u8 },
+    /// The match of the current sequence.
+    Match { offset: usize, remaining: usize },
+}
+
+/// A streaming decompressor for a LZ4 compressed block. Only the last 64 KiB of decompressed data
+/// are kept in memory, since LZ4 matches can't reference data that is further back.
+pub(crate) struct BlockReader<I, D> {
+    block_index: u16,
+    /// The compressed block.
+    input: I,
+    /// The position of the next byte in `input`.
+    position: usize,
+    dictionary: D,
+    /// Decompressed data. Contains the history and the bytes that were not read yet.
+    buffer: Vec<u8>,
+    /// The position of the next unread byte in `buffer`.
+    read_position: usize,
+    /// The number of decompressed bytes that were removed from the start of `buffer`.
+    discarded: usize,
+    uncompressed_length: usize,
+    state: State,
+}
+
+impl<I: Deref<Target = [u8]>, D: Deref<Target = [u8]>> BlockReader<I, D> {
+    pub(crate) fn new(
+        block_index: u16,
+        input: I,
+        position: usize,
+        uncompressed_length: usize,
+        dictionary: D,
+    ) -> Self {
+        Self {
+            block_index,
+            input,
+            position,
+            dictionary,
+            buffer: Vec::new(),
+            read_position: 0,
+            discarded: 0,
+            uncompressed_length,
+            state: State::Token,
+        }
+    }
+
+    fn corrupt(&self) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Corrupted LZ4 data in block {}", self.block_index),
+        )
+    }
+
+    fn read_byte(&mut self) -> io::Result<u8> {
+        let Some(&byte) = self.input.get(self.position) else {
+            return Err(self.corrupt());
+        };
+        self.position += 1;
+        Ok(byte)
+    }
+
+    /// Reads a length that starts with a 4 bit value and continues with extra bytes if it's 15.
+    fn read_length(&mut self, nibble: u8) -> io::Result<usize> {
+        let mut length = nibble as usize;
+        if nibble == 15 {
+            loop {
+                let byte = self.read_byte()?;
+                length += byte as usize;
+                if byte != 255 {
+                    break;
+                }
+            }
+        }
+        Ok(length)
+    }
+
+    /// The number of bytes that were decompressed so far.
+    fn decompressed(&self) -> usize {
+        self.discarded + self.buffer.len()
+    }
+
+    /// Decompresses the next chunk into `buffer`. Returns `false` at the end of the block.
+    fn fill(&mut self) -> io::Result<bool> {
+        if self.read_position > 2 * WINDOW_SIZE {
+            let drained = self.read_position - WINDOW_SIZE;
+            self.buffer.drain(..drained);
+            self.discarded += drained;
+            self.read_position = WINDOW_SIZE;
+        }
+        let start = self.buffer.len();
+        while self.buffer.len() - start < CHUNK_SIZE {
+            match self.state {
+                State::Token => {
+                    if self.position == self.input.len() {
+                        break;
+                    }
+                    let token = self.read_byte()?;
+                    let remaining = self.read_length(token >> 4)?;
+                    self.state = State::Literals { remaining, token };
+                }
+                State::Literals { remaining, token } => {
+                    let len = remaining.min(CHUNK_SIZE);
+                    let Some(literals) = self.input.get(self.position..self.position + len) else {
+                        return Err(self.corrupt());
+                    };
+                    self.buffer.extend_from_slice(literals);
+                    self.position += len;
+                    self.state = if remaining > len {
+                        State::Literals {
+                            remaining: remaining - len,
+                            token,
+                        }
+                    } else if self.position == self.input.len() {
+                        // The last sequence has no match.
+                        State::Token
+                    } else {
+                        let offset =
+                            u16::from_le_bytes([self.read_byte()?, self.read_byte()?]) as usize;
+                        if offset == 0 || offset > self.decompressed() + self.dictionary.len() {
+                            return Err(self.corrupt());
+                        }
+                        State::Match {
+                            offset,
+                            remaining: self.read_length(token & 15)? + 4,
+                        }
+                    };
+                }
+                State::Match { offset, remaining } => {
+                    let len = remaining.min(CHUNK_SIZE);
+                    let mut copied = 0;
+                    while copied < len {
+                        let decompressed = self.decompressed();
+                        if offset > decompressed {
+                            // The match starts in the dictionary.
+                            let start = self.dictionary.len() - (offset - decompressed);
+                            let count = (len - copied).min(offset - decompressed);
+                            self.buffer
+                                .extend_from_slice(&self.dictionary[start..start + count]);
+                            copied += count;
+                        } else {
+                            // Copy at most `offset` bytes at once, since the match can overlap
+                            // with the bytes it produces.
+                            let start = self.buffer.len() - offset;
+                            let count = (len - copied).min(offset);
+                            self.buffer.extend_from_within(start..start + count);
+                            copied += count;
+                        }
+                    }
+                    self.state = if remaining > len {
+                        State::Match {
+                            offset,
+                            remaining: remaining - len,
+                        }
+                    } else {
+                        State::Token
+                    };
+                }
+            }
+            if self.decompressed() > self.uncompressed_length {
+                return Err(self.corrupt());
+            }
+        }
+        if self.buffer.len() > start {
+            return Ok(true);
+        }
+        if self.decompressed() != self.uncompressed_length {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                StaticSortedFileError::DecompressionLengthMismatch {
+                    expected: self.uncompressed_length,
+                    actual: self.decompressed(),
+                },
+            ));
+        }
+        Ok(false)
+    }
+}
+
+impl<I: Deref<Target = [u8]>, D: Deref<Target = [u8]>> Read for BlockReader<I, D> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.read_position == self.buffer.len() && !self.fill()? {
+            return Ok(0);
+        }
+        let available = &self.buffer[self.read_position..];
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.read_position += len;
+        Ok(len)
+    }
+}
@@ -36,7 +36,7 @@ impl<K: StoreKey> Collector<K> {
     }
 
     /// Adds a normal key-value pair to the collector.
-    pub fn put(&mut self, key: K, value: Vec<u8>) {
+    pub fn put(&mut self, key: K, value: Vec<u8>, write_sequence_number: Option<u32>) {
         let key = EntryKey {
             hash: hash_key(&key),
             data: key,
@@ -48,11 +48,15 @@ impl<K: StoreKey> Collector<K> {
         };
         self.total_key_size += key.len();
         self.total_value_size += value.len();
-        self.entries.push(CollectorEntry { key, value });
+        self.entries.push(CollectorEntry {
+            key,
+            value,
+            write_sequence_number,
+        });
     }
 
     /// Adds a blob key-value pair to the collector.
-    pub fn put_blob(&mut self, key: K, blob: u32) {
+    pub fn put_blob(&mut self, key: K, blob: u32, write_sequence_number: Option<u32>) {
         let key = EntryKey {
             hash: hash_key(&key),
             data: key,
@@ -61,11 +65,12 @@ impl<K: StoreKey> Collector<K> {
         self.entries.push(CollectorEntry {
             key,
             value: CollectorEntryValue::Large { blob },
+            write_sequence_number,
         });
     }
 
     /// Adds a tombstone pair to the collector.
-    pub fn delete(&mut self, key: K) {
+    pub fn delete(&mut self, key: K, write_sequence_number: Option<u32>) {
         let key = EntryKey {
             hash: hash_key(&key),
             data: key,
@@ -74,6 +79,7 @@ impl<K: StoreKey> Collector<K> {
         self.entries.push(CollectorEntry {
             key,
             value: CollectorEntryValue::Deleted,
+            write_sequence_number,
         });
     }
 
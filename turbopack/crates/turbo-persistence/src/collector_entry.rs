@@ -8,6 +8,7 @@ use crate::{
 pub struct CollectorEntry<K: StoreKey> {
     pub key: EntryKey<K>,
     pub value: CollectorEntryValue,
+    pub write_sequence_number: Option<u32>,
 }
 
 pub enum CollectorEntryValue {
@@ -82,4 +83,8 @@ impl<K: StoreKey> Entry for CollectorEntry<K> {
             CollectorEntryValue::Deleted => EntryValue::Deleted,
         }
     }
+
+    fn write_sequence_number(&self) -> Option<u32> {
+        self.write_sequence_number
+    }
 }
@@ -4,6 +4,7 @@ pub const MAX_MEDIUM_VALUE_SIZE: usize = 64 * 1024 * 1024;
 /// Values larger than this become separate value blocks
 // Note this must fit into 2 bytes length
 pub const MAX_SMALL_VALUE_SIZE: usize = 64 * 1024 - 1;
+const _: () = assert!(MAX_SMALL_VALUE_SIZE <= u16::MAX as usize);
 
 /// Maximum number of entries per SST file
 pub const MAX_ENTRIES_PER_INITIAL_FILE: usize = 1024 * 1024;
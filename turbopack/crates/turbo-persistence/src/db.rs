@@ -3,7 +3,7 @@ use std::{
     collections::HashSet,
     fs::{self, File, OpenOptions, ReadDir},
     io::Write,
-    mem::{swap, transmute, MaybeUninit},
+    mem::swap,
     path::{Path, PathBuf},
     sync::{
         atomic::{AtomicBool, AtomicU32, Ordering},
@@ -32,7 +32,7 @@ use crate::{
     lookup_entry::LookupEntry,
     merge_iter::MergeIter,
     static_sorted_file::{
-        AqmfCache, BlockCache, LookupResult, StaticSortedFile, StaticSortedFileRange,
+        AqmfCache, BlockCache, LookupOptions, LookupResult, StaticSortedFile, StaticSortedFileRange,
     },
     static_sorted_file_builder::StaticSortedFileBuilder,
     write_batch::{FinishResult, WriteBatch},
@@ -341,11 +341,8 @@ impl TurboPersistence {
         let mut compressed = &mmap[..];
         let uncompressed_length = compressed.read_u32::<BE>()? as usize;
 
-        let buffer = Arc::new_zeroed_slice(uncompressed_length);
-        // Safety: MaybeUninit<u8> can be safely transmuted to u8.
-        let mut buffer = unsafe { transmute::<Arc<[MaybeUninit<u8>]>, Arc<[u8]>>(buffer) };
-        // Safety: We know that the buffer is not shared yet.
-        let decompressed = unsafe { Arc::get_mut_unchecked(&mut buffer) };
+        let mut buffer: Arc<[u8]> = std::iter::repeat_n(0, uncompressed_length).collect();
+        let decompressed = Arc::get_mut(&mut buffer).expect("The buffer is not shared yet");
         decompress(compressed, decompressed)?;
         Ok(ArcSlice::from(buffer))
     }
@@ -789,16 +786,45 @@ impl TurboPersistence {
     /// Get a value from the database. Returns None if the key is not found. The returned value
     /// might hold onto a block of the database and it should not be hold long-term.
     pub fn get<K: QueryKey>(&self, family: usize, key: &K) -> Result<Option<ArcSlice<u8>>> {
+        self.get_with_options(family, key, &LookupOptions::default())
+    }
+
+    /// Get a value from the database as of the write with the given sequence number. Entries that
+    /// were put with a later sequence number are skipped, so an older value of the key is returned
+    /// if it's still stored. Entries that were put without a sequence number are always visible.
+    pub fn get_as_of<K: QueryKey>(
+        &self,
+        family: usize,
+        key: &K,
+        max_sequence_number: u32,
+    ) -> Result<Option<ArcSlice<u8>>> {
+        self.get_with_options(
+            family,
+            key,
+            &LookupOptions {
+                max_sequence_number: Some(max_sequence_number),
+                ..Default::default()
+            },
+        )
+    }
+
+    fn get_with_options<K: QueryKey>(
+        &self,
+        family: usize,
+        key: &K,
+        options: &LookupOptions,
+    ) -> Result<Option<ArcSlice<u8>>> {
         let hash = hash_key(key);
         let inner = self.inner.read();
         for sst in inner.static_sorted_files.iter().rev() {
-            match sst.lookup(
+            match sst.lookup_with_options(
                 family as u32,
                 hash,
                 key,
                 &self.aqmf_cache,
                 &self.key_block_cache,
                 &self.value_block_cache,
+                options,
             )? {
                 LookupResult::Deleted => {
                     #[cfg(feature = "stats")]
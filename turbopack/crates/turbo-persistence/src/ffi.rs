@@ -0,0 +1,93 @@
+use std::{
+    panic::{catch_unwind, AssertUnwindSafe},
+    ptr,
+};
+
+use crate::{AqmfCache, BlockCache, LookupResult, StaticSortedFile};
+
+/// The key was found and its value was copied into the value buffer.
+pub const FFI_LOOKUP_FOUND: i32 = 0;
+/// The key is not in the file.
+pub const FFI_LOOKUP_MISS: i32 = 1;
+/// The key was deleted.
+pub const FFI_LOOKUP_DELETED: i32 = 2;
+/// The value is stored in a blob file. The sequence number of the blob file is written to
+/// `blob_sequence_number`.
+pub const FFI_LOOKUP_BLOB: i32 = 3;
+/// The value buffer is too small. The size of the value is written to `value_len`.
+pub const FFI_LOOKUP_BUFFER_TOO_SMALL: i32 = 4;
+/// The file couldn't be read, e. g. because it's corrupt, one of the pointers is null or the
+/// lookup panicked.
+pub const FFI_LOOKUP_ERROR: i32 = -1;
+
+/// Looks up a key in a SST file from C. The value is copied into the buffer at `value_ptr` with
+/// `value_capacity` bytes and its length is written to `value_len`. For values stored in a blob
+/// file the sequence number of the blob file is written to `blob_sequence_number` instead. Returns
+/// one of the `FFI_LOOKUP_*` result codes. A panic during the lookup is not propagated to the
+/// caller, but returns [FFI_LOOKUP_ERROR].
+///
+/// # Safety
+///
+/// `file` and the caches must be null or point to live values that are not mutated during the
+/// call. The key must be valid as described in [StaticSortedFile::lookup_raw]. `value_ptr` must
+/// be null or point to `value_capacity` writable bytes and `value_len` and
+/// `blob_sequence_number` must be null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn turbo_persistence_lookup(
+    file: *const StaticSortedFile,
+    key_family: u32,
+    key_ptr: *const u8,
+    key_len: usize,
+    aqmf_cache: *const AqmfCache,
+    key_block_cache: *const BlockCache,
+    value_block_cache: *const BlockCache,
+    value_ptr: *mut u8,
+    value_capacity: usize,
+    value_len: *mut usize,
+    blob_sequence_number: *mut u32,
+) -> i32 {
+    let (Some(file), Some(aqmf_cache), Some(key_block_cache), Some(value_block_cache)) = (
+        file.as_ref(),
+        aqmf_cache.as_ref(),
+        key_block_cache.as_ref(),
+        value_block_cache.as_ref(),
+    ) else {
+        return FFI_LOOKUP_ERROR;
+    };
+    if value_ptr.is_null() || value_len.is_null() || blob_sequence_number.is_null() {
+        return FFI_LOOKUP_ERROR;
+    }
+    // Unwinding across `extern "C"` aborts the process.
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        file.lookup_raw(
+            key_family,
+            key_ptr,
+            key_len,
+            aqmf_cache,
+            key_block_cache,
+            value_block_cache,
+        )
+    }));
+    let Ok(result) = result else {
+        return FFI_LOOKUP_ERROR;
+    };
+    match result {
+        Ok(LookupResult::Slice { value }) => {
+            *value_len = value.len();
+            if value.len() > value_capacity {
+                return FFI_LOOKUP_BUFFER_TOO_SMALL;
+            }
+            ptr::copy_nonoverlapping(value.as_ptr(), value_ptr, value.len());
+            FFI_LOOKUP_FOUND
+        }
+        Ok(LookupResult::Deleted) => FFI_LOOKUP_DELETED,
+        Ok(LookupResult::Blob { sequence_number }) => {
+            *blob_sequence_number = sequence_number;
+            FFI_LOOKUP_BLOB
+        }
+        Ok(LookupResult::RangeMiss | LookupResult::QuickFilterMiss | LookupResult::KeyMiss) => {
+            FFI_LOOKUP_MISS
+        }
+        Err(_) => FFI_LOOKUP_ERROR,
+    }
+}
@@ -0,0 +1,115 @@
+use std::{fs::File, io, sync::Arc};
+
+use memmap2::Mmap;
+
+/// The storage that holds the bytes of a SST file.
+pub trait FileBacking: Send + Sync {
+    /// Returns the size of the file in bytes.
+    fn size(&self) -> u64;
+
+    /// Returns the complete content of the file if it's accessible in memory. Reads are served
+    /// from this slice without copying.
+    fn as_slice(&self) -> Option<&[u8]> {
+        None
+    }
+
+    /// Reads exactly `buf.len()` bytes starting at `offset`.
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+
+    /// Releases the physical memory that is used to cache the file content, if possible. The
+    /// content stays accessible and is read again on the next access.
+    fn evict_pages(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Copies a range of an in-memory file into `buf`.
+fn read_exact_from_slice(data: &[u8], buf: &mut [u8], offset: u64) -> io::Result<()> {
+    let start = usize::try_from(offset).map_err(|_| io::ErrorKind::UnexpectedEof)?;
+    let Some(slice) = data.get(start..start + buf.len()) else {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    };
+    buf.copy_from_slice(slice);
+    Ok(())
+}
+
+/// A memory mapped file.
+impl FileBacking for Mmap {
+    fn size(&self) -> u64 {
+        self.len() as u64
+    }
+
+    fn as_slice(&self) -> Option<&[u8]> {
+        Some(self)
+    }
+
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        read_exact_from_slice(self, buf, offset)
+    }
+
+    fn evict_pages(&self) -> io::Result<()> {
+        #[cfg(unix)]
+        {
+            // Safety: The mapping is read-only and backed by a file, so dropped pages are read
+            // from the file again on the next access.
+            unsafe { self.unchecked_advise(memmap2::UncheckedAdvice::DontNeed)? };
+        }
+        Ok(())
+    }
+}
+
+/// An in-memory buffer that holds the file content.
+impl FileBacking for Arc<[u8]> {
+    fn size(&self) -> u64 {
+        self.len() as u64
+    }
+
+    fn as_slice(&self) -> Option<&[u8]> {
+        Some(self)
+    }
+
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        read_exact_from_slice(self, buf, offset)
+    }
+}
+
+/// A file that is read with positioned reads instead of being memory mapped. This avoids using a
+/// memory mapping per file, but doesn't share the page cache with other readers.
+pub struct PreadFile {
+    file: File,
+    size: u64,
+}
+
+impl PreadFile {
+    pub fn new(file: File) -> io::Result<Self> {
+        let size = file.metadata()?.len();
+        Ok(Self { file, size })
+    }
+}
+
+impl FileBacking for PreadFile {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    #[cfg(unix)]
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        std::os::unix::fs::FileExt::read_exact_at(&self.file, buf, offset)
+    }
+
+    #[cfg(windows)]
+    fn read_exact_at(&self, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+        while !buf.is_empty() {
+            match std::os::windows::fs::FileExt::seek_read(&self.file, buf, offset) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => {
+                    buf = &mut buf[n..];
+                    offset += n as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
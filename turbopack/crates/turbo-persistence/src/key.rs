@@ -84,21 +84,51 @@ pub trait QueryKey: KeyBase {
     fn cmp(&self, key: &[u8]) -> std::cmp::Ordering;
 }
 
+/// Compares two byte slices lexicographically. This gives the same result as `Ord::cmp`.
+#[inline]
+fn cmp_bytes(a: &[u8], b: &[u8]) -> std::cmp::Ordering {
+    #[cfg(feature = "fast_compare")]
+    {
+        cmp_bytes_word_wise(a, b)
+    }
+    #[cfg(not(feature = "fast_compare"))]
+    {
+        Ord::cmp(a, b)
+    }
+}
+
+/// Compares two byte slices lexicographically by comparing 8 bytes at a time.
+#[cfg(any(feature = "fast_compare", test))]
+fn cmp_bytes_word_wise(a: &[u8], b: &[u8]) -> std::cmp::Ordering {
+    let len = min(a.len(), b.len());
+    let mut a_words = a[..len].chunks_exact(8);
+    let mut b_words = b[..len].chunks_exact(8);
+    for (a_word, b_word) in (&mut a_words).zip(&mut b_words) {
+        // Big endian integers compare like the bytes they are made of.
+        let a_word = u64::from_be_bytes(a_word.try_into().unwrap());
+        let b_word = u64::from_be_bytes(b_word.try_into().unwrap());
+        if a_word != b_word {
+            return a_word.cmp(&b_word);
+        }
+    }
+    Ord::cmp(a_words.remainder(), b_words.remainder()).then(a.len().cmp(&b.len()))
+}
+
 impl QueryKey for &'_ [u8] {
     fn cmp(&self, key: &[u8]) -> std::cmp::Ordering {
-        Ord::cmp(self, &key)
+        cmp_bytes(self, key)
     }
 }
 
 impl<const N: usize> QueryKey for [u8; N] {
     fn cmp(&self, key: &[u8]) -> std::cmp::Ordering {
-        Ord::cmp(&self[..], key)
+        cmp_bytes(&self[..], key)
     }
 }
 
 impl QueryKey for Vec<u8> {
     fn cmp(&self, key: &[u8]) -> std::cmp::Ordering {
-        Ord::cmp(&**self, key)
+        cmp_bytes(self, key)
     }
 }
 
@@ -183,7 +213,10 @@ pub fn hash_key(key: &impl KeyBase) -> u64 {
 mod tests {
     use std::cmp::Ordering;
 
-    use crate::{key::hash_key, QueryKey};
+    use crate::{
+        key::{cmp_bytes_word_wise, hash_key},
+        QueryKey,
+    };
 
     #[test]
     fn tuple() {
@@ -200,6 +233,29 @@ mod tests {
         assert_eq!(QueryKey::cmp(&key, &[]), Ordering::Greater);
     }
 
+    #[test]
+    fn word_wise_compare() {
+        let keys: Vec<Vec<u8>> = vec![
+            vec![],
+            vec![0],
+            vec![0xff],
+            vec![1, 2, 3, 4, 5, 6, 7],
+            vec![1, 2, 3, 4, 5, 6, 7, 8],
+            vec![1, 2, 3, 4, 5, 6, 7, 8, 0],
+            vec![1, 2, 3, 4, 5, 6, 7, 9],
+            vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17],
+            vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 18],
+            vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 17],
+            vec![0xff; 16],
+            vec![0x80, 0, 0, 0, 0, 0, 0, 0, 1],
+        ];
+        for a in &keys {
+            for b in &keys {
+                assert_eq!(cmp_bytes_word_wise(a, b), Ord::cmp(a, b), "{a:?} vs {b:?}");
+            }
+        }
+    }
+
     #[test]
     fn hash() {
         let h1 = hash_key(&[1, 2, 3, 4]);
@@ -1,15 +1,18 @@
 #![feature(once_cell_try)]
-#![feature(new_zeroed_alloc)]
-#![feature(get_mut_unchecked)]
 
 mod arc_slice;
+mod block_reader;
 mod collector;
 mod collector_entry;
 mod compaction;
 mod constants;
 mod db;
+#[cfg(feature = "ffi")]
+mod ffi;
+mod file_backing;
 mod key;
 mod lookup_entry;
+mod membership_filter;
 mod merge_iter;
 mod static_sorted_file;
 mod static_sorted_file_builder;
@@ -20,5 +23,28 @@ mod tests;
 
 pub use arc_slice::ArcSlice;
 pub use db::TurboPersistence;
+#[cfg(feature = "ffi")]
+pub use ffi::{
+    turbo_persistence_lookup, FFI_LOOKUP_BLOB, FFI_LOOKUP_BUFFER_TOO_SMALL, FFI_LOOKUP_DELETED,
+    FFI_LOOKUP_ERROR, FFI_LOOKUP_FOUND, FFI_LOOKUP_MISS,
+};
+pub use file_backing::FileBacking;
 pub use key::{QueryKey, StoreKey};
+pub use lookup_entry::{LookupEntry, LookupValue};
+pub use membership_filter::{
+    MembershipFilter, MembershipFilterBuilder, MembershipFilterDecoder, FILTER_TYPE_AQMF,
+};
+pub use merge_iter::{compact, DedupMergeIter};
+#[cfg(feature = "stats")]
+pub use static_sorted_file::AqmfStatistics;
+pub use static_sorted_file::{
+    AqmfCache, BlockCache, BlockObserver, BlockWeighter, ContentBlockCache, CorruptBlock,
+    DumpEntry, DuplicateKeys, IndexBlockInfo, IndexShape, LookupKind, LookupOptions, LookupResult,
+    LookupTrace, MemoryEstimate, MmapFileOptions, NegativeCache, PartitionedBlockCache,
+    PrewarmBlocks, ScanEntry, ScanEntryKind, SharedDictionaries, StaticSortedFile,
+    StaticSortedFileError, StaticSortedFileIter, StaticSortedFileRange, DEFAULT_MAX_BLOCK_SIZE,
+    KEY_BLOCK_ENTRY_TYPE_BLOB, KEY_BLOCK_ENTRY_TYPE_DELETED, KEY_BLOCK_ENTRY_TYPE_MEDIUM,
+    KEY_BLOCK_ENTRY_TYPE_SMALL,
+};
+pub use static_sorted_file_builder::StaticSortedFileBuilderOptions;
 pub use write_batch::WriteBatch;
@@ -33,6 +33,8 @@ pub struct LookupEntry {
     pub key: ArcSlice<u8>,
     /// The value.
     pub value: LookupValue,
+    /// The sequence number of the write that created this entry, if the file tracks it.
+    pub write_sequence_number: Option<u32>,
 }
 
 impl Entry for LookupEntry {
@@ -63,4 +65,8 @@ impl Entry for LookupEntry {
             },
         }
     }
+
+    fn write_sequence_number(&self) -> Option<u32> {
+        self.write_sequence_number
+    }
 }
@@ -0,0 +1,44 @@
+use std::{fmt::Debug, sync::Arc};
+
+use anyhow::Result;
+
+/// The filter type of the AQMF filter. Files that don't store a filter type use it.
+pub const FILTER_TYPE_AQMF: u8 = 0;
+
+/// A probabilistic filter over the key hashes of a SST file. It can have false positives, but no
+/// false negatives.
+pub trait MembershipFilter: Send + Sync {
+    /// Returns `false` if the key hash is definitely not in the file.
+    fn contains(&self, key_hash: u64) -> bool;
+
+    /// Returns the weight of the filter in the filter cache, roughly its memory size.
+    fn weight(&self) -> u64;
+}
+
+/// Computes the serialized filter of a new SST file.
+pub trait MembershipFilterBuilder: Debug + Send + Sync {
+    /// The filter type that is stored in the file header. Readers need a decoder for this type.
+    fn filter_type(&self) -> u8;
+
+    /// Serializes a filter for the sorted key hashes of all entries.
+    fn build(&self, key_hashes: &[u64]) -> Result<Vec<u8>>;
+}
+
+/// Deserializes a filter of a specific filter type.
+pub type MembershipFilterDecoder = fn(&[u8]) -> Result<Arc<dyn MembershipFilter>>;
+
+impl MembershipFilter for qfilter::Filter {
+    fn contains(&self, key_hash: u64) -> bool {
+        self.contains_fingerprint(key_hash)
+    }
+
+    fn weight(&self) -> u64 {
+        self.capacity() + 1
+    }
+}
+
+/// Deserializes an AQMF filter.
+pub(crate) fn decode_aqmf(bytes: &[u8]) -> Result<Arc<dyn MembershipFilter>> {
+    let filter: qfilter::Filter = pot::from_slice(bytes)?;
+    Ok(Arc::new(filter))
+}
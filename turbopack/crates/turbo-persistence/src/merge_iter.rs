@@ -1,8 +1,19 @@
-use std::{cmp::Ordering, collections::BinaryHeap};
+use std::{
+    cmp::Ordering,
+    collections::BinaryHeap,
+    hash::{BuildHasher, BuildHasherDefault},
+    io::Write,
+};
 
-use anyhow::Result;
+use anyhow::{bail, Result};
+use rustc_hash::FxHasher;
 
-use crate::lookup_entry::LookupEntry;
+use crate::{
+    constants::{DATA_THRESHOLD_PER_COMPACTED_FILE, MAX_ENTRIES_PER_COMPACTED_FILE},
+    lookup_entry::{LookupEntry, LookupValue},
+    static_sorted_file::{BlockCache, StaticSortedFile, StaticSortedFileIter},
+    static_sorted_file_builder::{StaticSortedFileBuilder, StaticSortedFileBuilderOptions},
+};
 
 /// An active iterator that is being merged. It has peeked the next element and can be compared
 /// according to that element. The `order` is used when multiple iterators have the same key.
@@ -56,10 +67,9 @@ impl<T: Iterator<Item = Result<LookupEntry>>> MergeIter<T> {
     }
 }
 
-impl<T: Iterator<Item = Result<LookupEntry>>> Iterator for MergeIter<T> {
-    type Item = Result<LookupEntry>;
-
-    fn next(&mut self) -> Option<Self::Item> {
+impl<T: Iterator<Item = Result<LookupEntry>>> MergeIter<T> {
+    /// Returns the next entry together with the position of the iterator it came from.
+    pub fn next_with_order(&mut self) -> Option<Result<(usize, LookupEntry)>> {
         let ActiveIterator {
             mut iter,
             order,
@@ -74,6 +84,183 @@ impl<T: Iterator<Item = Result<LookupEntry>>> Iterator for MergeIter<T> {
                 entry: next,
             }),
         }
-        Some(Ok(entry))
+        Some(Ok((order, entry)))
+    }
+}
+
+impl<T: Iterator<Item = Result<LookupEntry>>> Iterator for MergeIter<T> {
+    type Item = Result<LookupEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.next_with_order()?.map(|(_, entry)| entry))
+    }
+}
+
+/// An iterator over multiple SST files that yields every key only once. When multiple files
+/// contain the same key, the entry from the file with the highest sequence number wins. Keys whose
+/// winning entry is a tombstone are skipped, unless the tombstone is newer than the GC sequence
+/// number.
+pub struct DedupMergeIter<'l, S: BuildHasher + Clone = BuildHasherDefault<FxHasher>> {
+    iter: MergeIter<StaticSortedFileIter<'l, S>>,
+    /// The sequence numbers of the files in iterator order.
+    sequence_numbers: Vec<u32>,
+    /// Tombstones from files with a higher sequence number are kept.
+    gc_sequence_number: u32,
+    current: Option<(usize, LookupEntry)>,
+}
+
+impl<'l, S: BuildHasher + Clone> DedupMergeIter<'l, S> {
+    pub fn new(
+        mut files: Vec<&'l StaticSortedFile>,
+        key_block_cache: &'l BlockCache<S>,
+        value_block_cache: &'l BlockCache<S>,
+    ) -> Result<Self> {
+        // MergeIter yields equal keys in iterator order, so the newest file needs to come last.
+        files.sort_unstable_by_key(|file| file.sequence_number());
+        let sequence_numbers = files.iter().map(|file| file.sequence_number()).collect();
+        let iters = files
+            .into_iter()
+            .map(|file| file.iter(key_block_cache, value_block_cache))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            iter: MergeIter::new(iters.into_iter())?,
+            sequence_numbers,
+            gc_sequence_number: u32::MAX,
+            current: None,
+        })
+    }
+
+    /// Keeps tombstones from files with a sequence number above `gc_sequence_number`, since they
+    /// may hide entries in older files that are not part of this merge.
+    pub fn with_gc_sequence_number(mut self, gc_sequence_number: u32) -> Self {
+        self.gc_sequence_number = gc_sequence_number;
+        self
+    }
+}
+
+impl<S: BuildHasher + Clone> Iterator for DedupMergeIter<'_, S> {
+    type Item = Result<LookupEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let next = match self.iter.next_with_order() {
+                None => None,
+                Some(Err(e)) => return Some(Err(e)),
+                Some(Ok(entry)) => Some(entry),
+            };
+            match (self.current.take(), next) {
+                (None, None) => return None,
+                (None, Some(next)) => self.current = Some(next),
+                (Some((_, current)), Some((order, next)))
+                    if current.hash == next.hash && current.key == next.key =>
+                {
+                    // The later entry is from a newer file and overrides the current one.
+                    self.current = Some((order, next));
+                }
+                (Some((order, current)), next) => {
+                    self.current = next;
+                    if !matches!(current.value, LookupValue::Deleted)
+                        || self.sequence_numbers[order] > self.gc_sequence_number
+                    {
+                        return Some(Ok(current));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Merges SST files of one key family into new SST files. Newer files win for equal keys and
+/// tombstones are dropped unless they are newer than `gc_sequence_number`. The compression
+/// dictionaries and the AQMF filter are computed from the merged entries. The output files are
+/// encoded with `options` and keep the write sequence numbers of the entries.
+///
+/// Like the database compaction, a new output file is started when the current one reaches
+/// [`MAX_ENTRIES_PER_COMPACTED_FILE`] entries or [`DATA_THRESHOLD_PER_COMPACTED_FILE`] bytes.
+/// `create_output` is called once per output file. Returns the number of files written, which is
+/// zero when all entries were dropped.
+pub fn compact<S: BuildHasher + Clone, W: Write>(
+    inputs: &[&StaticSortedFile],
+    gc_sequence_number: u32,
+    options: &StaticSortedFileBuilderOptions,
+    create_output: impl FnMut() -> Result<W>,
+    key_block_cache: &BlockCache<S>,
+    value_block_cache: &BlockCache<S>,
+) -> Result<usize> {
+    compact_with_limits(
+        inputs,
+        gc_sequence_number,
+        options,
+        create_output,
+        key_block_cache,
+        value_block_cache,
+        MAX_ENTRIES_PER_COMPACTED_FILE,
+        DATA_THRESHOLD_PER_COMPACTED_FILE,
+    )
+}
+
+/// [`compact`] with custom limits for the output files.
+pub(crate) fn compact_with_limits<S: BuildHasher + Clone, W: Write>(
+    inputs: &[&StaticSortedFile],
+    gc_sequence_number: u32,
+    options: &StaticSortedFileBuilderOptions,
+    mut create_output: impl FnMut() -> Result<W>,
+    key_block_cache: &BlockCache<S>,
+    value_block_cache: &BlockCache<S>,
+    max_entries_per_file: usize,
+    data_threshold_per_file: usize,
+) -> Result<usize> {
+    let mut family = None;
+    for file in inputs {
+        let file_family = file.range()?.family;
+        match family {
+            Some(family) if family != file_family => {
+                bail!("Can't compact files of key families {family} and {file_family}")
+            }
+            _ => family = Some(file_family),
+        }
+    }
+    let family = family.unwrap_or_default();
+    let mut write_file = |entries: &[LookupEntry], total_key_size, total_value_size| {
+        let mut output = create_output()?;
+        StaticSortedFileBuilder::new_with_options(
+            family,
+            entries,
+            total_key_size,
+            total_value_size,
+            options,
+        )?
+        .write_to(&mut output)?;
+        anyhow::Ok(())
+    };
+
+    let mut files = 0;
+    let mut entries = Vec::new();
+    let mut total_key_size = 0;
+    let mut total_value_size = 0;
+    for entry in DedupMergeIter::new(inputs.to_vec(), key_block_cache, value_block_cache)?
+        .with_gc_sequence_number(gc_sequence_number)
+    {
+        let entry = entry?;
+        let key_size = entry.key.len();
+        let value_size = entry.value.size_in_sst();
+        if !entries.is_empty()
+            && (total_key_size + total_value_size + key_size + value_size > data_threshold_per_file
+                || entries.len() >= max_entries_per_file)
+        {
+            write_file(&entries, total_key_size, total_value_size)?;
+            files += 1;
+            entries.clear();
+            total_key_size = 0;
+            total_value_size = 0;
+        }
+        total_key_size += key_size;
+        total_value_size += value_size;
+        entries.push(entry);
+    }
+    if !entries.is_empty() {
+        write_file(&entries, total_key_size, total_value_size)?;
+        files += 1;
     }
+    Ok(files)
 }
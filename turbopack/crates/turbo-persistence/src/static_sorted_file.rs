@@ -1,44 +1,239 @@
 use std::{
+    borrow::Cow,
     cmp::Ordering,
     fs::File,
-    hash::BuildHasherDefault,
-    mem::{transmute, MaybeUninit},
+    hash::{BuildHasher, BuildHasherDefault, Hasher},
+    io::Read,
+    ops::Range,
     path::PathBuf,
-    sync::{Arc, OnceLock},
+    sync::{atomic::AtomicU32, Arc, OnceLock},
+    time::{Duration, Instant},
 };
 
-use anyhow::{bail, Result};
-use byteorder::{ReadBytesExt, BE};
-use lzzzz::lz4::decompress_with_dict;
-use memmap2::Mmap;
+use anyhow::{anyhow, bail, Result};
+use byteorder::{ReadBytesExt, BE, LE};
+use lzzzz::lz4::{
+    decompress, decompress_partial, decompress_partial_with_dict, decompress_with_dict,
+};
+use memmap2::{Mmap, MmapMut, MmapOptions};
+use parking_lot::RwLock;
 use quick_cache::sync::GuardResult;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
 use rustc_hash::FxHasher;
 
 use crate::{
     arc_slice::ArcSlice,
+    block_reader::BlockReader,
+    file_backing::{FileBacking, PreadFile},
     lookup_entry::{LookupEntry, LookupValue},
+    membership_filter::{decode_aqmf, MembershipFilter, MembershipFilterDecoder, FILTER_TYPE_AQMF},
     QueryKey,
 };
 
+/// The magic number at the start of every file. It's followed by a one byte format version.
+pub const MAGIC: u32 = 0x535354;
+/// The format version of the original file format.
+pub const FORMAT_VERSION_1: u8 = 1;
+/// The format version of the file format with a flags byte after the header.
+pub const FORMAT_VERSION_2: u8 = 2;
+
+/// The header flag for block offsets and key block entry headers in little endian byte order.
+pub const HEADER_FLAG_LITTLE_ENDIAN_OFFSETS: u8 = 1;
+/// The header flag for files that use shared compression dictionaries instead of storing them in
+/// the file. The flags byte is followed by the u32 id of the dictionaries.
+pub const HEADER_FLAG_SHARED_DICTIONARIES: u8 = 2;
+/// The header flag for files whose key blocks store the common prefix of their keys once. The keys
+/// of the entries only store the remaining suffix.
+pub const HEADER_FLAG_SHARED_KEY_PREFIXES: u8 = 4;
+/// The header flag for files with a checksum of the header, the AQMF filter, the compression
+/// dictionaries and the block offsets. The u64 checksum follows the shared dictionaries id.
+pub const HEADER_FLAG_HEADER_CHECKSUM: u8 = 8;
+/// The header flag for files with another filter than the AQMF filter. The u8 filter type follows
+/// the shared dictionaries id, before the header checksum.
+pub const HEADER_FLAG_FILTER_TYPE: u8 = 16;
+
 /// The block header for an index block.
 pub const BLOCK_TYPE_INDEX: u8 = 0;
 /// The block header for a key block.
 pub const BLOCK_TYPE_KEY: u8 = 1;
+/// The block header for a key block where each entry is followed by the sequence number of the
+/// write that created it.
+pub const BLOCK_TYPE_KEY_WITH_SEQUENCE_NUMBERS: u8 = 2;
 
 /// The tag for a small-sized value.
 pub const KEY_BLOCK_ENTRY_TYPE_SMALL: u8 = 0;
 /// The tag for the blob value.
 pub const KEY_BLOCK_ENTRY_TYPE_BLOB: u8 = 1;
-/// The tag for the deleted value.
+/// The tag for the deleted value. Deleted entries store no value reference and key blocks are
+/// compressed, so a live entry can't be turned into a tombstone in place. Deletes are written to a
+/// newer file and applied by [crate::compact].
 pub const KEY_BLOCK_ENTRY_TYPE_DELETED: u8 = 2;
 /// The tag for a medium-sized value.
 pub const KEY_BLOCK_ENTRY_TYPE_MEDIUM: u8 = 3;
 
+/// The default for the largest decompressed block size that is read, see
+/// [StaticSortedFile::with_max_block_size].
+pub const DEFAULT_MAX_BLOCK_SIZE: usize = 256 * 1024 * 1024;
+
+/// Errors that can occur when reading a SST file.
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum StaticSortedFileError {
+    /// A cache access didn't complete within the configured timeout.
+    #[error("Timeout while waiting for the cache")]
+    CacheTimeout,
+    /// The file was written in a format version that this reader doesn't support.
+    #[error("Unsupported file format version {0}")]
+    UnsupportedVersion(u8),
+    /// The file doesn't start with the magic number of SST files.
+    #[error("Invalid magic number")]
+    InvalidMagic,
+    /// The header has flags that this reader doesn't support.
+    #[error("Unsupported header flags {0:#x}")]
+    UnsupportedHeaderFlags(u8),
+    /// The file ends within the header.
+    #[error("Truncated header")]
+    TruncatedHeader,
+    /// An index block refers to a block that is not before it. This would create a cycle.
+    #[error("Corrupt index block {block} refers to block {next_block}")]
+    CorruptIndexCycle { block: u16, next_block: u16 },
+    /// The file contains multiple entries with the same key.
+    #[error("Duplicate key with hash {hash:#x}")]
+    DuplicateKey { hash: u64 },
+    /// An index block refers to a block that doesn't exist in the file.
+    #[error(
+        "Corrupt index block {block} refers to block {next_block}, but the file has only \
+         {block_count} blocks"
+    )]
+    CorruptIndexBlock {
+        block: u16,
+        next_block: u16,
+        block_count: u16,
+    },
+    /// A key entry refers to a value block that doesn't exist in the file.
+    #[error(
+        "Corrupt value reference to block {block}, but the file has only {block_count} blocks"
+    )]
+    CorruptValueRef { block: u16, block_count: u16 },
+    /// The checksum of the header doesn't match the stored checksum.
+    #[error("Header checksum mismatch, expected {expected:#x} but got {actual:#x}")]
+    HeaderChecksumMismatch { expected: u64, actual: u64 },
+    /// A block decompressed to a different length than the length stored in the file.
+    #[error("Block decompressed to {actual} bytes, but {expected} bytes were expected")]
+    DecompressionLengthMismatch { expected: usize, actual: usize },
+    /// The file uses a filter type that has no decoder, see
+    /// [StaticSortedFile::with_filter_decoder].
+    #[error("Unsupported filter type {0}")]
+    UnsupportedFilterType(u8),
+    /// The decompressor failed to decompress a block.
+    #[error(
+        "Failed to decompress block {block_index} ({compressed_length} bytes compressed, \
+         {uncompressed_length} bytes uncompressed, {}): {source}",
+        if *with_dictionary { "with dictionary" } else { "without dictionary" }
+    )]
+    Decompression {
+        block_index: u16,
+        compressed_length: usize,
+        uncompressed_length: usize,
+        with_dictionary: bool,
+        #[source]
+        source: lzzzz::Error,
+    },
+    /// A block declares a decompressed length above the configured maximum, see
+    /// [StaticSortedFile::with_max_block_size].
+    #[error(
+        "Block {block_index} declares {uncompressed_length} bytes uncompressed, but at most \
+         {max_block_size} bytes are allowed"
+    )]
+    BlockTooLarge {
+        block_index: u16,
+        uncompressed_length: usize,
+        max_block_size: usize,
+    },
+    /// An entry of a key block is smaller than the entry before it, so binary search over the
+    /// block can miss keys, see [StaticSortedFile::check_sorted].
+    #[error("Entry {entry_index} of key block {block_index} is not sorted")]
+    UnsortedBlock {
+        block_index: u16,
+        entry_index: usize,
+    },
+}
+
+/// Options for a lookup operation.
+#[derive(Clone, Default)]
+pub struct LookupOptions {
+    /// The maximum time to wait for another thread that is currently populating a cache entry.
+    /// `None` waits indefinitely.
+    pub cache_timeout: Option<Duration>,
+    /// Read all value blocks that are needed by a batched lookup in parallel before resolving the
+    /// values. Only used by `StaticSortedFile::lookup_many`.
+    pub prefetch_value_blocks: bool,
+    /// Read the index and key blocks that are needed by a batched lookup in parallel before
+    /// searching the keys. The blocks of each index level are read in parallel. Only used by
+    /// `StaticSortedFile::lookup_many`.
+    pub prefetch_key_blocks: bool,
+    /// Entries that were written by a write with a higher sequence number are treated as missing.
+    /// Files without write sequence numbers are not affected.
+    pub max_sequence_number: Option<u32>,
+    /// Don't insert the AQMF filter or any blocks into the caches. Blocks that are already cached
+    /// are still used. This avoids cache pollution for workloads that read each file only once.
+    pub bypass_caches: bool,
+    /// Like `bypass_caches`, but only for value blocks. The AQMF filter and key blocks are still
+    /// cached. This avoids evicting other data with large value blocks that are read only once.
+    pub bypass_value_block_cache: bool,
+    /// Like `bypass_caches`, but blocks and filters that are already cached are ignored too. Every
+    /// access reads and decompresses from the file, which gives a baseline for measuring cache
+    /// effectiveness. Pinned blocks are still used.
+    pub disable_caches: bool,
+    /// Don't check the AQMF filter and search the index directly. This avoids loading the filter
+    /// when the key is known to be present, e.g. when reading a key again after writing it.
+    pub skip_aqmf: bool,
+}
+
+/// Options for the memory mapping of `StaticSortedFile::open_with_options`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MmapFileOptions {
+    /// Prefault all pages of the file on open, so lookups don't hit page faults.
+    pub populate: bool,
+    /// Map the file with huge pages of `1 << huge_page_bits` bytes. `Some(0)` uses the default
+    /// huge page size. Only supported on Linux.
+    pub huge_page_bits: Option<u8>,
+}
+
+/// The result of `StaticSortedFile::lookup_into`. Like `LookupResult`, but the value is appended to
+/// the caller's buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LookupKind {
+    /// The key was deleted.
+    Deleted,
+    /// The key was found and the value was appended to the buffer.
+    Slice,
+    /// The key was found and the value is a blob.
+    Blob { sequence_number: u32 },
+    /// The key was not found because it is out of the range of this SST file.
+    RangeMiss,
+    /// The key was not found because it was not in the AQMF filter. But it was in the range.
+    QuickFilterMiss,
+    /// The key was not found. But it was in the range and the AQMF filter.
+    KeyMiss,
+}
+
+impl LookupKind {
+    /// Returns true if the key was not found in this file, for any reason.
+    pub fn is_miss(&self) -> bool {
+        matches!(
+            self,
+            LookupKind::RangeMiss | LookupKind::QuickFilterMiss | LookupKind::KeyMiss
+        )
+    }
+}
+
 /// The result of a lookup operation.
 pub enum LookupResult {
     /// The key was deleted.
     Deleted,
-    /// The key was found and the value is a slice.
+    /// The key was found and the value is a slice. Small values are slices of the cached value
+    /// block, so values from the same block share one allocation, see
+    /// [ArcSlice::shares_allocation].
     Slice { value: ArcSlice<u8> },
     /// The key was found and the value is a blob.
     Blob { sequence_number: u32 },
@@ -50,6 +245,25 @@ pub enum LookupResult {
     KeyMiss,
 }
 
+impl LookupResult {
+    /// Returns true if the key was not found in this file, for any reason. A deleted key is not a
+    /// miss, as it shadows older files.
+    pub fn is_miss(&self) -> bool {
+        matches!(
+            self,
+            LookupResult::RangeMiss | LookupResult::QuickFilterMiss | LookupResult::KeyMiss
+        )
+    }
+
+    /// Returns the value if it's stored in this file.
+    pub fn found_value(&self) -> Option<&ArcSlice<u8>> {
+        match self {
+            LookupResult::Slice { value } => Some(value),
+            _ => None,
+        }
+    }
+}
+
 impl From<LookupValue> for LookupResult {
     fn from(value: LookupValue) -> Self {
         match value {
@@ -60,14 +274,148 @@ impl From<LookupValue> for LookupResult {
     }
 }
 
+/// The decoded entry type and value reference of an entry. Used for debugging.
+pub enum DumpEntry {
+    /// A small-sized value in a shared value block. `value` is only resolved when requested.
+    Small {
+        block: u16,
+        position: u32,
+        size: u16,
+        value: Option<ArcSlice<u8>>,
+    },
+    /// A medium-sized value in its own value block.
+    Medium { block: u16 },
+    /// The value is stored in a blob file.
+    Blob { sequence_number: u32 },
+    /// The key was deleted.
+    Deleted,
+}
+
+/// The blocks that were visited by a lookup.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LookupTrace {
+    /// The visited index blocks in order, each with the block that was selected next.
+    pub index_blocks: Vec<(u16, u16)>,
+    /// The key block the lookup terminated in. This is `None` when the lookup was answered by the
+    /// range check or the AQMF filter.
+    pub key_block: Option<u16>,
+    /// The number of index, key and value blocks that were accessed, including cached blocks.
+    pub blocks_read: usize,
+    /// The decompressed size of the blocks that weren't cached and had to be decompressed.
+    pub bytes_decompressed: u64,
+}
+
+impl LookupTrace {
+    /// Records a block access. `decompressed` is the block if it had to be decompressed.
+    fn record_block(&mut self, decompressed: Option<&[u8]>) {
+        self.blocks_read += 1;
+        if let Some(block) = decompressed {
+            self.bytes_decompressed += block.len() as u64;
+        }
+    }
+}
+
+/// A reference to the value of an entry as stored in a key block.
+#[derive(Clone, Copy)]
+enum ValueRef {
+    /// A small-sized value in a shared value block.
+    Small {
+        block: u16,
+        position: u32,
+        size: u16,
+    },
+    /// A medium-sized value in its own value block.
+    Medium { block: u16 },
+    /// The value is stored in a blob file.
+    Blob { sequence_number: u32 },
+    /// The key was deleted.
+    Deleted,
+}
+
+impl ValueRef {
+    /// Decodes the value reference of a key block entry.
+    fn decode(ty: u8, mut val: &[u8]) -> Result<Self> {
+        Ok(match ty {
+            KEY_BLOCK_ENTRY_TYPE_SMALL => ValueRef::Small {
+                block: val.read_u16::<BE>()?,
+                size: val.read_u16::<BE>()?,
+                position: val.read_u32::<BE>()?,
+            },
+            KEY_BLOCK_ENTRY_TYPE_MEDIUM => ValueRef::Medium {
+                block: val.read_u16::<BE>()?,
+            },
+            KEY_BLOCK_ENTRY_TYPE_BLOB => ValueRef::Blob {
+                sequence_number: val.read_u32::<BE>()?,
+            },
+            KEY_BLOCK_ENTRY_TYPE_DELETED => ValueRef::Deleted,
+            _ => {
+                bail!("Invalid key block entry type");
+            }
+        })
+    }
+
+    /// Returns the key block entry type of this value reference.
+    fn entry_type(&self) -> u8 {
+        match self {
+            ValueRef::Small { .. } => KEY_BLOCK_ENTRY_TYPE_SMALL,
+            ValueRef::Medium { .. } => KEY_BLOCK_ENTRY_TYPE_MEDIUM,
+            ValueRef::Blob { .. } => KEY_BLOCK_ENTRY_TYPE_BLOB,
+            ValueRef::Deleted => KEY_BLOCK_ENTRY_TYPE_DELETED,
+        }
+    }
+}
+
+/// The location of a key hash in a file, see [StaticSortedFile::locate_hash].
+struct HashLocation {
+    /// The child positions and the child counts of the index blocks that were visited.
+    path: Vec<(usize, usize)>,
+    /// The number of entries in the key block with a smaller hash.
+    position: usize,
+    /// The number of entries in the key block.
+    entry_count: usize,
+    /// The key block is the last key block in the file.
+    is_last_key_block: bool,
+}
+
+/// The result of searching a key without reading its value.
+enum SearchResult {
+    /// The key was found. Contains the stored key, a reference to its value and the sequence
+    /// number of the write that created it, if the file tracks it.
+    Found(ArcSlice<u8>, ValueRef, Option<u32>),
+    /// The key was not found. Contains one of the miss variants of `LookupResult`.
+    Miss(LookupResult),
+}
+
+/// The estimated memory footprint of a fully warmed SST file. The block and filter sizes are
+/// computed like the weights of `BlockWeighter` and `AqmfWeighter`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryEstimate {
+    /// The size of the file content, i. e. the memory mapped bytes.
+    pub mmapped: u64,
+    /// The total size of all blocks after decompression.
+    pub decompressed_blocks: u64,
+    /// The size of the deserialized AQMF filter.
+    pub filter: u64,
+}
+
 /// A byte range in the SST file.
+#[derive(Clone)]
 struct LocationInFile {
     start: usize,
     end: usize,
 }
 
+impl LocationInFile {
+    fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+}
+
 /// The read and parsed header of an SST file.
+#[derive(Clone)]
 struct Header {
+    /// The format version of this file.
+    format_version: u8,
     /// The key family stored in this file.
     family: u32,
     /// The minimum hash value in this file.
@@ -86,6 +434,16 @@ struct Header {
     blocks_start: usize,
     /// The number of blocks in this file.
     block_count: u16,
+    /// Block offsets and key block entry headers are stored in little endian byte order.
+    little_endian_offsets: bool,
+    /// The id of the shared compression dictionaries if the file doesn't store its own.
+    shared_dictionaries_id: Option<u32>,
+    /// Key blocks store the common prefix of their keys once.
+    shared_key_prefixes: bool,
+    /// The stored checksum of the header. It's located right before the AQMF filter.
+    header_checksum: Option<u64>,
+    /// The type of the filter that is stored in place of the AQMF filter.
+    filter_type: u8,
 }
 
 /// The key family and hash range of an SST file.
@@ -96,258 +454,2649 @@ pub struct StaticSortedFileRange {
     pub max_hash: u64,
 }
 
+impl StaticSortedFileRange {
+    /// Returns true if both ranges are in the same key family and their hash ranges intersect.
+    /// Files without entries have an empty range and don't overlap with anything.
+    pub fn overlaps(&self, other: &StaticSortedFileRange) -> bool {
+        self.family == other.family
+            && self.min_hash <= self.max_hash
+            && other.min_hash <= other.max_hash
+            && self.min_hash <= other.max_hash
+            && other.min_hash <= self.max_hash
+    }
+}
+
 #[derive(Clone, Default)]
 pub struct AqmfWeighter;
 
-impl quick_cache::Weighter<u32, Arc<qfilter::Filter>> for AqmfWeighter {
-    fn weight(&self, _key: &u32, filter: &Arc<qfilter::Filter>) -> u64 {
-        filter.capacity() + 1
+impl quick_cache::Weighter<(u32, u32), Arc<dyn MembershipFilter>> for AqmfWeighter {
+    fn weight(&self, _key: &(u32, u32), filter: &Arc<dyn MembershipFilter>) -> u64 {
+        filter.weight()
     }
 }
 
-#[derive(Clone, Default)]
-pub struct BlockWeighter;
+/// Weights cached blocks by their size. A block cache can use a scaled weighter to evict its
+/// blocks earlier than those of another cache with the same capacity, e.g. to keep key blocks
+/// resident longer than large value blocks.
+#[derive(Clone)]
+pub struct BlockWeighter {
+    scale: u64,
+}
 
-impl quick_cache::Weighter<(u32, u16), ArcSlice<u8>> for BlockWeighter {
-    fn weight(&self, _key: &(u32, u16), val: &ArcSlice<u8>) -> u64 {
-        val.len() as u64 + 8
+impl BlockWeighter {
+    /// Creates a weighter that multiplies the weight of every block by `scale`.
+    pub fn scaled(scale: u64) -> Self {
+        assert!(scale > 0, "The scale of a block weighter must be positive");
+        Self { scale }
     }
 }
 
-pub type AqmfCache =
-    quick_cache::sync::Cache<u32, Arc<qfilter::Filter>, AqmfWeighter, BuildHasherDefault<FxHasher>>;
-pub type BlockCache =
-    quick_cache::sync::Cache<(u32, u16), ArcSlice<u8>, BlockWeighter, BuildHasherDefault<FxHasher>>;
+impl Default for BlockWeighter {
+    fn default() -> Self {
+        Self { scale: 1 }
+    }
+}
 
-/// A memory mapped SST file.
-pub struct StaticSortedFile {
-    /// The sequence number of this file.
-    sequence_number: u32,
-    /// The memory mapped file.
-    mmap: Mmap,
-    /// The parsed header of this file.
-    header: OnceLock<Header>,
-    /// The AQMF filter of this file. This is only used if the range is very large. Smaller ranges
-    /// use the AQMF cache instead.
-    aqmf: OnceLock<qfilter::Filter>,
+impl quick_cache::Weighter<(u32, u32, u16), ArcSlice<u8>> for BlockWeighter {
+    fn weight(&self, _key: &(u32, u32, u16), val: &ArcSlice<u8>) -> u64 {
+        (val.len() as u64 + 8) * self.scale
+    }
 }
 
-impl StaticSortedFile {
-    /// The sequence number of this file.
-    pub fn sequence_number(&self) -> u32 {
-        self.sequence_number
+impl quick_cache::Weighter<u128, ArcSlice<u8>> for BlockWeighter {
+    fn weight(&self, _key: &u128, val: &ArcSlice<u8>) -> u64 {
+        (val.len() as u64 + 8) * self.scale
     }
+}
 
-    /// Opens an SST file at the given path. This memory maps the file, but does not read it yet.
-    /// It's lazy read on demand.
-    pub fn open(sequence_number: u32, path: PathBuf) -> Result<Self> {
-        let mmap = unsafe { Mmap::map(&File::open(&path)?)? };
-        let file = Self {
-            sequence_number,
-            mmap,
-            header: OnceLock::new(),
-            aqmf: OnceLock::new(),
-        };
-        Ok(file)
+/// A cache for deserialized AQMF filters and other membership filters. It's keyed by sequence
+/// number and generation, see [StaticSortedFile::remap]. The hasher can be customized, e.g. to use
+/// a DoS-resistant hasher.
+pub type AqmfCache<S = BuildHasherDefault<FxHasher>> =
+    quick_cache::sync::Cache<(u32, u32), Arc<dyn MembershipFilter>, AqmfWeighter, S>;
+/// A cache for decompressed blocks. It's keyed by sequence number, generation and block index, see
+/// [StaticSortedFile::remap]. The hasher can be customized, e.g. to use a DoS-resistant hasher.
+pub type BlockCache<S = BuildHasherDefault<FxHasher>> =
+    quick_cache::sync::Cache<(u32, u32, u16), ArcSlice<u8>, BlockWeighter, S>;
+/// A cache for decompressed value blocks that is keyed by the content of the blocks instead of
+/// their location, see [StaticSortedFile::with_content_block_cache].
+pub type ContentBlockCache =
+    quick_cache::sync::Cache<u128, ArcSlice<u8>, BlockWeighter, BuildHasherDefault<FxHasher>>;
+/// A cache of key hashes that are known to be absent from a file, see
+/// [StaticSortedFile::with_negative_cache]. It's keyed by sequence number, generation and key hash.
+pub type NegativeCache = quick_cache::sync::Cache<
+    (u32, u32, u64),
+    (),
+    quick_cache::UnitWeighter,
+    BuildHasherDefault<FxHasher>,
+>;
+
+/// A block cache that is split into independent partitions. Each file uses the partition selected
+/// by its sequence number, so eviction and locking in one partition doesn't affect files in other
+/// partitions. A single partition behaves like a plain `BlockCache`.
+pub struct PartitionedBlockCache<S = BuildHasherDefault<FxHasher>> {
+    partitions: Box<[BlockCache<S>]>,
+}
+
+impl<S: BuildHasher + Clone> PartitionedBlockCache<S> {
+    /// Creates a partitioned cache from the given caches. There must be at least one partition.
+    pub fn new(partitions: Vec<BlockCache<S>>) -> Self {
+        assert!(
+            !partitions.is_empty(),
+            "PartitionedBlockCache needs at least one partition"
+        );
+        Self {
+            partitions: partitions.into_boxed_slice(),
+        }
     }
 
-    /// Reads and parses the header of this file if it hasn't been read yet.
-    fn header(&self) -> Result<&Header> {
-        self.header.get_or_try_init(|| {
-            let mut file = &*self.mmap;
-            let magic = file.read_u32::<BE>()?;
-            if magic != 0x53535401 {
-                bail!("Invalid magic number or version");
-            }
-            let family = file.read_u32::<BE>()?;
-            let min_hash = file.read_u64::<BE>()?;
-            let max_hash = file.read_u64::<BE>()?;
-            let aqmf_length = file.read_u24::<BE>()? as usize;
-            let key_compression_dictionary_length = file.read_u16::<BE>()? as usize;
-            let value_compression_dictionary_length = file.read_u16::<BE>()? as usize;
-            let block_count = file.read_u16::<BE>()?;
-            const HEADER_SIZE: usize = 33;
-            let mut current_offset = HEADER_SIZE;
-            let aqmf = LocationInFile {
-                start: current_offset,
-                end: current_offset + aqmf_length,
-            };
-            current_offset += aqmf_length;
-            let key_compression_dictionary = LocationInFile {
-                start: current_offset,
-                end: current_offset + key_compression_dictionary_length,
-            };
-            current_offset += key_compression_dictionary_length;
-            let value_compression_dictionary = LocationInFile {
-                start: current_offset,
-                end: current_offset + value_compression_dictionary_length,
-            };
-            current_offset += value_compression_dictionary_length;
-            let block_offsets_start = current_offset;
-            let blocks_start = block_offsets_start + block_count as usize * 4;
-
-            Ok(Header {
-                family,
-                min_hash,
-                max_hash,
-                aqmf,
-                key_compression_dictionary,
-                value_compression_dictionary,
-                block_offsets_start,
-                blocks_start,
-                block_count,
-            })
-        })
+    /// Returns the cache partition that should be used for the file with the given sequence
+    /// number.
+    pub fn partition(&self, sequence_number: u32) -> &BlockCache<S> {
+        &self.partitions[sequence_number as usize % self.partitions.len()]
     }
 
-    /// Returns the key family and hash range of this file.
-    pub fn range(&self) -> Result<StaticSortedFileRange> {
-        let header = self.header()?;
-        Ok(StaticSortedFileRange {
-            family: header.family,
-            min_hash: header.min_hash,
-            max_hash: header.max_hash,
-        })
+    /// Returns all cache partitions.
+    pub fn partitions(&self) -> &[BlockCache<S>] {
+        &self.partitions
     }
+}
 
-    /// Iterate over all entries in this file in sorted order.
-    pub fn iter<'l>(
-        &'l self,
-        key_block_cache: &'l BlockCache,
-        value_block_cache: &'l BlockCache,
-    ) -> Result<StaticSortedFileIter<'l>> {
-        let header = self.header()?;
-        let mut iter = StaticSortedFileIter {
-            this: self,
-            key_block_cache,
-            value_block_cache,
-            header,
-            stack: Vec::new(),
-            current_key_block: None,
-        };
-        iter.enter_block(header.block_count - 1)?;
-        Ok(iter)
+impl<S: BuildHasher + Clone> From<BlockCache<S>> for PartitionedBlockCache<S> {
+    fn from(cache: BlockCache<S>) -> Self {
+        Self::new(vec![cache])
     }
+}
 
-    /// Looks up a key in this file.
-    pub fn lookup<K: QueryKey>(
-        &self,
-        key_family: u32,
-        key_hash: u64,
-        key: &K,
-        aqmf_cache: &AqmfCache,
-        key_block_cache: &BlockCache,
-        value_block_cache: &BlockCache,
-    ) -> Result<LookupResult> {
-        let header = self.header()?;
-        if key_family != header.family || key_hash < header.min_hash || key_hash > header.max_hash {
-            return Ok(LookupResult::RangeMiss);
-        }
+/// Compression dictionaries that are shared by multiple SST files instead of being stored in each
+/// file. Files refer to them by `id`.
+#[derive(Debug)]
+pub struct SharedDictionaries {
+    /// The id that files use to refer to these dictionaries.
+    pub id: u32,
+    /// The compression dictionary for key and index blocks.
+    pub key: Arc<[u8]>,
+    /// The compression dictionary for value blocks.
+    pub value: Arc<[u8]>,
+}
 
-        let use_aqmf_cache = header.max_hash - header.min_hash < 1 << 62;
-        if use_aqmf_cache {
-            let aqmf = match aqmf_cache.get_value_or_guard(&self.sequence_number, None) {
-                GuardResult::Value(aqmf) => aqmf,
-                GuardResult::Guard(guard) => {
-                    let aqmf = &self.mmap[header.aqmf.start..header.aqmf.end];
-                    let aqmf: Arc<qfilter::Filter> = Arc::new(pot::from_slice(aqmf)?);
-                    let _ = guard.insert(aqmf.clone());
-                    aqmf
-                }
-                GuardResult::Timeout => unreachable!(),
-            };
-            if !aqmf.contains_fingerprint(key_hash) {
-                return Ok(LookupResult::QuickFilterMiss);
-            }
-        } else {
-            let aqmf = self.aqmf.get_or_try_init(|| {
-                let aqmf = &self.mmap[header.aqmf.start..header.aqmf.end];
-                anyhow::Ok(pot::from_slice(aqmf)?)
-            })?;
-            if !aqmf.contains_fingerprint(key_hash) {
-                return Ok(LookupResult::QuickFilterMiss);
-            }
+/// A block that was skipped because it couldn't be read, see
+/// [StaticSortedFile::iter_skipping_corrupt_blocks].
+#[derive(Debug)]
+pub struct CorruptBlock {
+    /// The index of the block in the file.
+    pub block_index: u16,
+    /// The error that occurred while reading the block.
+    pub error: anyhow::Error,
+}
+
+/// The content of an index block, see [StaticSortedFile::index_structure].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexBlockInfo {
+    /// The index of the block in the file.
+    pub block_index: u16,
+    /// The blocks that this index block refers to, in key hash order.
+    pub children: Vec<u16>,
+    /// The smallest key hash of each child except the first. Keys with a smaller hash are in the
+    /// previous child.
+    pub separator_hashes: Vec<u64>,
+}
+
+/// A summary of the index tree of a file, see [StaticSortedFile::index_shape].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IndexShape {
+    /// The number of index levels above the key blocks. Zero for a file without blocks.
+    pub depth: usize,
+    /// The number of index blocks.
+    pub index_blocks: usize,
+    /// The number of key blocks.
+    pub key_blocks: usize,
+    /// The largest number of children of a single index block.
+    pub max_fanout: usize,
+}
+
+/// The kind of value that a [ScanEntry] has.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScanEntryKind {
+    /// The value is stored in a value block of this file.
+    Value,
+    /// The value is stored in a blob file.
+    Blob { sequence_number: u32 },
+    /// The key was deleted.
+    Deleted,
+}
+
+/// An entry that is yielded by [StaticSortedFile::scan]. The value is only read when
+/// [ScanEntry::value] is called.
+pub struct ScanEntry<'l, S = BuildHasherDefault<FxHasher>> {
+    /// The hash of the key.
+    pub hash: u64,
+    /// The key.
+    pub key: ArcSlice<u8>,
+    value_ref: ValueRef,
+    file: &'l StaticSortedFile,
+    header: MappedHeader,
+    value_block_cache: &'l BlockCache<S>,
+}
+
+impl<S: BuildHasher + Clone> ScanEntry<'_, S> {
+    /// Returns the kind of value without reading it.
+    pub fn kind(&self) -> ScanEntryKind {
+        match self.value_ref {
+            ValueRef::Small { .. } | ValueRef::Medium { .. } => ScanEntryKind::Value,
+            ValueRef::Blob { sequence_number } => ScanEntryKind::Blob { sequence_number },
+            ValueRef::Deleted => ScanEntryKind::Deleted,
         }
-        let mut current_block = header.block_count - 1;
-        loop {
-            let block = self.get_key_block(header, current_block, key_block_cache)?;
-            let mut block = &block[..];
-            let block_type = block.read_u8()?;
-            match block_type {
-                BLOCK_TYPE_INDEX => {
-                    current_block = self.lookup_index_block(block, key_hash)?;
-                }
-                BLOCK_TYPE_KEY => {
-                    return self.lookup_key_block(block, key_hash, key, header, value_block_cache);
-                }
-                _ => {
-                    bail!("Invalid block type");
-                }
-            }
+    }
+
+    /// Reads the value. This decompresses the value block if it's not cached.
+    pub fn value(&self) -> Result<LookupValue> {
+        self.file.resolve_value(
+            self.value_ref,
+            &self.header,
+            self.value_block_cache,
+            &LookupOptions::default(),
+            None,
+        )
+    }
+}
+
+/// The blocks that are read into the caches by [StaticSortedFile::prewarm].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PrewarmBlocks {
+    /// Index and key blocks.
+    #[default]
+    Keys,
+    /// Index, key and value blocks.
+    All,
+}
+
+/// Receives a sample for every block that a file decompresses, see
+/// [StaticSortedFile::with_block_observer]. This allows to aggregate decompression times, e.g. into
+/// a histogram to tune cache and block sizes.
+pub trait BlockObserver: Send + Sync {
+    /// Called after a block was decompressed. `uncompressed_len` is the number of bytes that were
+    /// decompressed, which is less than the block size when only a prefix of the block was read.
+    fn on_decompress(
+        &self,
+        block_index: u16,
+        compressed_len: usize,
+        uncompressed_len: usize,
+        duration: Duration,
+    );
+}
+
+/// The size of the header without the optional fields of format version 2.
+const HEADER_SIZE: usize = 33;
+
+/// Parses the header at the start of `bytes`.
+fn parse_header(bytes: &[u8]) -> Result<Header, StaticSortedFileError> {
+    let truncated = |_| StaticSortedFileError::TruncatedHeader;
+    let mut file = bytes;
+    let magic = file.read_u24::<BE>().map_err(truncated)?;
+    if magic != MAGIC {
+        return Err(StaticSortedFileError::InvalidMagic);
+    }
+    let format_version = file.read_u8().map_err(truncated)?;
+    if format_version != FORMAT_VERSION_1 && format_version != FORMAT_VERSION_2 {
+        return Err(StaticSortedFileError::UnsupportedVersion(format_version));
+    }
+    let family = file.read_u32::<BE>().map_err(truncated)?;
+    let min_hash = file.read_u64::<BE>().map_err(truncated)?;
+    let max_hash = file.read_u64::<BE>().map_err(truncated)?;
+    let aqmf_length = file.read_u24::<BE>().map_err(truncated)? as usize;
+    let key_compression_dictionary_length = file.read_u16::<BE>().map_err(truncated)? as usize;
+    let value_compression_dictionary_length = file.read_u16::<BE>().map_err(truncated)? as usize;
+    let block_count = file.read_u16::<BE>().map_err(truncated)?;
+    let mut current_offset = HEADER_SIZE;
+    let mut flags = 0;
+    if format_version == FORMAT_VERSION_2 {
+        flags = file.read_u8().map_err(truncated)?;
+        current_offset += 1;
+        if flags
+            & !(HEADER_FLAG_LITTLE_ENDIAN_OFFSETS
+                | HEADER_FLAG_SHARED_DICTIONARIES
+                | HEADER_FLAG_SHARED_KEY_PREFIXES
+                | HEADER_FLAG_HEADER_CHECKSUM
+                | HEADER_FLAG_FILTER_TYPE)
+            != 0
+        {
+            return Err(StaticSortedFileError::UnsupportedHeaderFlags(flags));
         }
     }
+    let shared_dictionaries_id = if flags & HEADER_FLAG_SHARED_DICTIONARIES != 0 {
+        current_offset += 4;
+        Some(file.read_u32::<BE>().map_err(truncated)?)
+    } else {
+        None
+    };
+    let filter_type = if flags & HEADER_FLAG_FILTER_TYPE != 0 {
+        current_offset += 1;
+        file.read_u8().map_err(truncated)?
+    } else {
+        FILTER_TYPE_AQMF
+    };
+    let header_checksum = if flags & HEADER_FLAG_HEADER_CHECKSUM != 0 {
+        current_offset += 8;
+        Some(file.read_u64::<BE>().map_err(truncated)?)
+    } else {
+        None
+    };
+    let aqmf = LocationInFile {
+        start: current_offset,
+        end: current_offset + aqmf_length,
+    };
+    current_offset += aqmf_length;
+    let key_compression_dictionary = LocationInFile {
+        start: current_offset,
+        end: current_offset + key_compression_dictionary_length,
+    };
+    current_offset += key_compression_dictionary_length;
+    let value_compression_dictionary = LocationInFile {
+        start: current_offset,
+        end: current_offset + value_compression_dictionary_length,
+    };
+    current_offset += value_compression_dictionary_length;
+    let block_offsets_start = current_offset;
+    let blocks_start = block_offsets_start + block_count as usize * 4;
+
+    Ok(Header {
+        format_version,
+        family,
+        min_hash,
+        max_hash,
+        aqmf,
+        key_compression_dictionary,
+        value_compression_dictionary,
+        block_offsets_start,
+        blocks_start,
+        block_count,
+        little_endian_offsets: flags & HEADER_FLAG_LITTLE_ENDIAN_OFFSETS != 0,
+        shared_key_prefixes: flags & HEADER_FLAG_SHARED_KEY_PREFIXES != 0,
+        shared_dictionaries_id,
+        header_checksum,
+        filter_type,
+    })
+}
+
+/// The next generation of a [StaticSortedFile]. It's shared by all files of the process, so an
+/// instance that opens a file again doesn't reuse the cache keys of a previous instance.
+static NEXT_GENERATION: AtomicU32 = AtomicU32::new(0);
+
+fn next_generation() -> u32 {
+    NEXT_GENERATION.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+}
+
+/// One version of the content of an SST file together with the state that is derived from it. A
+/// remap replaces the whole mapping, so an operation that started before the remap keeps reading
+/// a consistent version of the file.
+struct Mapping {
+    /// The sequence number of the file.
+    sequence_number: u32,
+    /// Identifies this mapping in the cache keys. Every open and every remap takes a new
+    /// generation, so blocks and filters cached for another version of a file with the same
+    /// sequence number are not returned.
+    generation: u32,
+    /// The storage that holds the file content, usually a memory mapped file.
+    backing: Box<dyn FileBacking>,
+    /// The parsed header of this file, or the error that occurred while parsing it. The header is
+    /// only parsed once, even if it's corrupt.
+    header: OnceLock<Result<Header, StaticSortedFileError>>,
+    /// The AQMF filter of this file. This is only used if the range is very large. Smaller ranges
+    /// use the AQMF cache instead.
+    aqmf: OnceLock<Arc<dyn MembershipFilter>>,
+    /// Key and index blocks that are kept in memory regardless of the key block cache. Indexed by
+    /// block index.
+    pinned_blocks: RwLock<Vec<Option<ArcSlice<u8>>>>,
+    /// The compression dictionaries of files that are not accessible in memory. They are read
+    /// once since every block read needs them.
+    file_dictionaries: OnceLock<[Box<[u8]>; 2]>,
+    /// The hash of the value compression dictionary. It's part of the content key of value blocks.
+    value_dictionary_hash: OnceLock<u64>,
+    /// The content keys of the value blocks, computed on first use. The compressed blocks are
+    /// only read and hashed once per mapping.
+    value_block_content_keys: OnceLock<Box<[OnceLock<u128>]>>,
+    /// Whether each block is an index or key block. It's computed from the index structure once
+    /// per mapping, since [StaticSortedFile::warm_blocks] needs it on every call.
+    is_key_block: OnceLock<Box<[bool]>>,
+}
 
-    /// Looks up a hash in a index block.
-    fn lookup_index_block(&self, mut block: &[u8], hash: u64) -> Result<u16> {
-        let first_block = block.read_u16::<BE>()?;
-        let entry_count = block.len() / 10;
-        if entry_count == 0 {
-            return Ok(first_block);
+impl Mapping {
+    fn new(sequence_number: u32, backing: Box<dyn FileBacking>) -> Self {
+        Self {
+            sequence_number,
+            generation: next_generation(),
+            backing,
+            header: OnceLock::new(),
+            aqmf: OnceLock::new(),
+            pinned_blocks: RwLock::new(Vec::new()),
+            file_dictionaries: OnceLock::new(),
+            value_dictionary_hash: OnceLock::new(),
+            value_block_content_keys: OnceLock::new(),
+            is_key_block: OnceLock::new(),
         }
-        let entries = block;
-        fn get_hash(entries: &[u8], index: usize) -> Result<u64> {
-            Ok((&entries[index * 10..]).read_u64::<BE>()?)
+    }
+
+    /// Returns the length of the file in bytes.
+    fn file_len(&self) -> usize {
+        self.backing.size() as usize
+    }
+
+    /// Checks if a range of bytes is within the file.
+    fn in_bounds(&self, range: &Range<usize>) -> bool {
+        range.start <= range.end && range.end <= self.file_len()
+    }
+
+    /// Reads a range of this file. Files that are accessible in memory are read without copying.
+    fn read(&self, range: Range<usize>) -> Result<Cow<'_, [u8]>> {
+        if !self.in_bounds(&range) {
+            bail!(
+                "Range {range:?} is out of bounds of file seq:{} (length {})",
+                self.sequence_number,
+                self.file_len()
+            );
         }
-        fn get_block(entries: &[u8], index: usize) -> Result<u16> {
-            Ok((&entries[index * 10 + 8..]).read_u16::<BE>()?)
+        if let Some(data) = self.backing.as_slice() {
+            return Ok(Cow::Borrowed(&data[range]));
         }
-        let first_hash = get_hash(entries, 0)?;
-        match hash.cmp(&first_hash) {
-            Ordering::Less => {
-                return Ok(first_block);
-            }
-            Ordering::Equal => {
-                return get_block(entries, 0);
-            }
-            Ordering::Greater => {}
+        let mut buffer = vec![0; range.len()];
+        if let Err(err) = self.backing.read_exact_at(&mut buffer, range.start as u64) {
+            return Err(anyhow::Error::new(err).context(format!(
+                "Failed to read {range:?} of file seq:{}",
+                self.sequence_number
+            )));
         }
+        Ok(Cow::Owned(buffer))
+    }
 
-        let mut l = 1;
-        let mut r = entry_count;
-        // binary search for the range
-        while l < r {
-            let m = (l + r) / 2;
-            let mid_hash = get_hash(entries, m)?;
-            match hash.cmp(&mid_hash) {
-                Ordering::Less => {
-                    r = m;
-                }
-                Ordering::Equal => {
-                    return get_block(entries, m);
-                }
-                Ordering::Greater => {
-                    l = m + 1;
-                }
+    /// Reads and parses the header of this file if it hasn't been parsed yet. A parse error is
+    /// cached and returned again on later calls. Read errors are not cached, so the next call
+    /// reads the header again.
+    fn header(self: &Arc<Self>) -> Result<MappedHeader> {
+        let header = match self.header.get() {
+            Some(header) => header,
+            None => {
+                // The header can be followed by a flags byte, the shared dictionaries id, the
+                // filter type and the header checksum.
+                let size = self.backing.size().min(HEADER_SIZE as u64 + 14) as usize;
+                let bytes = self.read(0..size)?;
+                self.header.get_or_init(|| parse_header(&bytes))
             }
+        };
+        match header {
+            Ok(_) => Ok(MappedHeader {
+                mapping: self.clone(),
+            }),
+            Err(err) => Err(err.clone().into()),
         }
-        get_block(entries, l - 1)
     }
 
-    /// Looks up a key in a key block and the value in a value block.
-    fn lookup_key_block<K: QueryKey>(
-        &self,
-        mut block: &[u8],
-        key_hash: u64,
-        key: &K,
-        header: &Header,
-        value_block_cache: &BlockCache,
-    ) -> Result<LookupResult> {
-        let entry_count = block.read_u24::<BE>()? as usize;
-        let offsets = &block[..entry_count * 4];
-        let entries = &block[entry_count * 4..];
+    /// The key of a block of this mapping in the block caches.
+    fn block_cache_key(&self, block: u16) -> (u32, u32, u16) {
+        (self.sequence_number, self.generation, block)
+    }
+}
 
-        let mut l = 0;
-        let mut r = entry_count;
+/// A [Mapping] with a parsed header. Every operation holds one for as long as it runs and reads
+/// everything through it, so all reads of the operation see the same version of the file, and a
+/// mapping that was replaced by [StaticSortedFile::remap] is dropped when its last operation
+/// finishes.
+#[derive(Clone)]
+struct MappedHeader {
+    mapping: Arc<Mapping>,
+}
+
+impl std::ops::Deref for MappedHeader {
+    type Target = Header;
+
+    fn deref(&self) -> &Header {
+        match self.mapping.header.get() {
+            Some(Ok(header)) => header,
+            _ => unreachable!("A MappedHeader is only created for a parsed header"),
+        }
+    }
+}
+
+impl MappedHeader {
+    fn read(&self, range: Range<usize>) -> Result<Cow<'_, [u8]>> {
+        self.mapping.read(range)
+    }
+
+    /// Reads a range like [MappedHeader::read], but the returned bytes keep the mapping alive.
+    fn read_mapped(&self, range: Range<usize>) -> Result<MappedBytes> {
+        Ok(match self.read(range.clone())? {
+            Cow::Borrowed(_) => MappedBytes::Mapped {
+                mapping: self.mapping.clone(),
+                range,
+            },
+            Cow::Owned(data) => MappedBytes::Owned(data),
+        })
+    }
+
+    fn block_cache_key(&self, block: u16) -> (u32, u32, u16) {
+        self.mapping.block_cache_key(block)
+    }
+}
+
+/// Bytes of a [Mapping] that outlive the operation that read them. Bytes of a mapping that is
+/// accessible in memory keep the mapping alive instead of copying it.
+enum MappedBytes {
+    Mapped {
+        mapping: Arc<Mapping>,
+        range: Range<usize>,
+    },
+    Owned(Vec<u8>),
+}
+
+impl std::ops::Deref for MappedBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            MappedBytes::Mapped { mapping, range } => {
+                let data = mapping.backing.as_slice();
+                &data.expect("Only mappings in memory are borrowed")[range.clone()]
+            }
+            MappedBytes::Owned(data) => data,
+        }
+    }
+}
+
+/// A SST file. It's usually memory mapped, but can also be backed by an in-memory buffer.
+pub struct StaticSortedFile {
+    /// The sequence number of this file.
+    sequence_number: u32,
+    /// The current version of the file content.
+    mapping: RwLock<Arc<Mapping>>,
+    /// The shared compression dictionaries for files that don't store their own.
+    shared_dictionaries: Option<Arc<SharedDictionaries>>,
+    /// Value blocks are cached by content in this cache instead of the value block cache.
+    content_block_cache: Option<Arc<ContentBlockCache>>,
+    /// Key hashes that recently missed in this file.
+    negative_cache: Option<Arc<NegativeCache>>,
+    /// Blocks with at least this many decompressed bytes are decompressed into an anonymous
+    /// memory mapping instead of a heap allocation.
+    mmap_block_threshold: Option<usize>,
+    /// Blocks that declare a larger decompressed length are rejected before allocating a buffer.
+    max_block_size: usize,
+    /// Decoders for filter types other than the AQMF filter.
+    filter_decoders: Vec<(u8, MembershipFilterDecoder)>,
+    /// Is notified about every decompressed block.
+    block_observer: Option<Arc<dyn BlockObserver>>,
+    /// Counts how often lookups found the key after the AQMF filter reported it as present.
+    #[cfg(feature = "stats")]
+    aqmf_stats: TrackedAqmfStatistics,
+    /// Weak references to all blocks that were decompressed, to find blocks that are still
+    /// referenced when the file is dropped.
+    #[cfg(feature = "leak_check")]
+    decompressed_blocks: parking_lot::Mutex<Vec<(u16, crate::arc_slice::WeakArcSlice<u8>)>>,
+}
+
+/// Reports the blocks that are still referenced when the file is dropped. Blocks in caches that
+/// outlive the file are reported too.
+#[cfg(all(feature = "leak_check", feature = "tracing"))]
+impl Drop for StaticSortedFile {
+    fn drop(&mut self) {
+        self.mapping.get_mut().pinned_blocks.write().clear();
+        for (block_index, references) in self.outstanding_blocks() {
+            tracing::warn!(
+                sequence_number = self.sequence_number,
+                block_index,
+                references,
+                "SST file dropped while a block is still referenced"
+            );
+        }
+    }
+}
+
+/// How often the AQMF filter of a file reported a key as possibly present.
+#[cfg(feature = "stats")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AqmfStatistics {
+    /// Lookups where the filter reported the key and the key was found.
+    pub true_positives: u64,
+    /// Lookups where the filter reported the key, but the key was not in the file.
+    pub false_positives: u64,
+}
+
+#[cfg(feature = "stats")]
+#[derive(Default)]
+struct TrackedAqmfStatistics {
+    true_positives: std::sync::atomic::AtomicU64,
+    false_positives: std::sync::atomic::AtomicU64,
+}
+
+impl StaticSortedFile {
+    /// The sequence number of this file.
+    pub fn sequence_number(&self) -> u32 {
+        self.sequence_number
+    }
+
+    /// Opens an SST file at the given path. This memory maps the file, but does not read it yet.
+    /// It's lazy read on demand.
+    pub fn open(sequence_number: u32, path: PathBuf) -> Result<Self> {
+        Self::open_from_file(sequence_number, &File::open(&path)?)
+    }
+
+    /// Opens an SST file like [StaticSortedFile::open] and verifies the header checksum. This is
+    /// much cheaper than verifying all blocks, but catches corrupt block offsets and dictionaries.
+    /// Files without a header checksum are not checked.
+    pub fn open_validated(sequence_number: u32, path: PathBuf) -> Result<Self> {
+        let file = Self::open(sequence_number, path)?;
+        file.verify_header_checksum()?;
+        Ok(file)
+    }
+
+    /// Opens an SST file like [StaticSortedFile::open_validated] and also walks the index to
+    /// return its [IndexShape]. This catches degenerate layouts, e. g. all keys in a single key
+    /// block, and fails for files with invalid block types in the index.
+    pub fn open_validated_with_shape(
+        sequence_number: u32,
+        path: PathBuf,
+    ) -> Result<(Self, IndexShape)> {
+        let file = Self::open_validated(sequence_number, path)?;
+        let shape = file.index_shape()?;
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            sequence_number,
+            depth = shape.depth,
+            index_blocks = shape.index_blocks,
+            key_blocks = shape.key_blocks,
+            max_fanout = shape.max_fanout,
+            "sst index shape"
+        );
+        Ok((file, shape))
+    }
+
+    /// Opens an SST file from an already opened file handle like [StaticSortedFile::open]. This
+    /// allows the caller to control how the file is opened. The handle is only needed to create
+    /// the memory mapping.
+    pub fn open_from_file(sequence_number: u32, file: &File) -> Result<Self> {
+        let mmap = unsafe { Mmap::map(file)? };
+        Ok(Self::from_backing(sequence_number, Box::new(mmap)))
+    }
+
+    /// Opens an SST file at the given path like [StaticSortedFile::open], but creates the memory
+    /// mapping with the given options.
+    pub fn open_with_options(
+        sequence_number: u32,
+        path: PathBuf,
+        options: &MmapFileOptions,
+    ) -> Result<Self> {
+        let mut mmap_options = MmapOptions::new();
+        if options.populate {
+            mmap_options.populate();
+        }
+        if let Some(page_bits) = options.huge_page_bits {
+            mmap_options.huge(Some(page_bits));
+        }
+        let mmap = unsafe { mmap_options.map(&File::open(&path)?)? };
+        Ok(Self::from_backing(sequence_number, Box::new(mmap)))
+    }
+
+    /// Opens an SST file at the given path like [StaticSortedFile::open] and parses the header
+    /// immediately. The parse cost is paid by the open instead of the first lookup, and a corrupt
+    /// header fails the open.
+    pub fn open_eager(sequence_number: u32, path: PathBuf) -> Result<Self> {
+        let file = Self::open(sequence_number, path)?;
+        file.header()?;
+        Ok(file)
+    }
+
+    /// Opens an SST file at the given path without memory mapping it. Blocks are read with
+    /// positioned reads on demand.
+    pub fn open_without_mmap(sequence_number: u32, path: PathBuf) -> Result<Self> {
+        let file = PreadFile::new(File::open(&path)?)?;
+        Ok(Self::from_backing(sequence_number, Box::new(file)))
+    }
+
+    /// Creates an SST file from an in-memory buffer without touching the filesystem. Useful for
+    /// tests and for small hot files.
+    pub fn open_from_bytes(sequence_number: u32, bytes: Arc<[u8]>) -> Self {
+        Self::from_backing(sequence_number, Box::new(bytes))
+    }
+
+    /// Creates an SST file from arbitrary bytes. This is the entry point for fuzzing the read path:
+    /// reading a corrupt file returns errors, but never panics. Only available with the `fuzz`
+    /// feature.
+    #[cfg(any(test, feature = "fuzz"))]
+    pub fn from_bytes_for_fuzz(bytes: Vec<u8>) -> Self {
+        Self::open_from_bytes(0, Arc::from(bytes))
+    }
+
+    /// Sets the shared compression dictionaries. They are used when the file was written with
+    /// shared dictionaries and must have the id that the file refers to.
+    pub fn with_shared_dictionaries(mut self, dictionaries: Arc<SharedDictionaries>) -> Self {
+        self.shared_dictionaries = Some(dictionaries);
+        self
+    }
+
+    /// Decompresses blocks with at least `threshold` bytes into anonymous memory mappings instead
+    /// of heap allocations. This keeps large blocks out of the allocator and returns their memory
+    /// to the OS when they are dropped.
+    pub fn with_mmap_block_threshold(mut self, threshold: usize) -> Self {
+        self.mmap_block_threshold = Some(threshold);
+        self
+    }
+
+    /// Caches the value blocks of this file in `cache`, keyed by a hash of their content, instead
+    /// of the value block cache that is passed to lookups. Files that share a content block cache
+    /// share one cache entry for identical value blocks, e. g. after a deduplicating writer. The
+    /// key is computed from the compressed block and the value dictionary, which determine the
+    /// decompressed bytes, so blocks don't need to be decompressed to find them.
+    pub fn with_content_block_cache(mut self, cache: Arc<ContentBlockCache>) -> Self {
+        self.content_block_cache = Some(cache);
+        self
+    }
+
+    /// Remembers key hashes that are not in this file in the given cache. Repeated lookups of an
+    /// absent key return [LookupResult::KeyMiss] without searching the index and key blocks again.
+    /// Only hashes without any entry in the file are remembered, so a miss is never cached for a
+    /// key that shares its hash with a stored key. The AQMF filter is still checked first. Misses
+    /// are keyed by the generation of this instance, so they are not used after a remap or by
+    /// another instance of the same file.
+    pub fn with_negative_cache(mut self, cache: Arc<NegativeCache>) -> Self {
+        self.negative_cache = Some(cache);
+        self
+    }
+
+    /// Rejects blocks that declare more than `max_block_size` decompressed bytes with
+    /// [StaticSortedFileError::BlockTooLarge]. A corrupt length would otherwise allocate up to
+    /// 4 GiB before the decompression fails. Defaults to [DEFAULT_MAX_BLOCK_SIZE].
+    pub fn with_max_block_size(mut self, max_block_size: usize) -> Self {
+        self.max_block_size = max_block_size;
+        self
+    }
+
+    /// Registers a decoder for files that were written with a custom
+    /// [crate::MembershipFilterBuilder] of the given filter type. The AQMF filter is always
+    /// supported.
+    pub fn with_filter_decoder(
+        mut self,
+        filter_type: u8,
+        decoder: MembershipFilterDecoder,
+    ) -> Self {
+        self.filter_decoders.push((filter_type, decoder));
+        self
+    }
+
+    /// Sets an observer that is called with the duration of every block decompression of this
+    /// file. Blocks that are served from a cache are not decompressed and not observed.
+    pub fn with_block_observer(mut self, observer: Arc<dyn BlockObserver>) -> Self {
+        self.block_observer = Some(observer);
+        self
+    }
+
+    /// Creates an SST file that reads from the given backing storage.
+    fn from_backing(sequence_number: u32, backing: Box<dyn FileBacking>) -> Self {
+        Self {
+            sequence_number,
+            mapping: RwLock::new(Arc::new(Mapping::new(sequence_number, backing))),
+            shared_dictionaries: None,
+            content_block_cache: None,
+            negative_cache: None,
+            mmap_block_threshold: None,
+            max_block_size: DEFAULT_MAX_BLOCK_SIZE,
+            filter_decoders: Vec::new(),
+            block_observer: None,
+            #[cfg(feature = "stats")]
+            aqmf_stats: Default::default(),
+            #[cfg(feature = "leak_check")]
+            decompressed_blocks: Default::default(),
+        }
+    }
+
+    /// Memory maps the file at `path` again to pick up a new version of this file that was written
+    /// by another process. Pinned blocks are dropped. The parsed header, filter and dictionaries
+    /// are dropped too, unless the new version is unchanged up to the start of the blocks. Every
+    /// remap starts a new generation of cache keys, so blocks and filters of the old version in
+    /// shared caches are not returned anymore. They are evicted from the caches over time.
+    ///
+    /// Concurrent operations keep reading the version of the file that they started with. The
+    /// replaced mapping is dropped when the last of them finishes.
+    pub fn remap(&self, path: PathBuf) -> Result<()> {
+        let mmap = unsafe { Mmap::map(&File::open(&path)?)? };
+        let mut current = self.mapping.write();
+        let mut mapping = Mapping::new(self.sequence_number, Box::new(mmap));
+        // The header, filter, dictionaries and block offsets are all stored before the blocks, so
+        // the parsed state is still valid when these bytes are equal. The blocks themselves are
+        // not covered by the comparison.
+        if let Some(Ok(header)) = current.header.get() {
+            let blocks_start = header.blocks_start;
+            let unchanged = current.read(0..blocks_start).is_ok_and(|old| {
+                mapping
+                    .backing
+                    .as_slice()
+                    .and_then(|new| new.get(..blocks_start))
+                    == Some(&*old)
+            });
+            if unchanged {
+                mapping.header = current.header.clone();
+                mapping.aqmf = current.aqmf.clone();
+                mapping.file_dictionaries = current.file_dictionaries.clone();
+                mapping.value_dictionary_hash = current.value_dictionary_hash.clone();
+            }
+        }
+        *current = Arc::new(mapping);
+        Ok(())
+    }
+
+    /// Drops the pages of the memory mapped file from the page cache to release physical memory.
+    /// The file stays open and the parsed header is kept, so the next access reads the pages from
+    /// disk again. Dropping the `StaticSortedFile` unmaps the file completely.
+    pub fn evict_pages(&self) -> Result<()> {
+        self.mapping().backing.evict_pages()?;
+        Ok(())
+    }
+
+    /// Returns the blocks of this file that are still referenced, with the number of references.
+    /// This includes blocks in block caches and blocks pinned by this file. Referenced blocks
+    /// after the caches are dropped point to `ArcSlice`s that outlive their use, e. g. a value
+    /// that keeps a whole value block alive. Only available with the `leak_check` feature.
+    #[cfg(feature = "leak_check")]
+    pub fn outstanding_blocks(&self) -> Vec<(u16, usize)> {
+        self.decompressed_blocks
+            .lock()
+            .iter()
+            .map(|(block_index, block)| (*block_index, block.strong_count()))
+            .filter(|(_, references)| *references > 0)
+            .collect()
+    }
+
+    /// Verifies the header checksum of this file. The checksum covers the header, the AQMF filter,
+    /// the compression dictionaries and the block offsets. Files without a header checksum are
+    /// not checked.
+    pub fn verify_header_checksum(&self) -> Result<()> {
+        let header = &self.header()?;
+        let Some(expected) = header.header_checksum else {
+            return Ok(());
+        };
+        let actual = header_checksum(&[
+            &header.read(0..header.aqmf.start - 8)?,
+            &header.read(header.aqmf.start..header.blocks_start)?,
+        ]);
+        if actual != expected {
+            return Err(StaticSortedFileError::HeaderChecksumMismatch { expected, actual }.into());
+        }
+        Ok(())
+    }
+
+    /// Verifies the integrity of this file by decompressing all blocks on the given thread pool.
+    /// The pool can be reused to verify many files. Returns the first error that was encountered.
+    pub fn verify_parallel(&self, pool: &rayon::ThreadPool) -> Result<()> {
+        let header = &self.header()?;
+        if header.block_count == 0 {
+            return Ok(());
+        }
+        pool.install(|| {
+            // Index and key blocks are found by walking the index level by level. All remaining
+            // blocks are value blocks.
+            let mut is_key_block = vec![false; header.block_count as usize];
+            let mut level = vec![header.block_count - 1];
+            while !level.is_empty() {
+                for &block_index in &level {
+                    let Some(is_key_block) = is_key_block.get_mut(block_index as usize) else {
+                        bail!("Index references block {block_index} which doesn't exist");
+                    };
+                    if *is_key_block {
+                        bail!("Block {block_index} is referenced multiple times");
+                    }
+                    *is_key_block = true;
+                }
+                let children = level
+                    .into_par_iter()
+                    .map(|block_index| {
+                        let block = self.read_key_block(header, block_index)?;
+                        let mut block = &block[..];
+                        match block.read_u8()? {
+                            BLOCK_TYPE_INDEX => index_block_children(block),
+                            BLOCK_TYPE_KEY | BLOCK_TYPE_KEY_WITH_SEQUENCE_NUMBERS => Ok(Vec::new()),
+                            _ => bail!("Invalid block type in block {block_index}"),
+                        }
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                level = children.into_iter().flatten().collect();
+            }
+            (0..header.block_count)
+                .into_par_iter()
+                .filter(|&block_index| !is_key_block[block_index as usize])
+                .try_for_each(|block_index| {
+                    self.read_value_block(header, block_index)?;
+                    Ok(())
+                })
+        })
+    }
+
+    /// Verifies that the entries of every key block are increasing by key hash and key, which
+    /// lookups rely on for binary search. Fails with [StaticSortedFileError::UnsortedBlock] for the
+    /// first entry that is smaller than the entry before it. Equal keys are allowed, unless
+    /// `duplicate_keys` is [DuplicateKeys::Error], which fails with
+    /// [StaticSortedFileError::DuplicateKey] instead. Value blocks are not read.
+    pub fn check_sorted(&self, duplicate_keys: DuplicateKeys) -> Result<()> {
+        let header = &self.header()?;
+        if header.block_count == 0 {
+            return Ok(());
+        }
+        let mut stack = vec![header.block_count - 1];
+        let mut previous_key = Vec::new();
+        let mut full_key = Vec::new();
+        while let Some(block_index) = stack.pop() {
+            let block = self.read_key_block(header, block_index)?;
+            let block_type = *block
+                .first()
+                .ok_or_else(|| anyhow!("Empty block {block_index}"))?;
+            match block_type {
+                BLOCK_TYPE_INDEX => {
+                    for child in index_block_children(&block[1..])? {
+                        check_index_child(header, block_index, child)?;
+                        stack.push(child);
+                    }
+                }
+                BLOCK_TYPE_KEY | BLOCK_TYPE_KEY_WITH_SEQUENCE_NUMBERS => {
+                    let format = KeyBlockFormat::new(header, block_type);
+                    let KeyBlockLayout {
+                        entry_count,
+                        prefix,
+                        offsets,
+                        entries,
+                    } = key_block_layout(&block, format)?;
+                    let prefix = &block[prefix];
+                    let offsets = &block[offsets];
+                    let entries = &block[entries];
+                    let mut previous_hash = None;
+                    for entry_index in 0..entry_count {
+                        let entry =
+                            get_key_entry(offsets, entries, entry_count, entry_index, format)?;
+                        let key = prefixed_key(prefix, entry.key, &mut full_key);
+                        if let Some(previous_hash) = previous_hash {
+                            let ordering = u64::cmp(&entry.hash, &previous_hash)
+                                .then_with(|| key.cmp(&previous_key[..]));
+                            match ordering {
+                                Ordering::Less => {
+                                    return Err(StaticSortedFileError::UnsortedBlock {
+                                        block_index,
+                                        entry_index,
+                                    }
+                                    .into());
+                                }
+                                Ordering::Equal if duplicate_keys == DuplicateKeys::Error => {
+                                    return Err(StaticSortedFileError::DuplicateKey {
+                                        hash: entry.hash,
+                                    }
+                                    .into());
+                                }
+                                _ => {}
+                            }
+                        }
+                        previous_hash = Some(entry.hash);
+                        previous_key.clear();
+                        previous_key.extend_from_slice(key);
+                    }
+                }
+                _ => bail!("Invalid block type in block {block_index}"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads blocks of this file into the block caches in parallel, so following lookups don't need
+    /// to decompress them. Blocks that don't fit into the caches are evicted again as usual.
+    pub fn prewarm<S: BuildHasher + Clone + Send + Sync>(
+        &self,
+        blocks: PrewarmBlocks,
+        key_block_cache: &BlockCache<S>,
+        value_block_cache: &BlockCache<S>,
+    ) -> Result<()> {
+        let header = &self.header()?;
+        if header.block_count == 0 {
+            return Ok(());
+        }
+        let options = LookupOptions::default();
+        let mut is_key_block = vec![false; header.block_count as usize];
+        let mut level = vec![header.block_count - 1];
+        while !level.is_empty() {
+            for &block_index in &level {
+                is_key_block[block_index as usize] = true;
+            }
+            let children = level
+                .into_par_iter()
+                .map(|block_index| {
+                    let block =
+                        self.get_key_block(header, block_index, key_block_cache, &options, None)?;
+                    let mut data = &block[..];
+                    match data.read_u8()? {
+                        BLOCK_TYPE_INDEX => {
+                            let children = index_block_children(data)?;
+                            for &child in &children {
+                                check_index_child(header, block_index, child)?;
+                            }
+                            Ok(children)
+                        }
+                        BLOCK_TYPE_KEY | BLOCK_TYPE_KEY_WITH_SEQUENCE_NUMBERS => Ok(Vec::new()),
+                        _ => bail!("Invalid block type in block {block_index}"),
+                    }
+                })
+                .collect::<Result<Vec<_>>>()?;
+            level = children.into_iter().flatten().collect();
+        }
+        if blocks == PrewarmBlocks::All {
+            (0..header.block_count)
+                .into_par_iter()
+                .filter(|&block_index| !is_key_block[block_index as usize])
+                .try_for_each(|block_index| {
+                    self.get_value_block(header, block_index, value_block_cache, &options, None)
+                        .map(drop)
+                })?;
+        }
+        Ok(())
+    }
+
+    /// Like [StaticSortedFile::prewarm], but decompresses the blocks on the rayon thread pool and
+    /// returns a future that completes when all blocks are read. This doesn't block the thread
+    /// that polls the future, so many files can be prewarmed concurrently from async code.
+    #[cfg(feature = "async")]
+    pub async fn prewarm_async<S: BuildHasher + Clone + Send + Sync + 'static>(
+        self: Arc<Self>,
+        blocks: PrewarmBlocks,
+        key_block_cache: Arc<BlockCache<S>>,
+        value_block_cache: Arc<BlockCache<S>>,
+    ) -> Result<()> {
+        BlockingTask::spawn(move || self.prewarm(blocks, &key_block_cache, &value_block_cache))
+            .await
+    }
+
+    /// Reads the given blocks into the block caches in the given order. Index and key blocks go
+    /// into the key block cache and value blocks into the value block cache. Unlike
+    /// [StaticSortedFile::prewarm] this only decompresses the listed blocks, so a warming budget
+    /// can be spent on the hottest blocks first. The index blocks are read once to classify the
+    /// blocks.
+    pub fn warm_blocks<S: BuildHasher + Clone>(
+        &self,
+        blocks: &[u16],
+        key_block_cache: &BlockCache<S>,
+        value_block_cache: &BlockCache<S>,
+    ) -> Result<()> {
+        let header = &self.header()?;
+        let is_key_block = header.mapping.is_key_block.get_or_try_init(|| {
+            let mut is_key_block = vec![false; header.block_count as usize];
+            for index_block in self.index_blocks(header)? {
+                is_key_block[index_block.block_index as usize] = true;
+                for child in index_block.children {
+                    is_key_block[child as usize] = true;
+                }
+            }
+            anyhow::Ok(is_key_block.into_boxed_slice())
+        })?;
+        let options = LookupOptions::default();
+        for &block_index in blocks {
+            check_block_index(header, block_index)?;
+            if is_key_block[block_index as usize] {
+                self.get_key_block(header, block_index, key_block_cache, &options, None)?;
+            } else {
+                self.get_value_block(header, block_index, value_block_cache, &options, None)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a key or index block and keeps it in memory for the lifetime of this file, so it can't
+    /// be evicted from the key block cache. This is useful for index blocks that are used by
+    /// nearly every lookup.
+    pub fn pin_block<S: BuildHasher + Clone>(
+        &self,
+        block_index: u16,
+        key_block_cache: &BlockCache<S>,
+    ) -> Result<()> {
+        let header = &self.header()?;
+        check_block_index(header, block_index)?;
+        let block = self.get_key_block(
+            header,
+            block_index,
+            key_block_cache,
+            &LookupOptions::default(),
+            None,
+        )?;
+        let mut pinned_blocks = header.mapping.pinned_blocks.write();
+        if pinned_blocks.is_empty() {
+            pinned_blocks.resize(header.block_count as usize, None);
+        }
+        pinned_blocks[block_index as usize] = Some(block);
+        Ok(())
+    }
+
+    /// Returns the decompressed content of a key or index block, including the block type. The
+    /// block is read through the key block cache. This allows to decode blocks with custom code.
+    pub fn read_key_block_decompressed<S: BuildHasher + Clone>(
+        &self,
+        block_index: u16,
+        key_block_cache: &BlockCache<S>,
+    ) -> Result<ArcSlice<u8>> {
+        let header = &self.header()?;
+        check_block_index(header, block_index)?;
+        self.get_key_block(
+            header,
+            block_index,
+            key_block_cache,
+            &LookupOptions::default(),
+            None,
+        )
+    }
+
+    /// Returns the keys of the key block `block_index` in the order they are stored. The block is
+    /// read through the key block cache. The key block indices can be found with
+    /// [StaticSortedFile::index_structure]. Fails for index blocks.
+    pub fn key_block_keys<S: BuildHasher + Clone>(
+        &self,
+        block_index: u16,
+        key_block_cache: &BlockCache<S>,
+    ) -> Result<Vec<ArcSlice<u8>>> {
+        let header = &self.header()?;
+        check_block_index(header, block_index)?;
+        let block = self.get_key_block(
+            header,
+            block_index,
+            key_block_cache,
+            &LookupOptions::default(),
+            None,
+        )?;
+        let block_type = *block
+            .first()
+            .ok_or_else(|| anyhow!("Empty block {block_index}"))?;
+        if !matches!(
+            block_type,
+            BLOCK_TYPE_KEY | BLOCK_TYPE_KEY_WITH_SEQUENCE_NUMBERS
+        ) {
+            bail!("Block {block_index} is not a key block (block type {block_type})");
+        }
+        let format = KeyBlockFormat::new(header, block_type);
+        let KeyBlockLayout {
+            entry_count,
+            prefix,
+            offsets,
+            entries,
+        } = key_block_layout(&block, format)?;
+        let prefix = &block[prefix];
+        let offsets = &block[offsets];
+        let entries = &block[entries];
+        (0..entry_count)
+            .map(|index| {
+                let entry = get_key_entry(offsets, entries, entry_count, index, format)?;
+                Ok(stored_key(prefix, entry.key, &block))
+            })
+            .collect()
+    }
+
+    /// Returns the decompressed content of a value block. The block is read through the value
+    /// block cache.
+    pub fn read_value_block_decompressed<S: BuildHasher + Clone>(
+        &self,
+        block_index: u16,
+        value_block_cache: &BlockCache<S>,
+    ) -> Result<ArcSlice<u8>> {
+        let header = &self.header()?;
+        check_block_index(header, block_index)?;
+        self.get_value_block(
+            header,
+            block_index,
+            value_block_cache,
+            &LookupOptions::default(),
+            None,
+        )
+    }
+
+    /// Returns how often lookups in this file passed the AQMF filter and whether the key was
+    /// found. The ratio of false positives is the false positive rate of the filter.
+    #[cfg(feature = "stats")]
+    pub fn aqmf_statistics(&self) -> AqmfStatistics {
+        use std::sync::atomic::Ordering;
+        AqmfStatistics {
+            true_positives: self.aqmf_stats.true_positives.load(Ordering::Relaxed),
+            false_positives: self.aqmf_stats.false_positives.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Estimates the number of entries whose key hash is in `start_hash..end_hash`. Only the index
+    /// blocks and the two key blocks at the bounds are read. Entries in these key blocks are
+    /// counted exactly, and the key blocks in between are assumed to have as many entries as the
+    /// key blocks at the bounds on average. The last key block of the file is usually only
+    /// partially filled and doesn't count towards the average. This is intended for query
+    /// planning.
+    pub fn estimate_count_in_range<S: BuildHasher + Clone>(
+        &self,
+        start_hash: u64,
+        end_hash: u64,
+        key_block_cache: &BlockCache<S>,
+    ) -> Result<usize> {
+        let header = &self.header()?;
+        if start_hash >= end_hash
+            || header.block_count == 0
+            || end_hash <= header.min_hash
+            || start_hash > header.max_hash
+        {
+            return Ok(0);
+        }
+        let start = self.locate_hash(header, start_hash, key_block_cache)?;
+        let end = self.locate_hash(header, end_hash, key_block_cache)?;
+        if start.path == end.path {
+            return Ok(end.position.saturating_sub(start.position));
+        }
+        // The number of key blocks between the bounds. The children between the paths in the
+        // index block where they diverge are whole subtrees. Below that, the children after the
+        // start path and before the end path are. The number of key blocks in a subtree is
+        // estimated from the child counts of the index blocks on both paths.
+        let depth = start.path.len().min(end.path.len());
+        let child_count = |level: usize| (start.path[level].1 + end.path[level].1) / 2;
+        let subtree_size = |level: usize| (level + 1..depth).map(child_count).product::<usize>();
+        let mut between = 0;
+        if let Some(diverge) = (0..depth).find(|&level| start.path[level].0 != end.path[level].0) {
+            between += end.path[diverge]
+                .0
+                .saturating_sub(start.path[diverge].0 + 1)
+                * subtree_size(diverge);
+            for level in diverge + 1..depth {
+                let (start_position, start_child_count) = start.path[level];
+                let (end_position, _) = end.path[level];
+                between += (start_child_count.saturating_sub(start_position + 1) + end_position)
+                    * subtree_size(level);
+            }
+        }
+        let average = if end.is_last_key_block {
+            start.entry_count
+        } else {
+            (start.entry_count + end.entry_count) / 2
+        };
+        Ok(start.entry_count.saturating_sub(start.position) + between * average + end.position)
+    }
+
+    /// Descends the index to the key block that would contain `hash` and finds the position of
+    /// the first entry with a hash of at least `hash` in it.
+    fn locate_hash<S: BuildHasher + Clone>(
+        &self,
+        header: &MappedHeader,
+        hash: u64,
+        key_block_cache: &BlockCache<S>,
+    ) -> Result<HashLocation> {
+        let mut path = Vec::new();
+        let mut is_last_key_block = true;
+        let mut current_block = header.block_count - 1;
+        loop {
+            let block_arc = self.get_key_block(
+                header,
+                current_block,
+                key_block_cache,
+                &LookupOptions::default(),
+                None,
+            )?;
+            let mut block = &block_arc[..];
+            let block_type = block.read_u8()?;
+            match block_type {
+                BLOCK_TYPE_INDEX => {
+                    let (position, child_count, next_block) = index_block_position(block, hash)?;
+                    check_index_child(header, current_block, next_block)?;
+                    is_last_key_block &= position + 1 == child_count;
+                    path.push((position, child_count));
+                    current_block = next_block;
+                }
+                BLOCK_TYPE_KEY | BLOCK_TYPE_KEY_WITH_SEQUENCE_NUMBERS => {
+                    let format = KeyBlockFormat::new(header, block_type);
+                    let KeyBlockLayout {
+                        entry_count,
+                        offsets,
+                        entries,
+                        ..
+                    } = key_block_layout(&block_arc, format)?;
+                    let offsets = &block_arc[offsets];
+                    let entries = &block_arc[entries];
+                    let mut l = 0;
+                    let mut r = entry_count;
+                    while l < r {
+                        let m = (l + r) / 2;
+                        if get_key_entry(offsets, entries, entry_count, m, format)?.hash < hash {
+                            l = m + 1;
+                        } else {
+                            r = m;
+                        }
+                    }
+                    return Ok(HashLocation {
+                        path,
+                        position: l,
+                        entry_count,
+                        is_last_key_block,
+                    });
+                }
+                _ => bail!("Invalid block type in block {current_block}"),
+            }
+        }
+    }
+
+    /// Returns the number of blocks in this file. This includes index, key and value blocks.
+    pub fn block_count(&self) -> Result<usize> {
+        Ok(self.header()?.block_count as usize)
+    }
+
+    /// Counts the entries in this file, including deleted entries. Only index and key blocks are
+    /// read.
+    pub fn entry_count(&self) -> Result<usize> {
+        let header = &self.header()?;
+        if header.block_count == 0 {
+            return Ok(0);
+        }
+        let mut entry_count = 0;
+        let mut stack = vec![header.block_count - 1];
+        while let Some(block_index) = stack.pop() {
+            let block = self.read_key_block(header, block_index)?;
+            let mut block = &block[..];
+            match block.read_u8()? {
+                BLOCK_TYPE_INDEX => {
+                    for child in index_block_children(block)? {
+                        check_index_child(header, block_index, child)?;
+                        stack.push(child);
+                    }
+                }
+                BLOCK_TYPE_KEY | BLOCK_TYPE_KEY_WITH_SEQUENCE_NUMBERS => {
+                    entry_count += block.read_u24::<BE>()? as usize;
+                }
+                _ => bail!("Invalid block type in block {block_index}"),
+            }
+        }
+        Ok(entry_count)
+    }
+
+    /// Returns the content of all index blocks of this file, starting with the root index block and
+    /// then in depth-first order. This is intended for debugging tools that visualize the index.
+    /// Key blocks are only partially decompressed to find their block type.
+    pub fn index_structure(&self) -> Result<Vec<IndexBlockInfo>> {
+        self.index_blocks(&self.header()?)
+    }
+
+    /// Reads the index blocks of a mapping, see [StaticSortedFile::index_structure].
+    fn index_blocks(&self, header: &MappedHeader) -> Result<Vec<IndexBlockInfo>> {
+        if header.block_count == 0 {
+            return Ok(Vec::new());
+        }
+        let dictionary = self.key_compression_dictionary(header)?;
+        let mut index_blocks = Vec::new();
+        let mut stack = vec![header.block_count - 1];
+        while let Some(block_index) = stack.pop() {
+            let block_type = self.read_block(header, block_index, dictionary, 1)?[0];
+            match block_type {
+                BLOCK_TYPE_INDEX => {
+                    let block = self.read_key_block(header, block_index)?;
+                    let children = index_block_children(&block[1..])?;
+                    for &child in children.iter().rev() {
+                        check_index_child(header, block_index, child)?;
+                        stack.push(child);
+                    }
+                    index_blocks.push(IndexBlockInfo {
+                        block_index,
+                        children,
+                        separator_hashes: index_block_separator_hashes(&block[1..])?,
+                    });
+                }
+                BLOCK_TYPE_KEY | BLOCK_TYPE_KEY_WITH_SEQUENCE_NUMBERS => {}
+                _ => bail!("Invalid block type in block {block_index}"),
+            }
+        }
+        Ok(index_blocks)
+    }
+
+    /// Counts the index and key blocks of this file and measures the depth and fanout of the
+    /// index. Key blocks are only partially decompressed to find their block type.
+    pub fn index_shape(&self) -> Result<IndexShape> {
+        let header = &self.header()?;
+        let mut shape = IndexShape::default();
+        if header.block_count == 0 {
+            return Ok(shape);
+        }
+        let dictionary = self.key_compression_dictionary(header)?;
+        let mut stack = vec![(header.block_count - 1, 1)];
+        while let Some((block_index, depth)) = stack.pop() {
+            let block_type = self.read_block(header, block_index, dictionary, 1)?[0];
+            match block_type {
+                BLOCK_TYPE_INDEX => {
+                    let block = self.read_key_block(header, block_index)?;
+                    let children = index_block_children(&block[1..])?;
+                    for &child in children.iter() {
+                        check_index_child(header, block_index, child)?;
+                        stack.push((child, depth + 1));
+                    }
+                    shape.index_blocks += 1;
+                    shape.depth = shape.depth.max(depth);
+                    shape.max_fanout = shape.max_fanout.max(children.len());
+                }
+                BLOCK_TYPE_KEY | BLOCK_TYPE_KEY_WITH_SEQUENCE_NUMBERS => shape.key_blocks += 1,
+                _ => bail!("Invalid block type in block {block_index}"),
+            }
+        }
+        Ok(shape)
+    }
+
+    /// Returns the sequence numbers of all blob files that entries of this file refer to, sorted
+    /// and without duplicates. Only index and key blocks are read.
+    pub fn blob_references(&self) -> Result<Vec<u32>> {
+        let header = &self.header()?;
+        if header.block_count == 0 {
+            return Ok(Vec::new());
+        }
+        let mut blobs = Vec::new();
+        let mut stack = vec![header.block_count - 1];
+        while let Some(block_index) = stack.pop() {
+            let block_arc = self.read_key_block(header, block_index)?;
+            let mut block = &block_arc[..];
+            let block_type = block.read_u8()?;
+            match block_type {
+                BLOCK_TYPE_INDEX => {
+                    for child in index_block_children(block)? {
+                        check_index_child(header, block_index, child)?;
+                        stack.push(child);
+                    }
+                }
+                BLOCK_TYPE_KEY | BLOCK_TYPE_KEY_WITH_SEQUENCE_NUMBERS => {
+                    let format = KeyBlockFormat::new(header, block_type);
+                    let KeyBlockLayout {
+                        entry_count,
+                        offsets,
+                        entries,
+                        ..
+                    } = key_block_layout(&block_arc, format)?;
+                    let offsets = &block_arc[offsets];
+                    let entries = &block_arc[entries];
+                    for index in 0..entry_count {
+                        let entry = get_key_entry(offsets, entries, entry_count, index, format)?;
+                        if entry.ty == KEY_BLOCK_ENTRY_TYPE_BLOB {
+                            blobs.push((&entry.val[..]).read_u32::<BE>()?);
+                        }
+                    }
+                }
+                _ => bail!("Invalid block type in block {block_index}"),
+            }
+        }
+        blobs.sort_unstable();
+        blobs.dedup();
+        Ok(blobs)
+    }
+
+    /// Returns the decompressed size of a block without decompressing it.
+    pub fn block_uncompressed_len(&self, block_index: u16) -> Result<usize> {
+        self.uncompressed_len(&self.header()?, block_index)
+    }
+
+    /// Reads the uncompressed length of a block of a mapping, see
+    /// [StaticSortedFile::block_uncompressed_len].
+    fn uncompressed_len(&self, header: &MappedHeader, block_index: u16) -> Result<usize> {
+        if block_index >= header.block_count {
+            bail!(
+                "Block {block_index} is out of range, the file has {} blocks",
+                header.block_count
+            );
+        }
+        let location = self.block_location(header, block_index)?;
+        Ok((&*header.read(location.start..location.start + 4)?).read_u32::<BE>()? as usize)
+    }
+
+    /// Returns the compressed and decompressed size of each block without decompressing it. The
+    /// compressed size doesn't include the uncompressed length prefix of the block.
+    pub fn block_ratios(&self) -> Result<Vec<(usize, usize)>> {
+        let header = &self.header()?;
+        (0..header.block_count)
+            .map(|block_index| {
+                let location = self.block_location(header, block_index)?;
+                if location.end < location.start + 4 {
+                    bail!(
+                        "Corrupted file seq:{} block:{} is too small ({} bytes)",
+                        self.sequence_number,
+                        block_index,
+                        location.end.saturating_sub(location.start)
+                    );
+                }
+                let uncompressed_length =
+                    (&*header.read(location.start..location.start + 4)?).read_u32::<BE>()? as usize;
+                Ok((location.end - location.start - 4, uncompressed_length))
+            })
+            .collect()
+    }
+
+    /// Returns the serialized AQMF filter of this file. Memory mapped and in-memory files return
+    /// it without copying.
+    pub fn aqmf_bytes(&self) -> Result<impl std::ops::Deref<Target = [u8]>> {
+        let header = &self.header()?;
+        header.read_mapped(header.aqmf.start..header.aqmf.end)
+    }
+
+    /// Returns the dictionary that the key blocks of this file are compressed with. It's empty if
+    /// the keys are compressed without a dictionary.
+    pub fn key_dictionary(&self) -> Result<Vec<u8>> {
+        Ok(self.key_compression_dictionary(&self.header()?)?.to_vec())
+    }
+
+    /// Returns the dictionary that the value blocks of this file are compressed with. It's empty if
+    /// the values are compressed without a dictionary.
+    pub fn value_dictionary(&self) -> Result<Vec<u8>> {
+        Ok(self.value_compression_dictionary(&self.header()?)?.to_vec())
+    }
+
+    /// Estimates how much memory this file needs when all blocks and the AQMF filter are loaded.
+    pub fn memory_estimate(&self) -> Result<MemoryEstimate> {
+        let header = &self.header()?;
+        let mut decompressed_blocks = 0;
+        for block_index in 0..header.block_count {
+            decompressed_blocks += self.uncompressed_len(header, block_index)? as u64 + 8;
+        }
+        let filter = if header.aqmf.is_empty() {
+            0
+        } else {
+            self.decode_filter(header, &header.read(header.aqmf.start..header.aqmf.end)?)?
+                .weight()
+        };
+        Ok(MemoryEstimate {
+            mmapped: header.mapping.backing.size(),
+            decompressed_blocks,
+            filter,
+        })
+    }
+
+    /// Returns the length of the file in bytes.
+    pub fn file_len(&self) -> usize {
+        self.mapping().file_len()
+    }
+
+    /// Returns the current mapping of this file.
+    fn mapping(&self) -> Arc<Mapping> {
+        self.mapping.read().clone()
+    }
+
+    /// Reads and parses the header of the current mapping, see [Mapping::header].
+    fn header(&self) -> Result<MappedHeader> {
+        self.mapping().header()
+    }
+
+    /// Returns the format version of this file.
+    pub fn format_version(&self) -> Result<u8> {
+        Ok(self.header()?.format_version)
+    }
+
+    /// Returns the key family and hash range of this file.
+    pub fn range(&self) -> Result<StaticSortedFileRange> {
+        let header = &self.header()?;
+        Ok(StaticSortedFileRange {
+            family: header.family,
+            min_hash: header.min_hash,
+            max_hash: header.max_hash,
+        })
+    }
+
+    /// Returns false if the key is definitely not in this file. This checks the key family, the
+    /// hash range and the AQMF filter, but doesn't read any index or key blocks. Each index child
+    /// covers the hashes up to the next separator, so the index can't rule out more keys.
+    pub fn can_contain<S: BuildHasher + Clone>(
+        &self,
+        key_family: u32,
+        key_hash: u64,
+        aqmf_cache: &AqmfCache<S>,
+    ) -> Result<bool> {
+        Ok(self.filter_contains_many(key_family, &[key_hash], aqmf_cache)?[0])
+    }
+
+    /// Returns true if this file may contain keys that are also in the range of `other`. Entries
+    /// are ordered by key hash, so this compares the key families and hash ranges from the
+    /// headers.
+    pub fn overlaps(&self, other: &StaticSortedFile) -> Result<bool> {
+        Ok(self.range()?.overlaps(&other.range()?))
+    }
+
+    /// Iterate over all entries in this file in sorted order.
+    pub fn iter<'l, S: BuildHasher + Clone>(
+        &'l self,
+        key_block_cache: &'l BlockCache<S>,
+        value_block_cache: &'l BlockCache<S>,
+    ) -> Result<StaticSortedFileIter<'l, S>> {
+        self.iter_internal(key_block_cache, value_block_cache, false)
+    }
+
+    /// Iterate over all entries in this file in sorted order, skipping blocks that can't be read.
+    /// This is meant for best-effort recovery. The entries of corrupt key and index blocks and
+    /// entries whose values are stored in corrupt value blocks are skipped. The skipped blocks
+    /// can be retrieved with [StaticSortedFileIter::into_corrupt_blocks] after iterating.
+    pub fn iter_skipping_corrupt_blocks<'l, S: BuildHasher + Clone>(
+        &'l self,
+        key_block_cache: &'l BlockCache<S>,
+        value_block_cache: &'l BlockCache<S>,
+    ) -> Result<StaticSortedFileIter<'l, S>> {
+        self.iter_internal(key_block_cache, value_block_cache, true)
+    }
+
+    fn iter_internal<'l, S: BuildHasher + Clone>(
+        &'l self,
+        key_block_cache: &'l BlockCache<S>,
+        value_block_cache: &'l BlockCache<S>,
+        skip_corrupt_blocks: bool,
+    ) -> Result<StaticSortedFileIter<'l, S>> {
+        let header = &self.header()?;
+        let mut iter = StaticSortedFileIter {
+            this: self,
+            key_block_cache,
+            value_block_cache,
+            header: header.clone(),
+            stack: Vec::new(),
+            current_key_block: None,
+            corrupt_blocks: skip_corrupt_blocks.then(Vec::new),
+            duplicate_keys: DuplicateKeys::Yield,
+            peeked: None,
+            readahead: None,
+        };
+        if header.block_count > 0 {
+            iter.enter_block_or_skip(header.block_count - 1)?;
+        }
+        Ok(iter)
+    }
+
+    /// Iterate over all live entries in this file in sorted order and yield their keys and
+    /// values. Deleted entries are skipped. Blob values are stored outside of this file and are
+    /// skipped as well.
+    pub fn entries<'l, S: BuildHasher + Clone>(
+        &'l self,
+        key_block_cache: &'l BlockCache<S>,
+        value_block_cache: &'l BlockCache<S>,
+    ) -> Result<impl Iterator<Item = Result<(ArcSlice<u8>, ArcSlice<u8>)>> + 'l> {
+        Ok(self
+            .iter(key_block_cache, value_block_cache)?
+            .filter_map(|entry| match entry {
+                Ok(LookupEntry {
+                    key,
+                    value: LookupValue::Slice { value },
+                    ..
+                }) => Some(Ok((key, value))),
+                Ok(LookupEntry {
+                    value: LookupValue::Blob { .. } | LookupValue::Deleted,
+                    ..
+                }) => None,
+                Err(err) => Some(Err(err)),
+            }))
+    }
+
+    /// Iterate over all entries in this file in sorted order without reading their values. The
+    /// value of an entry is read when [ScanEntry::value] is called, so key-only scans don't
+    /// decompress any value blocks.
+    pub fn scan<'l, S: BuildHasher + Clone>(
+        &'l self,
+        key_block_cache: &'l BlockCache<S>,
+        value_block_cache: &'l BlockCache<S>,
+    ) -> Result<impl Iterator<Item = Result<ScanEntry<'l, S>>> + 'l> {
+        let header = self.header()?;
+        let mut iter = self.iter(key_block_cache, value_block_cache)?;
+        Ok(std::iter::from_fn(move || {
+            iter.next_with(|hash, key, ty, val, _| {
+                Ok(ScanEntry {
+                    hash,
+                    key,
+                    value_ref: ValueRef::decode(ty, val)?,
+                    file: self,
+                    header: header.clone(),
+                    value_block_cache,
+                })
+            })
+            .transpose()
+        }))
+    }
+
+    /// Iterate over all entries in this file whose key starts with `prefix`. An empty prefix
+    /// yields all entries.
+    ///
+    /// Entries are sorted by key hash, so keys with a common prefix are spread over the whole
+    /// file and all key blocks need to be visited. Values are only read for matching entries.
+    pub fn scan_prefix<'l, S: BuildHasher + Clone>(
+        &'l self,
+        prefix: &'l [u8],
+        key_block_cache: &'l BlockCache<S>,
+        value_block_cache: &'l BlockCache<S>,
+    ) -> Result<impl Iterator<Item = Result<LookupEntry>> + 'l> {
+        self.scan_filtered(
+            move |key| key.starts_with(prefix),
+            key_block_cache,
+            value_block_cache,
+        )
+    }
+
+    /// Iterate over all entries in this file whose key matches `predicate`. The predicate is
+    /// called with the key before the value is resolved, so value blocks are only decompressed
+    /// for matching entries.
+    pub fn scan_filtered<'l, S: BuildHasher + Clone>(
+        &'l self,
+        predicate: impl Fn(&[u8]) -> bool + 'l,
+        key_block_cache: &'l BlockCache<S>,
+        value_block_cache: &'l BlockCache<S>,
+    ) -> Result<impl Iterator<Item = Result<LookupEntry>> + 'l> {
+        let mut iter = self.iter(key_block_cache, value_block_cache)?;
+        Ok(std::iter::from_fn(move || {
+            iter.next_matching(&predicate).transpose()
+        }))
+    }
+
+    /// Returns up to `n` entries from the start of this file in sorted order. This descends to the
+    /// leftmost key block and only reads further key blocks when it has less than `n` entries.
+    pub fn head<S: BuildHasher + Clone>(
+        &self,
+        n: usize,
+        key_block_cache: &BlockCache<S>,
+        value_block_cache: &BlockCache<S>,
+    ) -> Result<Vec<(ArcSlice<u8>, LookupResult)>> {
+        self.edge_entries(n, false, key_block_cache, value_block_cache)
+    }
+
+    /// Returns up to `n` entries from the end of this file in sorted order. This is the
+    /// counterpart of [StaticSortedFile::head] and descends to the rightmost key block.
+    pub fn tail<S: BuildHasher + Clone>(
+        &self,
+        n: usize,
+        key_block_cache: &BlockCache<S>,
+        value_block_cache: &BlockCache<S>,
+    ) -> Result<Vec<(ArcSlice<u8>, LookupResult)>> {
+        let mut entries = self.edge_entries(n, true, key_block_cache, value_block_cache)?;
+        entries.reverse();
+        Ok(entries)
+    }
+
+    /// Collects up to `n` entries by walking the index depth-first from the left or, when
+    /// `from_end` is set, from the right. Entries are returned in the order they were visited.
+    fn edge_entries<S: BuildHasher + Clone>(
+        &self,
+        n: usize,
+        from_end: bool,
+        key_block_cache: &BlockCache<S>,
+        value_block_cache: &BlockCache<S>,
+    ) -> Result<Vec<(ArcSlice<u8>, LookupResult)>> {
+        let header = &self.header()?;
+        let mut result = Vec::new();
+        if header.block_count == 0 || n == 0 {
+            return Ok(result);
+        }
+        let options = LookupOptions::default();
+        let mut stack = vec![header.block_count - 1];
+        while let Some(block_index) = stack.pop() {
+            let block = self.get_key_block(header, block_index, key_block_cache, &options, None)?;
+            let block_type = *block
+                .first()
+                .ok_or_else(|| anyhow!("Empty block {block_index}"))?;
+            match block_type {
+                BLOCK_TYPE_INDEX => {
+                    let mut children = index_block_children(&block[1..])?;
+                    if !from_end {
+                        children.reverse();
+                    }
+                    for child in children {
+                        check_index_child(header, block_index, child)?;
+                        stack.push(child);
+                    }
+                }
+                BLOCK_TYPE_KEY | BLOCK_TYPE_KEY_WITH_SEQUENCE_NUMBERS => {
+                    let format = KeyBlockFormat::new(header, block_type);
+                    let KeyBlockLayout {
+                        entry_count,
+                        prefix,
+                        offsets,
+                        entries,
+                    } = key_block_layout(&block, format)?;
+                    let prefix = &block[prefix];
+                    let offsets = &block[offsets];
+                    let entries = &block[entries];
+                    let take = entry_count.min(n - result.len());
+                    let indices = if from_end {
+                        entry_count - take..entry_count
+                    } else {
+                        0..take
+                    };
+                    let mut block_entries = indices
+                        .map(|index| {
+                            let entry =
+                                get_key_entry(offsets, entries, entry_count, index, format)?;
+                            let value = self.handle_key_match(
+                                entry.ty,
+                                entry.val,
+                                header,
+                                value_block_cache,
+                                &options,
+                            )?;
+                            Ok((stored_key(prefix, entry.key, &block), value.into()))
+                        })
+                        .collect::<Result<Vec<_>>>()?;
+                    if from_end {
+                        block_entries.reverse();
+                    }
+                    result.extend(block_entries);
+                    if result.len() >= n {
+                        break;
+                    }
+                }
+                _ => bail!("Invalid block type in block {block_index}"),
+            }
+        }
+        Ok(result)
+    }
+
+    /// Reads a small value at a known location, e. g. from a [DumpEntry::Small] of an earlier
+    /// scan. This skips the index and key blocks and only reads the value block. The location is
+    /// bounds checked, but a wrong location in a valid block returns wrong bytes.
+    pub fn read_value_at<S: BuildHasher + Clone>(
+        &self,
+        block: u16,
+        position: u32,
+        size: u16,
+        value_block_cache: &BlockCache<S>,
+    ) -> Result<ArcSlice<u8>> {
+        let header = &self.header()?;
+        self.read_small_value(
+            header,
+            block,
+            position,
+            size,
+            value_block_cache,
+            &LookupOptions::default(),
+            None,
+        )
+    }
+
+    /// Returns a reader that decompresses a value block while it's read, e. g. a
+    /// [DumpEntry::Medium] block. Unlike lookups this doesn't materialize the whole block, only a
+    /// 64 KiB window is kept in memory. The block is not cached.
+    pub fn value_block_reader(&self, block: u16) -> Result<impl Read> {
+        let header = &self.header()?;
+        if block >= header.block_count {
+            return Err(StaticSortedFileError::CorruptValueRef {
+                block,
+                block_count: header.block_count,
+            }
+            .into());
+        }
+        let dictionary = self.value_compression_dictionary(header)?.to_vec();
+        let (data, uncompressed_length) = self.read_compressed_block(header, block)?;
+        let data = match data {
+            Cow::Borrowed(_) => {
+                let LocationInFile { start, end } = self.block_location(header, block)?;
+                MappedBytes::Mapped {
+                    mapping: header.mapping.clone(),
+                    range: start..end,
+                }
+            }
+            Cow::Owned(data) => MappedBytes::Owned(data),
+        };
+        Ok(BlockReader::new(
+            block,
+            data,
+            4,
+            uncompressed_length,
+            dictionary,
+        ))
+    }
+
+    /// Computes a hash of the logical content of this file, i. e. the keys, entry types and
+    /// values in sorted order. It doesn't depend on the sequence number, the compression or the
+    /// block layout, so files with the same entries hash equally. Sequence numbers of writes are
+    /// not included.
+    pub fn content_hash<S: BuildHasher + Clone>(
+        &self,
+        key_block_cache: &BlockCache<S>,
+        value_block_cache: &BlockCache<S>,
+    ) -> Result<u64> {
+        let mut hasher = twox_hash::XxHash64::with_seed(0);
+        for entry in self.iter(key_block_cache, value_block_cache)? {
+            let LookupEntry { key, value, .. } = entry?;
+            hasher.write_u64(key.len() as u64);
+            hasher.write(&key);
+            match value {
+                LookupValue::Slice { value } => {
+                    hasher.write_u8(KEY_BLOCK_ENTRY_TYPE_SMALL);
+                    hasher.write_u64(value.len() as u64);
+                    hasher.write(&value);
+                }
+                LookupValue::Blob { sequence_number } => {
+                    hasher.write_u8(KEY_BLOCK_ENTRY_TYPE_BLOB);
+                    hasher.write_u32(sequence_number);
+                }
+                LookupValue::Deleted => hasher.write_u8(KEY_BLOCK_ENTRY_TYPE_DELETED),
+            }
+        }
+        Ok(hasher.finish())
+    }
+
+    /// Returns all keys in this file in sorted order with their decoded entry type. This is
+    /// intended for debugging. When `resolve_values` is set, small values are read from their
+    /// value blocks.
+    pub fn dump<S: BuildHasher + Clone>(
+        &self,
+        resolve_values: bool,
+        key_block_cache: &BlockCache<S>,
+        value_block_cache: &BlockCache<S>,
+    ) -> Result<Vec<(ArcSlice<u8>, DumpEntry)>> {
+        let header = &self.header()?;
+        let mut iter = self.iter(key_block_cache, value_block_cache)?;
+        let mut result = Vec::new();
+        while let Some(item) = iter.next_with(|_, key, ty, val, _| {
+            let entry = match ValueRef::decode(ty, val)? {
+                ValueRef::Small {
+                    block,
+                    position,
+                    size,
+                } => {
+                    let value = if resolve_values {
+                        Some(self.read_small_value(
+                            header,
+                            block,
+                            position,
+                            size,
+                            value_block_cache,
+                            &LookupOptions::default(),
+                            None,
+                        )?)
+                    } else {
+                        None
+                    };
+                    DumpEntry::Small {
+                        block,
+                        position,
+                        size,
+                        value,
+                    }
+                }
+                ValueRef::Medium { block } => DumpEntry::Medium { block },
+                ValueRef::Blob { sequence_number } => DumpEntry::Blob { sequence_number },
+                ValueRef::Deleted => DumpEntry::Deleted,
+            };
+            Ok((key, entry))
+        })? {
+            result.push(item);
+        }
+        Ok(result)
+    }
+
+    /// Returns the keys in sorted order whose small or medium value is stored in the value block
+    /// `block_index`. Only index and key blocks are read, which allows to find the keys that make a
+    /// value block hot.
+    pub fn keys_for_value_block<S: BuildHasher + Clone>(
+        &self,
+        block_index: u16,
+        key_block_cache: &BlockCache<S>,
+        value_block_cache: &BlockCache<S>,
+    ) -> Result<Vec<ArcSlice<u8>>> {
+        check_block_index(&*self.header()?, block_index)?;
+        let mut iter = self.iter(key_block_cache, value_block_cache)?;
+        let mut keys = Vec::new();
+        while let Some(key) = iter.next_with(|_, key, ty, val, _| {
+            Ok(match ValueRef::decode(ty, val)? {
+                ValueRef::Small { block, .. } | ValueRef::Medium { block }
+                    if block == block_index =>
+                {
+                    Some(key)
+                }
+                _ => None,
+            })
+        })? {
+            keys.extend(key);
+        }
+        Ok(keys)
+    }
+
+    /// Looks up a key in this file.
+    ///
+    /// If a corrupt file contains the key multiple times, the entry that the binary search over
+    /// the key block hits first is returned. This is the same entry for every lookup of that key.
+    pub fn lookup<K: QueryKey, S: BuildHasher + Clone>(
+        &self,
+        key_family: u32,
+        key_hash: u64,
+        key: &K,
+        aqmf_cache: &AqmfCache<S>,
+        key_block_cache: &BlockCache<S>,
+        value_block_cache: &BlockCache<S>,
+    ) -> Result<LookupResult> {
+        self.lookup_with_options(
+            key_family,
+            key_hash,
+            key,
+            aqmf_cache,
+            key_block_cache,
+            value_block_cache,
+            &LookupOptions::default(),
+        )
+    }
+
+    /// Looks up a key in this file with the given options.
+    pub fn lookup_with_options<K: QueryKey, S: BuildHasher + Clone>(
+        &self,
+        key_family: u32,
+        key_hash: u64,
+        key: &K,
+        aqmf_cache: &AqmfCache<S>,
+        key_block_cache: &BlockCache<S>,
+        value_block_cache: &BlockCache<S>,
+        options: &LookupOptions,
+    ) -> Result<LookupResult> {
+        self.lookup_internal(
+            key_family,
+            key_hash,
+            key,
+            aqmf_cache,
+            key_block_cache,
+            value_block_cache,
+            options,
+            None,
+        )
+    }
+
+    /// Looks up a key in this file and records which blocks were visited. This is intended for
+    /// diagnostics and returns the same result as `lookup_with_options`.
+    pub fn lookup_traced<K: QueryKey, S: BuildHasher + Clone>(
+        &self,
+        key_family: u32,
+        key_hash: u64,
+        key: &K,
+        aqmf_cache: &AqmfCache<S>,
+        key_block_cache: &BlockCache<S>,
+        value_block_cache: &BlockCache<S>,
+        options: &LookupOptions,
+    ) -> Result<(LookupResult, LookupTrace)> {
+        let mut trace = LookupTrace::default();
+        let result = self.lookup_internal(
+            key_family,
+            key_hash,
+            key,
+            aqmf_cache,
+            key_block_cache,
+            value_block_cache,
+            options,
+            Some(&mut trace),
+        )?;
+        Ok((result, trace))
+    }
+
+    /// Looks up a key in this file and records the visited blocks in `trace` if provided.
+    fn lookup_internal<K: QueryKey, S: BuildHasher + Clone>(
+        &self,
+        key_family: u32,
+        key_hash: u64,
+        key: &K,
+        aqmf_cache: &AqmfCache<S>,
+        key_block_cache: &BlockCache<S>,
+        value_block_cache: &BlockCache<S>,
+        options: &LookupOptions,
+        mut trace: Option<&mut LookupTrace>,
+    ) -> Result<LookupResult> {
+        #[cfg(feature = "tracing")]
+        let _span =
+            tracing::trace_span!("sst lookup", sequence_number = self.sequence_number).entered();
+        let header = &self.header()?;
+        match self.search(
+            header,
+            key_family,
+            key_hash,
+            key,
+            aqmf_cache,
+            key_block_cache,
+            options,
+            trace.as_deref_mut(),
+        )? {
+            SearchResult::Found(_, value_ref, _) => Ok(self
+                .resolve_value(value_ref, header, value_block_cache, options, trace)?
+                .into()),
+            SearchResult::Miss(result) => Ok(result),
+        }
+    }
+
+    /// Looks up a key in this file as of the write with the given sequence number. Entries that
+    /// were written later are treated as missing.
+    pub fn lookup_as_of<K: QueryKey, S: BuildHasher + Clone>(
+        &self,
+        key_family: u32,
+        key_hash: u64,
+        key: &K,
+        max_sequence_number: u32,
+        aqmf_cache: &AqmfCache<S>,
+        key_block_cache: &BlockCache<S>,
+        value_block_cache: &BlockCache<S>,
+    ) -> Result<LookupResult> {
+        self.lookup_with_options(
+            key_family,
+            key_hash,
+            key,
+            aqmf_cache,
+            key_block_cache,
+            value_block_cache,
+            &LookupOptions {
+                max_sequence_number: Some(max_sequence_number),
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Looks up a key in this file and returns the stored key together with the value. Returns
+    /// `None` when the key is not in this file.
+    pub fn lookup_entry<K: QueryKey, S: BuildHasher + Clone>(
+        &self,
+        key_family: u32,
+        key_hash: u64,
+        key: &K,
+        aqmf_cache: &AqmfCache<S>,
+        key_block_cache: &BlockCache<S>,
+        value_block_cache: &BlockCache<S>,
+        options: &LookupOptions,
+    ) -> Result<Option<LookupEntry>> {
+        let header = &self.header()?;
+        match self.search(
+            header,
+            key_family,
+            key_hash,
+            key,
+            aqmf_cache,
+            key_block_cache,
+            options,
+            None,
+        )? {
+            SearchResult::Found(stored_key, value_ref, write_sequence_number) => {
+                Ok(Some(LookupEntry {
+                    hash: key_hash,
+                    key: stored_key,
+                    value: self.resolve_value(
+                        value_ref,
+                        header,
+                        value_block_cache,
+                        options,
+                        None,
+                    )?,
+                    write_sequence_number,
+                }))
+            }
+            SearchResult::Miss(_) => Ok(None),
+        }
+    }
+
+    /// Looks up a key in this file and returns at most the first `max_len` bytes of its value.
+    /// Medium values are only decompressed up to `max_len` bytes, so reading a small prefix of a
+    /// large value doesn't decompress the whole value.
+    pub fn lookup_value_prefix<K: QueryKey, S: BuildHasher + Clone>(
+        &self,
+        key_family: u32,
+        key_hash: u64,
+        key: &K,
+        max_len: usize,
+        aqmf_cache: &AqmfCache<S>,
+        key_block_cache: &BlockCache<S>,
+        value_block_cache: &BlockCache<S>,
+        options: &LookupOptions,
+    ) -> Result<LookupResult> {
+        let header = &self.header()?;
+        match self.search(
+            header,
+            key_family,
+            key_hash,
+            key,
+            aqmf_cache,
+            key_block_cache,
+            options,
+            None,
+        )? {
+            SearchResult::Found(_, ValueRef::Medium { block }, _) => Ok(LookupResult::Slice {
+                value: self.read_value_block_prefix(header, block, max_len)?,
+            }),
+            SearchResult::Found(_, value_ref, _) => Ok(
+                match self.resolve_value(value_ref, header, value_block_cache, options, None)? {
+                    LookupValue::Slice { value } if value.len() > max_len => LookupResult::Slice {
+                        value: value.slice(0..max_len),
+                    },
+                    value => value.into(),
+                },
+            ),
+            SearchResult::Miss(result) => Ok(result),
+        }
+    }
+
+    /// Looks up a key in this file and returns the size of its value without reading the value.
+    /// Returns `None` if the key is not in this file, was deleted or its value is stored in a
+    /// blob file.
+    pub fn value_size<K: QueryKey, S: BuildHasher + Clone>(
+        &self,
+        key_family: u32,
+        key_hash: u64,
+        key: &K,
+        aqmf_cache: &AqmfCache<S>,
+        key_block_cache: &BlockCache<S>,
+        options: &LookupOptions,
+    ) -> Result<Option<usize>> {
+        let header = &self.header()?;
+        match self.search(
+            header,
+            key_family,
+            key_hash,
+            key,
+            aqmf_cache,
+            key_block_cache,
+            options,
+            None,
+        )? {
+            SearchResult::Found(_, ValueRef::Small { size, .. }, _) => Ok(Some(size as usize)),
+            SearchResult::Found(_, ValueRef::Medium { block }, _) => {
+                Ok(Some(self.uncompressed_len(header, block)?))
+            }
+            SearchResult::Found(_, ValueRef::Blob { .. } | ValueRef::Deleted, _)
+            | SearchResult::Miss(_) => Ok(None),
+        }
+    }
+
+    /// Looks up a key in this file and returns the `KEY_BLOCK_ENTRY_TYPE_*` of its entry without
+    /// reading the value. Returns `None` if the key is not in this file.
+    pub fn entry_type<K: QueryKey, S: BuildHasher + Clone>(
+        &self,
+        key_family: u32,
+        key_hash: u64,
+        key: &K,
+        aqmf_cache: &AqmfCache<S>,
+        key_block_cache: &BlockCache<S>,
+        options: &LookupOptions,
+    ) -> Result<Option<u8>> {
+        let header = &self.header()?;
+        match self.search(
+            header,
+            key_family,
+            key_hash,
+            key,
+            aqmf_cache,
+            key_block_cache,
+            options,
+            None,
+        )? {
+            SearchResult::Found(_, value_ref, _) => Ok(Some(value_ref.entry_type())),
+            SearchResult::Miss(_) => Ok(None),
+        }
+    }
+
+    /// Looks up a key that is passed as a pointer and a length, e. g. by a caller across a C FFI
+    /// boundary. The key hash is computed from the key.
+    ///
+    /// # Safety
+    ///
+    /// `key_ptr` must point to `key_len` initialized bytes that are not mutated during the call.
+    /// It may be null if `key_len` is zero.
+    #[cfg(feature = "ffi")]
+    pub unsafe fn lookup_raw<S: BuildHasher + Clone>(
+        &self,
+        key_family: u32,
+        key_ptr: *const u8,
+        key_len: usize,
+        aqmf_cache: &AqmfCache<S>,
+        key_block_cache: &BlockCache<S>,
+        value_block_cache: &BlockCache<S>,
+    ) -> Result<LookupResult> {
+        let key = if key_len == 0 {
+            &[][..]
+        } else {
+            std::slice::from_raw_parts(key_ptr, key_len)
+        };
+        self.lookup(
+            key_family,
+            crate::key::hash_key(&key),
+            &key,
+            aqmf_cache,
+            key_block_cache,
+            value_block_cache,
+        )
+    }
+
+    /// Looks up a key in this file and appends its value to `out` instead of returning a shared
+    /// slice. Small values are copied out of the cached value block. Medium values are
+    /// decompressed directly into `out`.
+    pub fn lookup_into<K: QueryKey, S: BuildHasher + Clone>(
+        &self,
+        key_family: u32,
+        key_hash: u64,
+        key: &K,
+        out: &mut Vec<u8>,
+        aqmf_cache: &AqmfCache<S>,
+        key_block_cache: &BlockCache<S>,
+        value_block_cache: &BlockCache<S>,
+        options: &LookupOptions,
+    ) -> Result<LookupKind> {
+        let header = &self.header()?;
+        let value_ref = match self.search(
+            header,
+            key_family,
+            key_hash,
+            key,
+            aqmf_cache,
+            key_block_cache,
+            options,
+            None,
+        )? {
+            SearchResult::Found(_, value_ref, _) => value_ref,
+            SearchResult::Miss(LookupResult::RangeMiss) => return Ok(LookupKind::RangeMiss),
+            SearchResult::Miss(LookupResult::QuickFilterMiss) => {
+                return Ok(LookupKind::QuickFilterMiss)
+            }
+            SearchResult::Miss(_) => return Ok(LookupKind::KeyMiss),
+        };
+        Ok(match value_ref {
+            ValueRef::Small {
+                block,
+                position,
+                size,
+            } => {
+                let position = position as usize;
+                let block =
+                    self.get_value_block(header, block, value_block_cache, options, None)?;
+                let Some(value) = block.get(position..position + size as usize) else {
+                    bail!(
+                        "Corrupted file seq:{} value at {position} + {size} is out of the value \
+                         block",
+                        self.sequence_number
+                    );
+                };
+                out.extend_from_slice(value);
+                LookupKind::Slice
+            }
+            ValueRef::Medium { block } => {
+                // Medium value blocks are never cached, so they are decompressed directly.
+                let dictionary = self.value_compression_dictionary(header)?;
+                self.read_block_into(header, block, dictionary, out)?;
+                LookupKind::Slice
+            }
+            ValueRef::Blob { sequence_number } => LookupKind::Blob { sequence_number },
+            ValueRef::Deleted => LookupKind::Deleted,
+        })
+    }
+
+    /// Looks up a key in this file and returns an owned copy of its value that can be mutated
+    /// freely. Medium values are decompressed directly into the returned
+    /// buffer without an extra copy. Returns `None` if the key is missing, deleted or stored in a
+    /// blob file, see [Self::lookup_into] to tell these apart.
+    pub fn lookup_owned<K: QueryKey, S: BuildHasher + Clone>(
+        &self,
+        key_family: u32,
+        key_hash: u64,
+        key: &K,
+        aqmf_cache: &AqmfCache<S>,
+        key_block_cache: &BlockCache<S>,
+        value_block_cache: &BlockCache<S>,
+    ) -> Result<Option<Vec<u8>>> {
+        let mut value = Vec::new();
+        Ok(
+            match self.lookup_into(
+                key_family,
+                key_hash,
+                key,
+                &mut value,
+                aqmf_cache,
+                key_block_cache,
+                value_block_cache,
+                &LookupOptions::default(),
+            )? {
+                LookupKind::Slice => Some(value),
+                _ => None,
+            },
+        )
+    }
+
+    /// Checks for multiple key hashes whether this file might contain them, using only the hash
+    /// range and the AQMF filter. No blocks are read and the filter is loaded at most once. `false`
+    /// means that the key is definitely not in this file. The results are returned in the same
+    /// order as the key hashes.
+    pub fn filter_contains_many<S: BuildHasher + Clone>(
+        &self,
+        key_family: u32,
+        key_hashes: &[u64],
+        aqmf_cache: &AqmfCache<S>,
+    ) -> Result<Vec<bool>> {
+        let header = &self.header()?;
+        let in_range = |key_hash: u64| {
+            key_family == header.family && (header.min_hash..=header.max_hash).contains(&key_hash)
+        };
+        if !key_hashes.iter().any(|&key_hash| in_range(key_hash)) {
+            return Ok(vec![false; key_hashes.len()]);
+        }
+        self.with_aqmf(header, aqmf_cache, &LookupOptions::default(), |aqmf| {
+            key_hashes
+                .iter()
+                .map(|&key_hash| {
+                    in_range(key_hash) && aqmf.is_none_or(|aqmf| aqmf.contains(key_hash))
+                })
+                .collect()
+        })
+    }
+
+    /// Looks up multiple keys in this file. `keys` contains pairs of key hash and key. The results
+    /// are returned in the same order as the keys.
+    ///
+    /// When `LookupOptions::prefetch_value_blocks` is set, all distinct value blocks that are
+    /// needed for the found keys are read in parallel before the values are resolved.
+    pub fn lookup_many<K: QueryKey, S: BuildHasher + Clone + Send + Sync>(
+        &self,
+        key_family: u32,
+        keys: &[(u64, K)],
+        aqmf_cache: &AqmfCache<S>,
+        key_block_cache: &BlockCache<S>,
+        value_block_cache: &BlockCache<S>,
+        options: &LookupOptions,
+    ) -> Result<Vec<LookupResult>> {
+        let header = &self.header()?;
+        if options.prefetch_key_blocks {
+            self.prefetch_key_blocks(
+                header,
+                key_family,
+                keys.iter().map(|(key_hash, _)| *key_hash),
+                key_block_cache,
+                options,
+            );
+        }
+        let search_results = keys
+            .iter()
+            .map(|(key_hash, key)| {
+                self.search(
+                    header,
+                    key_family,
+                    *key_hash,
+                    key,
+                    aqmf_cache,
+                    key_block_cache,
+                    options,
+                    None,
+                )
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if options.prefetch_value_blocks {
+            let mut blocks = search_results
+                .iter()
+                .filter_map(|result| match result {
+                    SearchResult::Found(_, ValueRef::Small { block, .. }, _) => Some(*block),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            // Each block is only read once, even if multiple keys point into it.
+            blocks.sort_unstable();
+            blocks.dedup();
+            blocks.into_par_iter().try_for_each(|block| {
+                self.get_value_block(header, block, value_block_cache, options, None)?;
+                anyhow::Ok(())
+            })?;
+        }
+        search_results
+            .into_iter()
+            .map(|result| match result {
+                SearchResult::Found(_, value_ref, _) => Ok(self
+                    .resolve_value(value_ref, header, value_block_cache, options, None)?
+                    .into()),
+                SearchResult::Miss(result) => Ok(result),
+            })
+            .collect()
+    }
+
+    /// Reads key blocks into the key block cache in parallel. Blocks that are already cached are
+    /// skipped, and blocks are only read as long as they fit into the remaining capacity of the
+    /// cache. Errors are ignored since they are reported when the blocks are used.
+    fn read_ahead_key_blocks<S: BuildHasher + Clone + Send + Sync>(
+        &self,
+        header: &MappedHeader,
+        blocks: &[u16],
+        key_block_cache: &BlockCache<S>,
+    ) {
+        let mut available = key_block_cache
+            .capacity()
+            .saturating_sub(key_block_cache.weight());
+        let blocks = blocks
+            .iter()
+            .copied()
+            .filter(|&block| !key_block_cache.contains_key(&header.block_cache_key(block)))
+            .take_while(|&block| {
+                let Ok(len) = self.uncompressed_len(header, block) else {
+                    return false;
+                };
+                let Some(remaining) = available.checked_sub(len as u64) else {
+                    return false;
+                };
+                available = remaining;
+                true
+            })
+            .collect::<Vec<_>>();
+        blocks.into_par_iter().for_each(|block| {
+            let _ = self.get_key_block(
+                header,
+                block,
+                key_block_cache,
+                &LookupOptions::default(),
+                None,
+            );
+        });
+    }
+
+    /// Reads the index and key blocks that lookups of the given key hashes descend into. The
+    /// blocks of each index level are read in parallel. This is best-effort, errors are reported
+    /// by the lookups.
+    fn prefetch_key_blocks<S: BuildHasher + Clone + Send + Sync>(
+        &self,
+        header: &MappedHeader,
+        key_family: u32,
+        key_hashes: impl Iterator<Item = u64>,
+        key_block_cache: &BlockCache<S>,
+        options: &LookupOptions,
+    ) {
+        if key_family != header.family || header.block_count == 0 {
+            return;
+        }
+        let root = header.block_count - 1;
+        let mut pending = key_hashes
+            .filter(|key_hash| (header.min_hash..=header.max_hash).contains(key_hash))
+            .map(|key_hash| (root, key_hash))
+            .collect::<Vec<_>>();
+        while !pending.is_empty() {
+            let mut blocks = pending.iter().map(|(block, _)| *block).collect::<Vec<_>>();
+            blocks.sort_unstable();
+            blocks.dedup();
+            let blocks = blocks
+                .into_par_iter()
+                .filter_map(|block| {
+                    let block_arc = self
+                        .get_key_block(header, block, key_block_cache, options, None)
+                        .ok()?;
+                    Some((block, block_arc))
+                })
+                .collect::<Vec<_>>();
+            pending = pending
+                .into_iter()
+                .filter_map(|(block, key_hash)| {
+                    let index = blocks.binary_search_by_key(&block, |(b, _)| *b).ok()?;
+                    let mut data = &blocks[index].1[..];
+                    if data.read_u8().ok()? != BLOCK_TYPE_INDEX {
+                        return None;
+                    }
+                    let next_block = self.lookup_index_block(data, key_hash).ok()?;
+                    check_index_child(header, block, next_block).ok()?;
+                    Some((next_block, key_hash))
+                })
+                .collect();
+        }
+    }
+
+    /// Searches a key in this file without reading its value.
+    fn search<K: QueryKey, S: BuildHasher + Clone>(
+        &self,
+        header: &MappedHeader,
+        key_family: u32,
+        key_hash: u64,
+        key: &K,
+        aqmf_cache: &AqmfCache<S>,
+        key_block_cache: &BlockCache<S>,
+        options: &LookupOptions,
+        trace: Option<&mut LookupTrace>,
+    ) -> Result<SearchResult> {
+        if key_family != header.family || key_hash < header.min_hash || key_hash > header.max_hash {
+            return Ok(SearchResult::Miss(LookupResult::RangeMiss));
+        }
+
+        if !options.skip_aqmf && !self.aqmf_contains(header, key_hash, aqmf_cache, options)? {
+            return Ok(SearchResult::Miss(LookupResult::QuickFilterMiss));
+        }
+        let result = self.search_blocks(header, key_hash, key, key_block_cache, options, trace)?;
+        #[cfg(feature = "stats")]
+        if !options.skip_aqmf && !header.aqmf.is_empty() {
+            let counter = match result {
+                SearchResult::Found(..) => &self.aqmf_stats.true_positives,
+                SearchResult::Miss(_) => &self.aqmf_stats.false_positives,
+            };
+            counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        }
+        Ok(result)
+    }
+
+    /// Searches a key in the index and key blocks of this file.
+    fn search_blocks<K: QueryKey, S: BuildHasher + Clone>(
+        &self,
+        header: &MappedHeader,
+        key_hash: u64,
+        key: &K,
+        key_block_cache: &BlockCache<S>,
+        options: &LookupOptions,
+        mut trace: Option<&mut LookupTrace>,
+    ) -> Result<SearchResult> {
+        if header.block_count == 0 {
+            // The file has no entries.
+            return Ok(SearchResult::Miss(LookupResult::KeyMiss));
+        }
+        if let Some(negative_cache) = &self.negative_cache {
+            if !options.disable_caches
+                && negative_cache.contains_key(&(
+                    self.sequence_number,
+                    header.mapping.generation,
+                    key_hash,
+                ))
+            {
+                return Ok(SearchResult::Miss(LookupResult::KeyMiss));
+            }
+        }
+        let mut current_block = header.block_count - 1;
+        loop {
+            let block_arc = self.get_key_block(
+                header,
+                current_block,
+                key_block_cache,
+                options,
+                trace.as_deref_mut(),
+            )?;
+            let mut block = &block_arc[..];
+            let block_type = block.read_u8()?;
+            match block_type {
+                BLOCK_TYPE_INDEX => {
+                    let next_block = self.lookup_index_block(block, key_hash)?;
+                    check_index_child(header, current_block, next_block)?;
+                    if let Some(trace) = trace.as_deref_mut() {
+                        trace.index_blocks.push((current_block, next_block));
+                    }
+                    current_block = next_block;
+                }
+                BLOCK_TYPE_KEY | BLOCK_TYPE_KEY_WITH_SEQUENCE_NUMBERS => {
+                    if let Some(trace) = trace {
+                        trace.key_block = Some(current_block);
+                    }
+                    return self.lookup_key_block(
+                        header,
+                        &block_arc,
+                        KeyBlockFormat::new(header, block_type),
+                        key_hash,
+                        key,
+                        options,
+                    );
+                }
+                _ => {
+                    bail!("Invalid block type");
+                }
+            }
+        }
+    }
+
+    /// Looks up a hash in a index block. A hash that is equal to a separator selects the child
+    /// that starts with it, since the writer never splits equal hashes over two key blocks.
+    fn lookup_index_block(&self, block: &[u8], hash: u64) -> Result<u16> {
+        Ok(index_block_position(block, hash)?.2)
+    }
+
+    /// Looks up a key in a key block. `block` is the content of `block_arc` after the block type.
+    fn lookup_key_block<K: QueryKey>(
+        &self,
+        header: &MappedHeader,
+        block_arc: &ArcSlice<u8>,
+        format: KeyBlockFormat,
+        key_hash: u64,
+        key: &K,
+        options: &LookupOptions,
+    ) -> Result<SearchResult> {
+        let KeyBlockLayout {
+            entry_count,
+            prefix,
+            offsets,
+            entries,
+        } = key_block_layout(block_arc, format)?;
+        let prefix = &block_arc[prefix];
+        let offsets = &block_arc[offsets];
+        let entries = &block_arc[entries];
+        // Reused buffer for keys that need to be reconstructed from the shared prefix.
+        let mut full_key = Vec::new();
+
+        let mut l = 0;
+        let mut r = entry_count;
         // binary search for the key
         while l < r {
             let m = (l + r) / 2;
@@ -356,125 +3105,481 @@ impl StaticSortedFile {
                 key: mid_key,
                 ty,
                 val: mid_val,
-            } = get_key_entry(offsets, entries, entry_count, m)?;
-            match key_hash.cmp(&mid_hash).then_with(|| key.cmp(mid_key)) {
+                sequence_number,
+            } = get_key_entry(offsets, entries, entry_count, m, format)?;
+            let ordering = match key_hash.cmp(&mid_hash) {
+                Ordering::Equal => key.cmp(prefixed_key(prefix, mid_key, &mut full_key)),
+                ordering => ordering,
+            };
+            match ordering {
                 Ordering::Less => {
                     r = m;
                 }
                 Ordering::Equal => {
-                    return Ok(self
-                        .handle_key_match(ty, mid_val, header, value_block_cache)?
-                        .into());
+                    if let (Some(sequence_number), Some(max_sequence_number)) =
+                        (sequence_number, options.max_sequence_number)
+                    {
+                        if sequence_number > max_sequence_number {
+                            return Ok(SearchResult::Miss(LookupResult::KeyMiss));
+                        }
+                    }
+                    let stored_key = stored_key(prefix, mid_key, block_arc);
+                    return Ok(SearchResult::Found(
+                        stored_key,
+                        ValueRef::decode(ty, mid_val)?,
+                        sequence_number,
+                    ));
                 }
                 Ordering::Greater => {
                     l = m + 1;
                 }
             }
         }
-        Ok(LookupResult::KeyMiss)
+        if let Some(negative_cache) = &self.negative_cache {
+            // Entries with the same hash would be next to the position of the key.
+            let hash_at = |index: usize| -> Result<u64> {
+                Ok(get_key_entry(offsets, entries, entry_count, index, format)?.hash)
+            };
+            let hash_is_absent = (l == 0 || hash_at(l - 1)? != key_hash)
+                && (l == entry_count || hash_at(l)? != key_hash);
+            if hash_is_absent && !options.bypass_caches && !options.disable_caches {
+                negative_cache.insert(
+                    (self.sequence_number, header.mapping.generation, key_hash),
+                    (),
+                );
+            }
+        }
+        Ok(SearchResult::Miss(LookupResult::KeyMiss))
     }
 
     /// Handles a key match by looking up the value.
-    fn handle_key_match(
+    fn handle_key_match<S: BuildHasher + Clone>(
         &self,
         ty: u8,
-        mut val: &[u8],
-        header: &Header,
-        value_block_cache: &BlockCache,
+        val: &[u8],
+        header: &MappedHeader,
+        value_block_cache: &BlockCache<S>,
+        options: &LookupOptions,
     ) -> Result<LookupValue> {
-        Ok(match ty {
-            KEY_BLOCK_ENTRY_TYPE_SMALL => {
-                let block = val.read_u16::<BE>()?;
-                let size = val.read_u16::<BE>()? as usize;
-                let position = val.read_u32::<BE>()? as usize;
-                let value = self
-                    .get_value_block(header, block, value_block_cache)?
-                    .slice(position..position + size);
+        self.resolve_value(
+            ValueRef::decode(ty, val)?,
+            header,
+            value_block_cache,
+            options,
+            None,
+        )
+    }
+
+    /// Reads the value that a value reference points to.
+    fn resolve_value<S: BuildHasher + Clone>(
+        &self,
+        value_ref: ValueRef,
+        header: &MappedHeader,
+        value_block_cache: &BlockCache<S>,
+        options: &LookupOptions,
+        trace: Option<&mut LookupTrace>,
+    ) -> Result<LookupValue> {
+        Ok(match value_ref {
+            ValueRef::Small {
+                block,
+                position,
+                size,
+            } => {
+                let value = self.read_small_value(
+                    header,
+                    block,
+                    position,
+                    size,
+                    value_block_cache,
+                    options,
+                    trace,
+                )?;
                 LookupValue::Slice { value }
             }
-            KEY_BLOCK_ENTRY_TYPE_MEDIUM => {
-                let block = val.read_u16::<BE>()?;
+            ValueRef::Medium { block } => {
                 let value = self.read_value_block(header, block)?;
+                if let Some(trace) = trace {
+                    trace.record_block(Some(&value));
+                }
                 LookupValue::Slice { value }
             }
-            KEY_BLOCK_ENTRY_TYPE_BLOB => {
-                let sequence_number = val.read_u32::<BE>()?;
-                LookupValue::Blob { sequence_number }
-            }
-            KEY_BLOCK_ENTRY_TYPE_DELETED => LookupValue::Deleted,
-            _ => {
-                bail!("Invalid key block entry type");
-            }
+            ValueRef::Blob { sequence_number } => LookupValue::Blob { sequence_number },
+            ValueRef::Deleted => LookupValue::Deleted,
         })
     }
 
-    /// Gets a key block from the cache or reads it from the file.
-    fn get_key_block(
+    /// Reads a small value from its shared value block.
+    fn read_small_value<S: BuildHasher + Clone>(
         &self,
-        header: &Header,
+        header: &MappedHeader,
         block: u16,
-        key_block_cache: &BlockCache,
-    ) -> Result<ArcSlice<u8>, anyhow::Error> {
-        Ok(
-            match key_block_cache.get_value_or_guard(&(self.sequence_number, block), None) {
-                GuardResult::Value(block) => block,
+        position: u32,
+        size: u16,
+        value_block_cache: &BlockCache<S>,
+        options: &LookupOptions,
+        trace: Option<&mut LookupTrace>,
+    ) -> Result<ArcSlice<u8>> {
+        let range = position as usize..position as usize + size as usize;
+        let value_block = self.get_value_block(header, block, value_block_cache, options, trace)?;
+        if range.end > value_block.len() {
+            bail!(
+                "Corrupted file seq:{} value {range:?} is out of bounds of value block {block} \
+                 ({} bytes)",
+                self.sequence_number,
+                value_block.len()
+            );
+        }
+        Ok(value_block.slice(range))
+    }
+
+    /// Checks the AQMF filter of this file for the key hash. Returns `false` if the key is
+    /// definitely not in this file. Files without a filter might contain every key.
+    fn aqmf_contains<S: BuildHasher + Clone>(
+        &self,
+        header: &MappedHeader,
+        key_hash: u64,
+        aqmf_cache: &AqmfCache<S>,
+        options: &LookupOptions,
+    ) -> Result<bool> {
+        self.with_aqmf(header, aqmf_cache, options, |aqmf| {
+            aqmf.is_none_or(|aqmf| aqmf.contains(key_hash))
+        })
+    }
+
+    /// Loads the AQMF filter or the custom filter of this file and calls `f` with it. `f` is
+    /// called with `None` if the file has no filter.
+    ///
+    /// The AQMF filter is always deserialized into an owned `qfilter::Filter`, since it can't view
+    /// borrowed bytes. The serialized filter is read from the memory mapping without a copy, so
+    /// the filter's own buffer is the only allocation. Concurrent lookups that miss the cache wait
+    /// on the cache guard of the first one, so the filter is only deserialized once.
+    fn with_aqmf<S: BuildHasher + Clone, R>(
+        &self,
+        header: &MappedHeader,
+        aqmf_cache: &AqmfCache<S>,
+        options: &LookupOptions,
+        f: impl FnOnce(Option<&dyn MembershipFilter>) -> R,
+    ) -> Result<R> {
+        if header.aqmf.is_empty() {
+            return Ok(f(None));
+        }
+        #[cfg(feature = "tracing")]
+        let span = tracing::trace_span!(
+            "sst load aqmf",
+            sequence_number = self.sequence_number,
+            cache_hit = tracing::field::Empty
+        )
+        .entered();
+        let use_aqmf_cache = header.max_hash - header.min_hash < 1 << 62;
+        if options.bypass_caches || options.disable_caches {
+            #[cfg(feature = "tracing")]
+            span.record("cache_hit", false);
+            let aqmf =
+                self.decode_filter(header, &header.read(header.aqmf.start..header.aqmf.end)?)?;
+            Ok(f(Some(&*aqmf)))
+        } else if use_aqmf_cache {
+            let aqmf = match aqmf_cache.get_value_or_guard(
+                &(self.sequence_number, header.mapping.generation),
+                options.cache_timeout,
+            ) {
+                GuardResult::Value(aqmf) => {
+                    #[cfg(feature = "tracing")]
+                    span.record("cache_hit", true);
+                    aqmf
+                }
+                GuardResult::Guard(guard) => {
+                    #[cfg(feature = "tracing")]
+                    span.record("cache_hit", false);
+                    let aqmf = header.read(header.aqmf.start..header.aqmf.end)?;
+                    let aqmf = self.decode_filter(header, &aqmf)?;
+                    let _ = guard.insert(aqmf.clone());
+                    aqmf
+                }
+                GuardResult::Timeout => return Err(StaticSortedFileError::CacheTimeout.into()),
+            };
+            Ok(f(Some(&*aqmf)))
+        } else {
+            #[cfg(feature = "tracing")]
+            span.record("cache_hit", header.mapping.aqmf.get().is_some());
+            let aqmf = header.mapping.aqmf.get_or_try_init(|| {
+                let aqmf = header.read(header.aqmf.start..header.aqmf.end)?;
+                self.decode_filter(header, &aqmf)
+            })?;
+            Ok(f(Some(&**aqmf)))
+        }
+    }
+
+    /// Deserializes the filter of this file with the decoder for its filter type.
+    fn decode_filter(
+        &self,
+        header: &MappedHeader,
+        bytes: &[u8],
+    ) -> Result<Arc<dyn MembershipFilter>> {
+        if header.filter_type == FILTER_TYPE_AQMF {
+            return decode_aqmf(bytes);
+        }
+        let Some((_, decoder)) = self
+            .filter_decoders
+            .iter()
+            .find(|(filter_type, _)| *filter_type == header.filter_type)
+        else {
+            return Err(StaticSortedFileError::UnsupportedFilterType(header.filter_type).into());
+        };
+        decoder(bytes)
+    }
+
+    /// Gets a key block from the cache or reads it from the file. The access is recorded in
+    /// `trace` if provided.
+    fn get_key_block<S: BuildHasher + Clone>(
+        &self,
+        header: &MappedHeader,
+        block: u16,
+        key_block_cache: &BlockCache<S>,
+        options: &LookupOptions,
+        trace: Option<&mut LookupTrace>,
+    ) -> Result<ArcSlice<u8>> {
+        #[cfg(feature = "tracing")]
+        let span = tracing::trace_span!(
+            "sst key block",
+            sequence_number = self.sequence_number,
+            block_index = block,
+            cache_hit = tracing::field::Empty
+        )
+        .entered();
+        let (block, decompressed) = 'block: {
+            if let Some(Some(block)) = header.mapping.pinned_blocks.read().get(block as usize) {
+                break 'block (block.clone(), false);
+            }
+            if options.bypass_caches || options.disable_caches {
+                if !options.disable_caches {
+                    if let Some(block) = key_block_cache.get(&header.block_cache_key(block)) {
+                        break 'block (block, false);
+                    }
+                }
+                break 'block (self.read_key_block(header, block)?, true);
+            }
+            match key_block_cache
+                .get_value_or_guard(&header.block_cache_key(block), options.cache_timeout)
+            {
+                GuardResult::Value(block) => (block, false),
                 GuardResult::Guard(guard) => {
                     let block = self.read_key_block(header, block)?;
                     let _ = guard.insert(block.clone());
-                    block
+                    (block, true)
                 }
-                GuardResult::Timeout => unreachable!(),
-            },
-        )
+                GuardResult::Timeout => return Err(StaticSortedFileError::CacheTimeout.into()),
+            }
+        };
+        #[cfg(feature = "tracing")]
+        span.record("cache_hit", !decompressed);
+        if let Some(trace) = trace {
+            trace.record_block(decompressed.then_some(&*block));
+        }
+        Ok(block)
     }
 
-    /// Gets a value block from the cache or reads it from the file.
-    fn get_value_block(
+    /// Gets a value block from the cache or reads it from the file. The access is recorded in
+    /// `trace` if provided.
+    fn get_value_block<S: BuildHasher + Clone>(
         &self,
-        header: &Header,
+        header: &MappedHeader,
         block: u16,
-        value_block_cache: &BlockCache,
+        value_block_cache: &BlockCache<S>,
+        options: &LookupOptions,
+        trace: Option<&mut LookupTrace>,
     ) -> Result<ArcSlice<u8>> {
-        let block = match value_block_cache.get_value_or_guard(&(self.sequence_number, block), None)
-        {
-            GuardResult::Value(block) => block,
+        #[cfg(feature = "tracing")]
+        let span = tracing::trace_span!(
+            "sst value block",
+            sequence_number = self.sequence_number,
+            block_index = block,
+            cache_hit = tracing::field::Empty
+        )
+        .entered();
+        let (block, decompressed) = 'block: {
+            if let Some(content_block_cache) = &self.content_block_cache {
+                break 'block self.get_content_block(
+                    header,
+                    block,
+                    content_block_cache,
+                    options,
+                )?;
+            }
+            if options.bypass_caches || options.bypass_value_block_cache || options.disable_caches {
+                if !options.disable_caches {
+                    if let Some(block) = value_block_cache.get(&header.block_cache_key(block)) {
+                        break 'block (block, false);
+                    }
+                }
+                break 'block (self.read_value_block(header, block)?, true);
+            }
+            match value_block_cache
+                .get_value_or_guard(&header.block_cache_key(block), options.cache_timeout)
+            {
+                GuardResult::Value(block) => (block, false),
+                GuardResult::Guard(guard) => {
+                    let block = self.read_value_block(header, block)?;
+                    let _ = guard.insert(block.clone());
+                    (block, true)
+                }
+                GuardResult::Timeout => return Err(StaticSortedFileError::CacheTimeout.into()),
+            }
+        };
+        #[cfg(feature = "tracing")]
+        span.record("cache_hit", !decompressed);
+        if let Some(trace) = trace {
+            trace.record_block(decompressed.then_some(&*block));
+        }
+        Ok(block)
+    }
+
+    /// Gets a value block from the content block cache or reads it from the file. Returns whether
+    /// the block was decompressed.
+    fn get_content_block(
+        &self,
+        header: &MappedHeader,
+        block: u16,
+        content_block_cache: &ContentBlockCache,
+        options: &LookupOptions,
+    ) -> Result<(ArcSlice<u8>, bool)> {
+        let key = self.value_block_content_key(header, block)?;
+        if options.bypass_caches || options.bypass_value_block_cache || options.disable_caches {
+            if !options.disable_caches {
+                if let Some(block) = content_block_cache.get(&key) {
+                    return Ok((block, false));
+                }
+            }
+            return Ok((self.read_value_block(header, block)?, true));
+        }
+        match content_block_cache.get_value_or_guard(&key, options.cache_timeout) {
+            GuardResult::Value(block) => Ok((block, false)),
             GuardResult::Guard(guard) => {
                 let block = self.read_value_block(header, block)?;
                 let _ = guard.insert(block.clone());
-                block
+                Ok((block, true))
             }
-            GuardResult::Timeout => unreachable!(),
-        };
-        Ok(block)
+            GuardResult::Timeout => Err(StaticSortedFileError::CacheTimeout.into()),
+        }
+    }
+
+    /// Hashes the compressed value block and the value compression dictionary. Equal keys mean
+    /// equal decompressed blocks. The key consists of two differently seeded 64 bit hashes, so
+    /// accidental collisions are practically impossible. It's computed once per block.
+    fn value_block_content_key(&self, header: &MappedHeader, block: u16) -> Result<u128> {
+        check_block_index(header, block)?;
+        let keys = header
+            .mapping
+            .value_block_content_keys
+            .get_or_init(|| (0..header.block_count).map(|_| OnceLock::new()).collect());
+        keys[block as usize]
+            .get_or_try_init(|| {
+                let dictionary_hash =
+                    *header.mapping.value_dictionary_hash.get_or_try_init(|| {
+                        let mut hasher = twox_hash::XxHash64::with_seed(0);
+                        hasher.write(self.value_compression_dictionary(header)?);
+                        anyhow::Ok(hasher.finish())
+                    })?;
+                let LocationInFile { start, end } = self.block_location(header, block)?;
+                let block = header.read(start..end)?;
+                let hash = |seed| {
+                    let mut hasher = twox_hash::XxHash64::with_seed(seed);
+                    hasher.write_u64(dictionary_hash);
+                    hasher.write(&block);
+                    hasher.finish()
+                };
+                anyhow::Ok(((hash(0) as u128) << 64) | hash(1) as u128)
+            })
+            .copied()
+    }
+
+    /// Reads a key block from the file.
+    fn read_key_block(&self, header: &MappedHeader, block_index: u16) -> Result<ArcSlice<u8>> {
+        let dictionary = self.key_compression_dictionary(header)?;
+        self.read_block(header, block_index, dictionary, usize::MAX)
+    }
+
+    /// Reads a value block from the file.
+    fn read_value_block(&self, header: &MappedHeader, block_index: u16) -> Result<ArcSlice<u8>> {
+        self.read_value_block_prefix(header, block_index, usize::MAX)
+    }
+
+    /// Reads at most the first `max_len` bytes of a value block from the file. Only the needed part
+    /// of the block is decompressed.
+    fn read_value_block_prefix(
+        &self,
+        header: &MappedHeader,
+        block_index: u16,
+        max_len: usize,
+    ) -> Result<ArcSlice<u8>> {
+        if block_index >= header.block_count {
+            return Err(StaticSortedFileError::CorruptValueRef {
+                block: block_index,
+                block_count: header.block_count,
+            }
+            .into());
+        }
+        let dictionary = self.value_compression_dictionary(header)?;
+        self.read_block(header, block_index, dictionary, max_len)
+    }
+
+    /// Returns the compression dictionary for key blocks.
+    fn key_compression_dictionary<'l>(&'l self, header: &'l MappedHeader) -> Result<&'l [u8]> {
+        Ok(match self.shared_dictionaries(header)? {
+            Some(dictionaries) => &dictionaries.key[..],
+            None => self.file_dictionaries(header)?.0,
+        })
+    }
+
+    /// Returns the compression dictionary for value blocks.
+    fn value_compression_dictionary<'l>(&'l self, header: &'l MappedHeader) -> Result<&'l [u8]> {
+        Ok(match self.shared_dictionaries(header)? {
+            Some(dictionaries) => &dictionaries.value[..],
+            None => self.file_dictionaries(header)?.1,
+        })
     }
 
-    /// Reads a key block from the file.
-    fn read_key_block(&self, header: &Header, block_index: u16) -> Result<ArcSlice<u8>> {
-        self.read_block(
-            header,
-            block_index,
-            &self.mmap
-                [header.key_compression_dictionary.start..header.key_compression_dictionary.end],
-        )
+    /// Returns the key and value compression dictionaries that are stored in the file.
+    fn file_dictionaries<'l>(&'l self, header: &'l MappedHeader) -> Result<(&'l [u8], &'l [u8])> {
+        let key = header.key_compression_dictionary.start..header.key_compression_dictionary.end;
+        let value =
+            header.value_compression_dictionary.start..header.value_compression_dictionary.end;
+        if header.mapping.backing.as_slice().is_some() {
+            if let (Cow::Borrowed(key), Cow::Borrowed(value)) =
+                (header.read(key.clone())?, header.read(value.clone())?)
+            {
+                return Ok((key, value));
+            }
+        }
+        let [key, value] = header.mapping.file_dictionaries.get_or_try_init(|| {
+            anyhow::Ok([
+                header.read(key)?.into_owned().into_boxed_slice(),
+                header.read(value)?.into_owned().into_boxed_slice(),
+            ])
+        })?;
+        Ok((key, value))
     }
 
-    /// Reads a value block from the file.
-    fn read_value_block(&self, header: &Header, block_index: u16) -> Result<ArcSlice<u8>> {
-        self.read_block(
-            header,
-            block_index,
-            &self.mmap[header.value_compression_dictionary.start
-                ..header.value_compression_dictionary.end],
-        )
+    /// Returns the shared compression dictionaries if the file uses them.
+    fn shared_dictionaries(&self, header: &MappedHeader) -> Result<Option<&SharedDictionaries>> {
+        let Some(id) = header.shared_dictionaries_id else {
+            return Ok(None);
+        };
+        match &self.shared_dictionaries {
+            Some(dictionaries) if dictionaries.id == id => Ok(Some(dictionaries)),
+            Some(dictionaries) => bail!(
+                "File seq:{} needs shared dictionaries {id}, but {} were provided",
+                self.sequence_number,
+                dictionaries.id
+            ),
+            None => bail!(
+                "File seq:{} needs shared dictionaries {id}, but none were provided",
+                self.sequence_number
+            ),
+        }
     }
 
-    /// Reads a block from the file.
-    fn read_block(
-        &self,
-        header: &Header,
-        block_index: u16,
-        compression_dictionary: &[u8],
-    ) -> Result<ArcSlice<u8>> {
+    /// Gets the location of a block in the file. This includes the uncompressed length prefix.
+    fn block_location(&self, header: &MappedHeader, block_index: u16) -> Result<LocationInFile> {
         #[cfg(feature = "strict_checks")]
         if block_index >= header.block_count {
             bail!(
@@ -489,27 +3594,32 @@ impl StaticSortedFile {
         }
         let offset = header.block_offsets_start + block_index as usize * 4;
         #[cfg(feature = "strict_checks")]
-        if offset + 4 > self.mmap.len() {
+        if !header.mapping.in_bounds(&(offset..offset + 4)) {
             bail!(
                 "Corrupted file seq:{} block:{} block offset locations {} + 4 bytes > file end {} \
                  (block_offsets: {:x}, blocks: {:x})",
                 self.sequence_number,
                 block_index,
                 offset,
-                self.mmap.len(),
+                header.mapping.backing.size(),
                 header.block_offsets_start,
                 header.blocks_start
             );
         }
-        let block_start = if block_index == 0 {
-            header.blocks_start
+        let (block_start, block_end) = if block_index == 0 {
+            let end = header.read(offset..offset + 4)?;
+            (0, read_offset(&end, header.little_endian_offsets)?)
         } else {
-            header.blocks_start + (&self.mmap[offset - 4..offset]).read_u32::<BE>()? as usize
+            let offsets = header.read(offset - 4..offset + 4)?;
+            (
+                read_offset(&offsets[..4], header.little_endian_offsets)?,
+                read_offset(&offsets[4..], header.little_endian_offsets)?,
+            )
         };
-        let block_end =
-            header.blocks_start + (&self.mmap[offset..offset + 4]).read_u32::<BE>()? as usize;
+        let block_start = header.blocks_start + block_start as usize;
+        let block_end = header.blocks_start + block_end as usize;
         #[cfg(feature = "strict_checks")]
-        if block_end > self.mmap.len() || block_start > self.mmap.len() {
+        if !header.mapping.in_bounds(&(block_start..block_end)) {
             bail!(
                 "Corrupted file seq:{} block:{} block {} - {} > file end {} (block_offsets: {:x}, \
                  blocks: {:x})",
@@ -517,63 +3627,485 @@ impl StaticSortedFile {
                 block_index,
                 block_start,
                 block_end,
-                self.mmap.len(),
+                header.mapping.backing.size(),
                 header.block_offsets_start,
                 header.blocks_start
             );
         }
-        let uncompressed_length =
-            (&self.mmap[block_start..block_start + 4]).read_u32::<BE>()? as usize;
-        let block = self.mmap[block_start + 4..block_end].to_vec();
+        Ok(LocationInFile {
+            start: block_start,
+            end: block_end,
+        })
+    }
+
+    /// Reads a block from the file. Only the first `max_len` bytes of the block are decompressed.
+    fn read_block(
+        &self,
+        header: &MappedHeader,
+        block_index: u16,
+        compression_dictionary: &[u8],
+        max_len: usize,
+    ) -> Result<ArcSlice<u8>> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::trace_span!(
+            "sst read block",
+            sequence_number = self.sequence_number,
+            block_index
+        )
+        .entered();
+        let (data, uncompressed_length) = self.read_compressed_block(header, block_index)?;
+        let len = uncompressed_length.min(max_len);
+        let start = self.block_observer.is_some().then(Instant::now);
+
+        if len > 0
+            && self
+                .mmap_block_threshold
+                .is_some_and(|threshold| len >= threshold)
+        {
+            let mut buffer = MmapMut::map_anon(len)?;
+            decompress_block(
+                block_index,
+                &data[4..],
+                &mut buffer,
+                uncompressed_length,
+                compression_dictionary,
+            )?;
+            self.observe_decompression(block_index, data.len() - 4, len, start);
+            return Ok(
+                self.track_block(block_index, ArcSlice::from_owner(buffer.make_read_only()?))
+            );
+        }
+
+        // Collecting a zeroed iterator of known length allocates the `Arc` directly, without an
+        // intermediate `Vec` that would need to be copied.
+        let mut buffer: Arc<[u8]> = std::iter::repeat_n(0, len).collect();
+        let decompressed = Arc::get_mut(&mut buffer).expect("The buffer is not shared yet");
+        decompress_block(
+            block_index,
+            &data[4..],
+            decompressed,
+            uncompressed_length,
+            compression_dictionary,
+        )?;
+        self.observe_decompression(block_index, data.len() - 4, len, start);
+        Ok(self.track_block(block_index, ArcSlice::from(buffer)))
+    }
+
+    /// Reports a decompression that began at `start` to the block observer.
+    fn observe_decompression(
+        &self,
+        block_index: u16,
+        compressed_len: usize,
+        uncompressed_len: usize,
+        start: Option<Instant>,
+    ) {
+        if let (Some(observer), Some(start)) = (&self.block_observer, start) {
+            observer.on_decompress(
+                block_index,
+                compressed_len,
+                uncompressed_len,
+                start.elapsed(),
+            );
+        }
+    }
+
+    /// Records a weak reference to a decompressed block when leak checking is enabled.
+    fn track_block(&self, _block_index: u16, block: ArcSlice<u8>) -> ArcSlice<u8> {
+        #[cfg(feature = "leak_check")]
+        {
+            let mut decompressed_blocks = self.decompressed_blocks.lock();
+            // Drop released blocks whenever the list doubles, so it stays proportional to the
+            // number of live blocks.
+            if decompressed_blocks.len().is_power_of_two() {
+                decompressed_blocks.retain(|(_, block)| block.strong_count() > 0);
+            }
+            decompressed_blocks.push((_block_index, block.downgrade()));
+        }
+        block
+    }
+
+    /// Reads a block from the file and appends the decompressed block to `out`.
+    fn read_block_into(
+        &self,
+        header: &MappedHeader,
+        block_index: u16,
+        compression_dictionary: &[u8],
+        out: &mut Vec<u8>,
+    ) -> Result<()> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::trace_span!(
+            "sst read block",
+            sequence_number = self.sequence_number,
+            block_index
+        )
+        .entered();
+        let (data, uncompressed_length) = self.read_compressed_block(header, block_index)?;
+        let start = out.len();
+        out.resize(start + uncompressed_length, 0);
+        let decompression_start = self.block_observer.is_some().then(Instant::now);
+        if let Err(err) = decompress_block(
+            block_index,
+            &data[4..],
+            &mut out[start..],
+            uncompressed_length,
+            compression_dictionary,
+        ) {
+            out.truncate(start);
+            return Err(err);
+        }
+        self.observe_decompression(
+            block_index,
+            data.len() - 4,
+            uncompressed_length,
+            decompression_start,
+        );
+        Ok(())
+    }
+
+    /// Reads a block from the file without decompressing it. Returns the block including the
+    /// uncompressed length prefix and the uncompressed length.
+    fn read_compressed_block<'l>(
+        &'l self,
+        header: &'l MappedHeader,
+        block_index: u16,
+    ) -> Result<(Cow<'l, [u8]>, usize)> {
+        let LocationInFile {
+            start: block_start,
+            end: block_end,
+        } = self.block_location(header, block_index)?;
+        // Memory mapped data is decompressed directly from the file without copying it first.
+        let data = header.read(block_start..block_end)?;
+        if data.len() < 4 {
+            bail!(
+                "Corrupted file seq:{} block:{} is too small ({} bytes)",
+                self.sequence_number,
+                block_index,
+                data.len()
+            );
+        }
+        let uncompressed_length = (&data[..4]).read_u32::<BE>()? as usize;
+        // LZ4 can't expand data by more than 255x. Larger lengths are corrupt and would cause
+        // huge allocations.
+        if uncompressed_length > (data.len() - 4) * 255 + 16 {
+            bail!(
+                "Corrupted file seq:{} block:{} uncompressed length {} is too large for {} \
+                 compressed bytes",
+                self.sequence_number,
+                block_index,
+                uncompressed_length,
+                data.len() - 4
+            );
+        }
+        if uncompressed_length > self.max_block_size {
+            return Err(StaticSortedFileError::BlockTooLarge {
+                block_index,
+                uncompressed_length,
+                max_block_size: self.max_block_size,
+            }
+            .into());
+        }
+        Ok((data, uncompressed_length))
+    }
+}
 
-        let buffer = Arc::new_zeroed_slice(uncompressed_length);
-        // Safety: MaybeUninit<u8> can be safely transmuted to u8.
-        let mut buffer = unsafe { transmute::<Arc<[MaybeUninit<u8>]>, Arc<[u8]>>(buffer) };
-        // Safety: We know that the buffer is not shared yet.
-        let decompressed = unsafe { Arc::get_mut_unchecked(&mut buffer) };
-        decompress_with_dict(&block, decompressed, compression_dictionary)?;
-        Ok(ArcSlice::from(buffer))
+/// Decompresses block `block_index` into `dst`. If `dst` is shorter than the uncompressed block,
+/// only the start of the block is decompressed. Fails if less than `dst.len()` bytes were
+/// decompressed.
+fn decompress_block(
+    block_index: u16,
+    block: &[u8],
+    dst: &mut [u8],
+    uncompressed_length: usize,
+    compression_dictionary: &[u8],
+) -> Result<()> {
+    let written = match (
+        dst.len() < uncompressed_length,
+        compression_dictionary.is_empty(),
+    ) {
+        (false, true) => decompress(block, dst),
+        (false, false) => decompress_with_dict(block, dst, compression_dictionary),
+        (true, true) => decompress_partial(block, dst, uncompressed_length),
+        (true, false) => {
+            decompress_partial_with_dict(block, dst, uncompressed_length, compression_dictionary)
+        }
+    }
+    .map_err(|source| StaticSortedFileError::Decompression {
+        block_index,
+        compressed_length: block.len(),
+        uncompressed_length,
+        with_dictionary: !compression_dictionary.is_empty(),
+        source,
+    })?;
+    if written != dst.len() {
+        return Err(StaticSortedFileError::DecompressionLengthMismatch {
+            expected: dst.len(),
+            actual: written,
+        }
+        .into());
     }
+    Ok(())
 }
 
 /// An iterator over all entries in a SST file in sorted order.
-pub struct StaticSortedFileIter<'l> {
+pub struct StaticSortedFileIter<'l, S = BuildHasherDefault<FxHasher>> {
     this: &'l StaticSortedFile,
-    key_block_cache: &'l BlockCache,
-    value_block_cache: &'l BlockCache,
-    header: &'l Header,
+    key_block_cache: &'l BlockCache<S>,
+    value_block_cache: &'l BlockCache<S>,
+    header: MappedHeader,
 
     stack: Vec<CurrentIndexBlock>,
     current_key_block: Option<CurrentKeyBlock>,
+    /// The blocks that were skipped. `None` if corrupt blocks are not skipped.
+    corrupt_blocks: Option<Vec<CorruptBlock>>,
+    /// How adjacent entries with equal keys are handled.
+    duplicate_keys: DuplicateKeys,
+    /// The entry after the last yielded one, read ahead to detect duplicate keys.
+    peeked: Option<LookupEntry>,
+    /// Reads upcoming key blocks into the cache before they are reached.
+    readahead: Option<Readahead<S>>,
+}
+
+/// The read-ahead configuration of an iterator.
+struct Readahead<S> {
+    /// The number of key blocks that are read at once.
+    window: usize,
+    /// Reads the given key blocks into the key block cache. This is a function pointer so the
+    /// iterator itself doesn't require a thread-safe hasher.
+    read_blocks: fn(&StaticSortedFile, &MappedHeader, &[u16], &BlockCache<S>),
+}
+
+/// How an iterator handles entries with equal keys. Keys are unique in files written by a correct
+/// writer.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicateKeys {
+    /// Yield all entries.
+    #[default]
+    Yield,
+    /// Fail with [StaticSortedFileError::DuplicateKey].
+    Error,
+    /// Only yield the last entry with the key.
+    KeepLast,
 }
 
 struct CurrentKeyBlock {
+    block_index: u16,
+    prefix: ArcSlice<u8>,
     offsets: ArcSlice<u8>,
     entries: ArcSlice<u8>,
     entry_count: usize,
+    format: KeyBlockFormat,
     index: usize,
 }
 
+impl CurrentKeyBlock {
+    /// Returns the hash of the entry at `index`.
+    fn hash(&self, index: usize) -> Result<u64> {
+        Ok(get_key_entry(
+            &self.offsets,
+            &self.entries,
+            self.entry_count,
+            index,
+            self.format,
+        )?
+        .hash)
+    }
+
+    /// Returns the index of the first entry at or after `start` with a hash of at least
+    /// `key_hash`.
+    fn seek(&self, key_hash: u64, start: usize) -> Result<usize> {
+        let mut l = start;
+        let mut r = self.entry_count;
+        while l < r {
+            let m = (l + r) / 2;
+            if self.hash(m)? < key_hash {
+                l = m + 1;
+            } else {
+                r = m;
+            }
+        }
+        Ok(l)
+    }
+}
+
 struct CurrentIndexBlock {
+    block_index: u16,
     entries: ArcSlice<u8>,
     block_indicies_count: usize,
     index: usize,
 }
 
-impl Iterator for StaticSortedFileIter<'_> {
+impl<S: BuildHasher + Clone> Iterator for StaticSortedFileIter<'_, S> {
     type Item = Result<LookupEntry>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.next_internal().transpose()
+        match self.duplicate_keys {
+            DuplicateKeys::Yield => self.next_internal(),
+            DuplicateKeys::Error | DuplicateKeys::KeepLast => self.next_deduplicated(),
+        }
+        .transpose()
     }
 }
 
-impl StaticSortedFileIter<'_> {
+impl<S: BuildHasher + Clone> StaticSortedFileIter<'_, S> {
+    /// Sets how entries with equal keys are handled. By default all entries are yielded.
+    pub fn with_duplicate_keys(mut self, duplicate_keys: DuplicateKeys) -> Self {
+        self.duplicate_keys = duplicate_keys;
+        self
+    }
+
+    /// Reads the next `window` key blocks into the key block cache in parallel whenever the
+    /// iterator reaches a new window of blocks. Blocks are only read ahead as long as they fit
+    /// into the remaining capacity of the cache. This improves the throughput of large sequential
+    /// scans and doesn't change the results.
+    pub fn with_readahead(mut self, window: usize) -> Self
+    where
+        S: Send + Sync,
+    {
+        self.readahead = (window > 1).then_some(Readahead {
+            window,
+            read_blocks: StaticSortedFile::read_ahead_key_blocks::<S>,
+        });
+        self
+    }
+
+    /// Moves the iterator to the first entry with a key hash of at least `key_hash`. Entries are
+    /// ordered by key hash. Seeking forward within the current key block only searches the rest
+    /// of the block. Otherwise the index is descended again from the root block.
+    pub fn seek(&mut self, key_hash: u64) -> Result<()> {
+        self.peeked = None;
+        if let Some(current) = &self.current_key_block {
+            if current.index > 0
+                && current.hash(current.index - 1)? < key_hash
+                && key_hash <= current.hash(current.entry_count - 1)?
+            {
+                let index = current.seek(key_hash, current.index)?;
+                if let Some(current) = &mut self.current_key_block {
+                    current.index = index;
+                }
+                return Ok(());
+            }
+        }
+        self.current_key_block = None;
+        self.stack.clear();
+        if self.header.block_count == 0 {
+            return Ok(());
+        }
+        let mut block_index = self.header.block_count - 1;
+        loop {
+            let stack_len = self.stack.len();
+            self.enter_block_or_skip(block_index)?;
+            if let Some(current) = &self.current_key_block {
+                let index = current.seek(key_hash, 0)?;
+                if index < current.entry_count {
+                    if let Some(current) = &mut self.current_key_block {
+                        current.index = index;
+                    }
+                } else {
+                    self.current_key_block = None;
+                }
+                return Ok(());
+            }
+            if self.stack.len() == stack_len {
+                // The block was an empty key block or it was skipped.
+                return Ok(());
+            }
+            let Some(mut index_block) = self.stack.pop() else {
+                return Ok(());
+            };
+            // The separator hash of child `i` is stored before its block index. The first child
+            // has no separator. The builder never splits equal key hashes across key blocks, so
+            // the last child with a separator of at most `key_hash` is entered.
+            let mut l = 1;
+            let mut r = index_block.block_indicies_count;
+            while l < r {
+                let m = (l + r) / 2;
+                if (&index_block.entries[m * 10 - 8..]).read_u64::<BE>()? <= key_hash {
+                    l = m + 1;
+                } else {
+                    r = m;
+                }
+            }
+            let child = l - 1;
+            let next_block = (&index_block.entries[child * 10..]).read_u16::<BE>()?;
+            if child + 1 < index_block.block_indicies_count {
+                index_block.index = child + 1;
+                let parent = index_block.block_index;
+                self.stack.push(index_block);
+                check_index_child(&self.header, parent, next_block)?;
+            } else {
+                check_index_child(&self.header, index_block.block_index, next_block)?;
+            }
+            block_index = next_block;
+        }
+    }
+
+    /// Gets the next entry and handles following entries with the same key according to
+    /// `duplicate_keys`.
+    fn next_deduplicated(&mut self) -> Result<Option<LookupEntry>> {
+        let mut current = match self.peeked.take() {
+            Some(entry) => entry,
+            None => match self.next_internal()? {
+                Some(entry) => entry,
+                None => return Ok(None),
+            },
+        };
+        while let Some(next) = self.next_internal()? {
+            if next.hash != current.hash || next.key != current.key {
+                self.peeked = Some(next);
+                break;
+            }
+            if self.duplicate_keys == DuplicateKeys::Error {
+                return Err(StaticSortedFileError::DuplicateKey { hash: next.hash }.into());
+            }
+            current = next;
+        }
+        Ok(Some(current))
+    }
+
+    /// Returns the blocks that were skipped because they couldn't be read. Always empty unless the
+    /// iterator was created with [StaticSortedFile::iter_skipping_corrupt_blocks].
+    pub fn into_corrupt_blocks(self) -> Vec<CorruptBlock> {
+        self.corrupt_blocks.unwrap_or_default()
+    }
+
+    /// Records a corrupt block if corrupt blocks are skipped, otherwise returns the error.
+    fn skip_corrupt_block(&mut self, block_index: u16, error: anyhow::Error) -> Result<()> {
+        let Some(corrupt_blocks) = &mut self.corrupt_blocks else {
+            return Err(error);
+        };
+        #[cfg(feature = "tracing")]
+        tracing::warn!(
+            sequence_number = self.this.sequence_number,
+            block_index,
+            "skipping corrupt block: {error:?}"
+        );
+        if !corrupt_blocks
+            .iter()
+            .any(|corrupt_block| corrupt_block.block_index == block_index)
+        {
+            corrupt_blocks.push(CorruptBlock { block_index, error });
+        }
+        Ok(())
+    }
+
+    /// Enters a block at the given index. Skips the block if it's corrupt and corrupt blocks are
+    /// skipped.
+    fn enter_block_or_skip(&mut self, block_index: u16) -> Result<()> {
+        match self.enter_block(block_index) {
+            Ok(()) => Ok(()),
+            Err(error) => self.skip_corrupt_block(block_index, error),
+        }
+    }
+
     /// Enters a block at the given index.
     fn enter_block(&mut self, block_index: u16) -> Result<()> {
-        let block_arc = self
-            .this
-            .get_key_block(self.header, block_index, self.key_block_cache)?;
+        let block_arc = self.this.get_key_block(
+            &self.header,
+            block_index,
+            self.key_block_cache,
+            &LookupOptions::default(),
+            None,
+        )?;
         let mut block = &*block_arc;
         let block_type = block.read_u8()?;
         match block_type {
@@ -581,23 +4113,31 @@ impl StaticSortedFileIter<'_> {
                 let block_indicies_count = (block.len() + 8) / 10;
                 let range = 1..block_arc.len();
                 self.stack.push(CurrentIndexBlock {
+                    block_index,
                     entries: block_arc.slice(range),
                     block_indicies_count,
                     index: 0,
                 });
             }
-            BLOCK_TYPE_KEY => {
-                let entry_count = block.read_u24::<BE>()? as usize;
-                let offsets_range = 4..4 + entry_count * 4;
-                let entries_range = 4 + entry_count * 4..block_arc.len();
-                let offsets = block_arc.clone().slice(offsets_range);
-                let entries = block_arc.slice(entries_range);
-                self.current_key_block = Some(CurrentKeyBlock {
+            BLOCK_TYPE_KEY | BLOCK_TYPE_KEY_WITH_SEQUENCE_NUMBERS => {
+                let format = KeyBlockFormat::new(&self.header, block_type);
+                let KeyBlockLayout {
+                    entry_count,
+                    prefix,
                     offsets,
                     entries,
-                    entry_count,
-                    index: 0,
-                });
+                } = key_block_layout(&block_arc, format)?;
+                if entry_count > 0 {
+                    self.current_key_block = Some(CurrentKeyBlock {
+                        block_index,
+                        prefix: block_arc.clone().slice(prefix),
+                        offsets: block_arc.clone().slice(offsets),
+                        entries: block_arc.slice(entries),
+                        entry_count,
+                        format,
+                        index: 0,
+                    });
+                }
             }
             _ => {
                 bail!("Invalid block type");
@@ -608,50 +4148,159 @@ impl StaticSortedFileIter<'_> {
 
     /// Gets the next entry in the file and moves the cursor.
     fn next_internal(&mut self) -> Result<Option<LookupEntry>> {
+        let this = self.this;
+        let value_block_cache = self.value_block_cache;
+        loop {
+            let Some((hash, key, value_ref, write_sequence_number)) =
+                self.next_with(|hash, key, ty, val, sequence_number| {
+                    Ok((hash, key, ValueRef::decode(ty, val)?, sequence_number))
+                })?
+            else {
+                return Ok(None);
+            };
+            match this.resolve_value(
+                value_ref,
+                &self.header,
+                value_block_cache,
+                &LookupOptions::default(),
+                None,
+            ) {
+                Ok(value) => {
+                    return Ok(Some(LookupEntry {
+                        hash,
+                        key,
+                        value,
+                        write_sequence_number,
+                    }))
+                }
+                Err(error) => match value_ref {
+                    ValueRef::Small { block, .. } | ValueRef::Medium { block } => {
+                        self.skip_corrupt_block(block, error)?
+                    }
+                    ValueRef::Blob { .. } | ValueRef::Deleted => return Err(error),
+                },
+            }
+        }
+    }
+
+    /// Gets the next entry whose key matches `predicate` and moves the cursor past it.
+    fn next_matching(&mut self, predicate: &impl Fn(&[u8]) -> bool) -> Result<Option<LookupEntry>> {
+        let this = self.this;
+        let header = self.header.clone();
+        let value_block_cache = self.value_block_cache;
+        loop {
+            let entry = self.next_with(|hash, key, ty, val, write_sequence_number| {
+                if !predicate(&key) {
+                    return Ok(None);
+                }
+                let value = this.handle_key_match(
+                    ty,
+                    val,
+                    &header,
+                    value_block_cache,
+                    &LookupOptions::default(),
+                )?;
+                Ok(Some(LookupEntry {
+                    hash,
+                    key,
+                    value,
+                    write_sequence_number,
+                }))
+            })?;
+            match entry {
+                Some(Some(entry)) => return Ok(Some(entry)),
+                Some(None) => continue,
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// Moves the cursor to the next entry and passes its hash, key, entry type and raw value
+    /// reference to `f`. The value is not resolved.
+    fn next_with<T>(
+        &mut self,
+        mut f: impl FnMut(u64, ArcSlice<u8>, u8, &[u8], Option<u32>) -> Result<T>,
+    ) -> Result<Option<T>> {
         loop {
             if let Some(CurrentKeyBlock {
+                block_index,
+                prefix,
                 offsets,
                 entries,
                 entry_count,
+                format,
                 index,
             }) = self.current_key_block.take()
             {
-                let GetKeyEntryResult { hash, key, ty, val } =
-                    get_key_entry(&offsets, &entries, entry_count, index)?;
-                let value =
-                    self.this
-                        .handle_key_match(ty, val, self.header, self.value_block_cache)?;
-                let entry = LookupEntry {
-                    hash,
-                    // Safety: The key is a valid slice of the entries.
-                    key: unsafe { ArcSlice::new_unchecked(key, ArcSlice::full_arc(&entries)) },
-                    value,
+                let item = get_key_entry(&offsets, &entries, entry_count, index, format).and_then(
+                    |GetKeyEntryResult {
+                         hash,
+                         key,
+                         ty,
+                         val,
+                         sequence_number,
+                     }| {
+                        let key = stored_key(&prefix, key, &entries);
+                        f(hash, key, ty, val, sequence_number)
+                    },
+                );
+                let item = match item {
+                    Ok(item) => item,
+                    Err(error) => {
+                        // The rest of the key block is skipped.
+                        self.skip_corrupt_block(block_index, error)?;
+                        continue;
+                    }
                 };
                 if index + 1 < entry_count {
                     self.current_key_block = Some(CurrentKeyBlock {
+                        block_index,
+                        prefix,
                         offsets,
                         entries,
                         entry_count,
+                        format,
                         index: index + 1,
                     });
                 }
-                return Ok(Some(entry));
+                return Ok(Some(item));
             }
             if let Some(CurrentIndexBlock {
+                block_index: index_block_index,
                 entries,
                 block_indicies_count,
                 index,
             }) = self.stack.pop()
             {
                 let block_index = (&entries[index * 10..]).read_u16::<BE>()?;
+                if let Some(Readahead {
+                    window,
+                    read_blocks,
+                }) = self.readahead
+                {
+                    if index % window == 0 {
+                        let end = (index + window).min(block_indicies_count);
+                        let blocks = (index..end)
+                            .filter_map(|i| (&entries[i * 10..]).read_u16::<BE>().ok())
+                            .filter(|&block| block < self.header.block_count)
+                            .collect::<Vec<_>>();
+                        read_blocks(self.this, &self.header, &blocks, self.key_block_cache);
+                    }
+                }
                 if index + 1 < block_indicies_count {
                     self.stack.push(CurrentIndexBlock {
+                        block_index: index_block_index,
                         entries,
                         block_indicies_count,
                         index: index + 1,
                     });
                 }
-                self.enter_block(block_index)?;
+                if let Err(error) = check_index_child(&self.header, index_block_index, block_index)
+                {
+                    self.skip_corrupt_block(index_block_index, error)?;
+                    continue;
+                }
+                self.enter_block_or_skip(block_index)?;
             } else {
                 return Ok(None);
             }
@@ -664,6 +4313,271 @@ struct GetKeyEntryResult<'l> {
     key: &'l [u8],
     ty: u8,
     val: &'l [u8],
+    sequence_number: Option<u32>,
+}
+
+/// The encoding of the entries in a key block.
+#[derive(Clone, Copy, Default)]
+struct KeyBlockFormat {
+    /// The entries are followed by the sequence number of their write.
+    sequence_numbers: bool,
+    /// The entry headers are stored in little endian byte order.
+    little_endian_offsets: bool,
+    /// The block stores the common prefix of its keys once.
+    shared_prefix: bool,
+}
+
+impl KeyBlockFormat {
+    fn new(header: &Header, block_type: u8) -> Self {
+        Self {
+            sequence_numbers: block_type == BLOCK_TYPE_KEY_WITH_SEQUENCE_NUMBERS,
+            little_endian_offsets: header.little_endian_offsets,
+            shared_prefix: header.shared_key_prefixes,
+        }
+    }
+}
+
+/// The parts of a key block as ranges of the block.
+struct KeyBlockLayout {
+    entry_count: usize,
+    /// The common prefix of all keys in the block. Empty if the block has no shared prefix.
+    prefix: Range<usize>,
+    /// The entry headers.
+    offsets: Range<usize>,
+    /// The entries.
+    entries: Range<usize>,
+}
+
+/// Parses the header of a key block. `block` includes the block type.
+fn key_block_layout(block: &[u8], format: KeyBlockFormat) -> Result<KeyBlockLayout> {
+    let mut header = &block[1..];
+    let entry_count = header.read_u24::<BE>()? as usize;
+    let mut start = 4;
+    let prefix = if format.shared_prefix {
+        let prefix_len = header.read_u16::<BE>()? as usize;
+        start += 2 + prefix_len;
+        start - prefix_len..start
+    } else {
+        start..start
+    };
+    let offsets = start..start + entry_count * 4;
+    if offsets.end > block.len() {
+        bail!("Corrupted key block: {entry_count} entries don't fit into the block");
+    }
+    Ok(KeyBlockLayout {
+        entry_count,
+        prefix,
+        entries: offsets.end..block.len(),
+        offsets,
+    })
+}
+
+/// Returns the full key of an entry. Keys in blocks without a shared prefix are returned
+/// directly, otherwise the key is reconstructed in `buffer`.
+fn prefixed_key<'l>(prefix: &'l [u8], suffix: &'l [u8], buffer: &'l mut Vec<u8>) -> &'l [u8] {
+    if prefix.is_empty() {
+        return suffix;
+    }
+    buffer.clear();
+    buffer.extend_from_slice(prefix);
+    buffer.extend_from_slice(suffix);
+    buffer
+}
+
+/// Returns the full key of an entry as a slice that keeps the block alive. Keys in blocks with a
+/// shared prefix are copied into a new allocation. `suffix` must be a slice of `block`.
+fn stored_key(prefix: &[u8], suffix: &[u8], block: &ArcSlice<u8>) -> ArcSlice<u8> {
+    if prefix.is_empty() {
+        let start = suffix.as_ptr() as usize - block.as_ptr() as usize;
+        return block.clone().slice(start..start + suffix.len());
+    }
+    let mut key = Vec::with_capacity(prefix.len() + suffix.len());
+    key.extend_from_slice(prefix);
+    key.extend_from_slice(suffix);
+    ArcSlice::from(Arc::<[u8]>::from(key))
+}
+
+/// Reads a 4 byte offset in the byte order given by the file header.
+fn read_offset(mut bytes: &[u8], little_endian: bool) -> Result<u32> {
+    Ok(if little_endian {
+        bytes.read_u32::<LE>()?
+    } else {
+        bytes.read_u32::<BE>()?
+    })
+}
+
+/// Checks that a block index that was passed by the caller exists in the file.
+fn check_block_index(header: &Header, block_index: u16) -> Result<()> {
+    if block_index >= header.block_count {
+        bail!(
+            "Block {block_index} is out of range, the file has {} blocks",
+            header.block_count
+        );
+    }
+    Ok(())
+}
+
+/// Checks that an index block refers to an existing block before it. Blocks are always written
+/// before the index block that refers to them, so this rules out cycles in corrupt files.
+fn check_index_child(header: &Header, block: u16, next_block: u16) -> Result<()> {
+    if next_block >= header.block_count {
+        return Err(StaticSortedFileError::CorruptIndexBlock {
+            block,
+            next_block,
+            block_count: header.block_count,
+        }
+        .into());
+    }
+    if next_block >= block {
+        return Err(StaticSortedFileError::CorruptIndexCycle { block, next_block }.into());
+    }
+    Ok(())
+}
+
+/// The result of a closure that runs on a thread pool. Awaiting it waits until the result is
+/// available. If the closure panics, the panic is resumed in the task that awaits it.
+#[cfg(feature = "async")]
+struct BlockingTask<T> {
+    state: Arc<parking_lot::Mutex<BlockingTaskState<T>>>,
+}
+
+/// The result of a [BlockingTask] once it is available and the waker of the task that awaits it.
+#[cfg(feature = "async")]
+type BlockingTaskState<T> = (Option<std::thread::Result<T>>, Option<std::task::Waker>);
+
+#[cfg(feature = "async")]
+impl<T> Default for BlockingTask<T> {
+    fn default() -> Self {
+        Self {
+            state: Arc::new(parking_lot::Mutex::new((None, None))),
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl<T> Clone for BlockingTask<T> {
+    fn clone(&self) -> Self {
+        Self {
+            state: self.state.clone(),
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl<T: Send + 'static> BlockingTask<T> {
+    /// Runs `f` on the rayon thread pool.
+    fn spawn(f: impl FnOnce() -> T + Send + 'static) -> Self {
+        let task = Self::default();
+        let result = task.clone();
+        rayon::spawn(move || {
+            result.complete(std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)));
+        });
+        task
+    }
+}
+
+#[cfg(feature = "async")]
+impl<T> BlockingTask<T> {
+    /// Stores the result and wakes the task that awaits it.
+    fn complete(&self, result: std::thread::Result<T>) {
+        let waker = {
+            let mut state = self.state.lock();
+            state.0 = Some(result);
+            state.1.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl<T> std::future::Future for BlockingTask<T> {
+    type Output = T;
+
+    fn poll(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<T> {
+        let mut state = self.state.lock();
+        match state.0.take() {
+            Some(Ok(result)) => return std::task::Poll::Ready(result),
+            Some(Err(panic)) => {
+                drop(state);
+                std::panic::resume_unwind(panic)
+            }
+            None => {}
+        }
+        state.1 = Some(cx.waker().clone());
+        std::task::Poll::Pending
+    }
+}
+
+/// Computes the header checksum from the parts of the file that it covers.
+pub(crate) fn header_checksum(parts: &[&[u8]]) -> u64 {
+    let mut hasher = twox_hash::XxHash64::with_seed(0);
+    for part in parts {
+        hasher.write(part);
+    }
+    hasher.finish()
+}
+
+/// Returns the block indices that an index block refers to. `block` is the index block without the
+/// block type.
+fn index_block_children(mut block: &[u8]) -> Result<Vec<u16>> {
+    let mut children = vec![block.read_u16::<BE>()?];
+    for entry in block.chunks_exact(10) {
+        children.push((&entry[8..]).read_u16::<BE>()?);
+    }
+    Ok(children)
+}
+
+/// Returns the key hashes that separate the children of an index block. `block` is the index block
+/// without the block type.
+fn index_block_separator_hashes(block: &[u8]) -> Result<Vec<u64>> {
+    block
+        .get(2..)
+        .unwrap_or_default()
+        .chunks_exact(10)
+        .map(|mut entry| Ok(entry.read_u64::<BE>()?))
+        .collect()
+}
+
+/// Returns the position of the child that `hash` belongs to in an index block, the number of
+/// children and the block index of that child. `block` is the index block without the block type.
+fn index_block_position(mut block: &[u8], hash: u64) -> Result<(usize, usize, u16)> {
+    let first_block = block.read_u16::<BE>()?;
+    if block.len() % 10 != 0 {
+        bail!(
+            "Corrupted index block with {} bytes of entries, expected a multiple of 10",
+            block.len()
+        );
+    }
+    let entries = block;
+    let entry_count = entries.len() / 10;
+    let get_hash = |index: usize| (&entries[index * 10..]).read_u64::<BE>();
+    // Count the separators that are less than or equal to the hash.
+    let mut l = 0;
+    let mut r = entry_count;
+    while l < r {
+        let m = (l + r) / 2;
+        if get_hash(m)? <= hash {
+            l = m + 1;
+        } else {
+            r = m;
+        }
+    }
+    if l == 0 {
+        return Ok((0, entry_count + 1, first_block));
+    }
+    Ok((
+        l,
+        entry_count + 1,
+        (&entries[(l - 1) * 10 + 8..]).read_u16::<BE>()?,
+    ))
+}
+
+/// Reads the entry type and the start position of a key block entry header.
+fn read_key_entry_header(bytes: &[u8], format: KeyBlockFormat) -> Result<(u8, usize)> {
+    let header = read_offset(bytes, format.little_endian_offsets)?;
+    Ok(((header >> 24) as u8, (header & 0xffffff) as usize))
 }
 
 /// Reads a key entry from a key block.
@@ -672,14 +4586,36 @@ fn get_key_entry<'l>(
     entries: &'l [u8],
     entry_count: usize,
     index: usize,
+    format: KeyBlockFormat,
 ) -> Result<GetKeyEntryResult<'l>> {
-    let mut offset = &offsets[index * 4..];
-    let ty = offset.read_u8()?;
-    let start = offset.read_u24::<BE>()? as usize;
-    let end = if index == entry_count - 1 {
+    let (ty, start) = read_key_entry_header(&offsets[index * 4..], format)?;
+    let mut end = if index == entry_count - 1 {
         entries.len()
     } else {
-        (&offsets[(index + 1) * 4 + 1..]).read_u24::<BE>()? as usize
+        read_key_entry_header(&offsets[(index + 1) * 4..], format)?.1
+    };
+    let value_size = match ty {
+        KEY_BLOCK_ENTRY_TYPE_SMALL => 8,
+        KEY_BLOCK_ENTRY_TYPE_MEDIUM => 2,
+        KEY_BLOCK_ENTRY_TYPE_BLOB => 4,
+        KEY_BLOCK_ENTRY_TYPE_DELETED => 0,
+        _ => {
+            bail!("Invalid key block entry type");
+        }
+    };
+    let sequence_number_size = if format.sequence_numbers { 4 } else { 0 };
+    if end > entries.len() || start + 8 + value_size + sequence_number_size > end {
+        bail!(
+            "Corrupted key block entry {index}: {start} - {end} is not a valid entry range \
+             (entries: {})",
+            entries.len()
+        );
+    }
+    let sequence_number = if format.sequence_numbers {
+        end -= 4;
+        Some((&entries[end..end + 4]).read_u32::<BE>()?)
+    } else {
+        None
     };
     let hash = (&entries[start..start + 8]).read_u64::<BE>()?;
     Ok(match ty {
@@ -688,27 +4624,34 @@ fn get_key_entry<'l>(
             key: &entries[start + 8..end - 8],
             ty,
             val: &entries[end - 8..end],
+            sequence_number,
         },
         KEY_BLOCK_ENTRY_TYPE_MEDIUM => GetKeyEntryResult {
             hash,
             key: &entries[start + 8..end - 2],
             ty,
             val: &entries[end - 2..end],
+            sequence_number,
         },
         KEY_BLOCK_ENTRY_TYPE_BLOB => GetKeyEntryResult {
             hash,
             key: &entries[start + 8..end - 4],
             ty,
             val: &entries[end - 4..end],
+            sequence_number,
         },
         KEY_BLOCK_ENTRY_TYPE_DELETED => GetKeyEntryResult {
             hash,
             key: &entries[start + 8..end],
             ty,
             val: &[],
+            sequence_number,
         },
         _ => {
             bail!("Invalid key block entry type");
         }
     })
 }
+
+#[cfg(test)]
+mod tests;
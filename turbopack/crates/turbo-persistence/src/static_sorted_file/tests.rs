@@ -0,0 +1,4079 @@
+use std::{
+    collections::HashSet,
+    fs::File,
+    hash::RandomState,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::{bail, Result};
+use byteorder::{ReadBytesExt, BE};
+
+use crate::{
+    arc_slice::ArcSlice,
+    collector_entry::{CollectorEntry, CollectorEntryValue, EntryKey},
+    constants::DATA_THRESHOLD_PER_COMPACTED_FILE,
+    file_backing::FileBacking,
+    key::hash_key,
+    lookup_entry::LookupValue,
+    membership_filter::{MembershipFilter, MembershipFilterBuilder},
+    merge_iter::{compact_with_limits, DedupMergeIter},
+    static_sorted_file::{
+        decompress_block, get_key_entry, key_block_layout, AqmfCache, BlockCache, BlockObserver,
+        BlockWeighter, ContentBlockCache, DumpEntry, DuplicateKeys, IndexShape, KeyBlockFormat,
+        LookupKind, LookupOptions, LookupResult, MmapFileOptions, NegativeCache,
+        PartitionedBlockCache, PrewarmBlocks, ScanEntryKind, SharedDictionaries, StaticSortedFile,
+        StaticSortedFileError, StaticSortedFileIter, StaticSortedFileRange, ValueRef,
+        BLOCK_TYPE_INDEX, BLOCK_TYPE_KEY, FORMAT_VERSION_1, FORMAT_VERSION_2,
+        KEY_BLOCK_ENTRY_TYPE_BLOB, KEY_BLOCK_ENTRY_TYPE_DELETED, KEY_BLOCK_ENTRY_TYPE_MEDIUM,
+        KEY_BLOCK_ENTRY_TYPE_SMALL, MAGIC,
+    },
+    static_sorted_file_builder::{StaticSortedFileBuilder, StaticSortedFileBuilderOptions},
+};
+
+/// Builds an in-memory SST file with small values from the given key/value pairs.
+fn build_file(sequence_number: u32, entries: &[(Vec<u8>, Vec<u8>)]) -> Result<StaticSortedFile> {
+    build_file_with_options(sequence_number, entries, &Default::default())
+}
+
+/// Builds an in-memory SST file with small values and the given builder options.
+fn build_file_with_options(
+    sequence_number: u32,
+    entries: &[(Vec<u8>, Vec<u8>)],
+    options: &StaticSortedFileBuilderOptions,
+) -> Result<StaticSortedFile> {
+    build_file_with_values_and_options(
+        sequence_number,
+        entries
+            .iter()
+            .map(|(key, value)| {
+                (
+                    key.clone(),
+                    CollectorEntryValue::Small {
+                        value: value.clone(),
+                    },
+                )
+            })
+            .collect(),
+        options,
+    )
+}
+
+/// Builds an in-memory SST file from the given keys and values.
+fn build_file_with_values(
+    sequence_number: u32,
+    entries: Vec<(Vec<u8>, CollectorEntryValue)>,
+) -> Result<StaticSortedFile> {
+    build_file_with_values_and_options(sequence_number, entries, &Default::default())
+}
+
+fn build_file_with_values_and_options(
+    sequence_number: u32,
+    entries: Vec<(Vec<u8>, CollectorEntryValue)>,
+    options: &StaticSortedFileBuilderOptions,
+) -> Result<StaticSortedFile> {
+    let mut entries = entries
+        .into_iter()
+        .map(|(key, value)| CollectorEntry {
+            key: EntryKey {
+                hash: hash_key(&key),
+                data: key,
+            },
+            value,
+            write_sequence_number: None,
+        })
+        .collect::<Vec<_>>();
+    // A stable sort keeps duplicate keys in the given order.
+    entries.sort_by(|a, b| a.key.cmp(&b.key));
+    let total_key_size = entries.iter().map(|e| e.key.data.len()).sum();
+    let total_value_size = entries.iter().map(|e| e.value.len()).sum();
+    let builder = StaticSortedFileBuilder::new_with_options(
+        0,
+        &entries,
+        total_key_size,
+        total_value_size,
+        options,
+    )?;
+    let mut buffer = Vec::new();
+    builder.write_to(&mut buffer)?;
+    Ok(StaticSortedFile::open_from_bytes(
+        sequence_number,
+        Arc::from(buffer),
+    ))
+}
+
+/// A key/value pair with a 4 byte big endian key and a 10 byte value derived from `i`.
+fn numbered_entry(i: u32) -> (Vec<u8>, Vec<u8>) {
+    (i.to_be_bytes().to_vec(), vec![i as u8; 10])
+}
+
+/// The entries `0..count`, see [`numbered_entry`].
+fn numbered_entries(count: u32) -> Vec<(Vec<u8>, Vec<u8>)> {
+    (0..count).map(numbered_entry).collect()
+}
+
+/// Fresh caches for a test.
+struct Caches {
+    aqmf: AqmfCache,
+    key_blocks: BlockCache,
+    value_blocks: BlockCache,
+}
+
+impl Caches {
+    fn new() -> Self {
+        Self {
+            aqmf: AqmfCache::with(
+                100,
+                1024 * 1024,
+                Default::default(),
+                Default::default(),
+                Default::default(),
+            ),
+            key_blocks: BlockCache::with(
+                100,
+                1024 * 1024,
+                Default::default(),
+                Default::default(),
+                Default::default(),
+            ),
+            value_blocks: BlockCache::with(
+                100,
+                1024 * 1024,
+                Default::default(),
+                Default::default(),
+                Default::default(),
+            ),
+        }
+    }
+
+    /// Looks up `key` in key family 0.
+    fn lookup<K: crate::QueryKey>(&self, file: &StaticSortedFile, key: &K) -> Result<LookupResult> {
+        file.lookup(
+            0,
+            hash_key(key),
+            key,
+            &self.aqmf,
+            &self.key_blocks,
+            &self.value_blocks,
+        )
+    }
+
+    /// Looks up `key` in key family 0 and fails unless it has a value.
+    fn lookup_value<K: crate::QueryKey>(
+        &self,
+        file: &StaticSortedFile,
+        key: &K,
+    ) -> Result<ArcSlice<u8>> {
+        match self.lookup(file, key)? {
+            LookupResult::Slice { value } => Ok(value),
+            _ => bail!("Value not found"),
+        }
+    }
+}
+
+#[test]
+fn dedup_merge_iter() -> Result<()> {
+    let small = |value: u8| CollectorEntryValue::Small { value: vec![value] };
+    let old = build_file_with_values(
+        1,
+        vec![
+            (vec![1], small(1)),
+            (vec![2], small(1)),
+            (vec![3], small(1)),
+        ],
+    )?;
+    let new = build_file_with_values(
+        2,
+        vec![
+            (vec![2], small(2)),
+            (vec![3], CollectorEntryValue::Deleted),
+            (vec![4], small(2)),
+        ],
+    )?;
+    let caches = Caches::new();
+    // The order of the files must not matter, only their sequence numbers.
+    let iter = DedupMergeIter::new(vec![&new, &old], &caches.key_blocks, &caches.value_blocks)?;
+    let mut result = iter
+        .map(|entry| {
+            let entry = entry?;
+            let LookupValue::Slice { value } = entry.value else {
+                panic!("Expected a slice value");
+            };
+            Ok((entry.key[0], value[0]))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    result.sort_unstable();
+    assert_eq!(result, vec![(1, 1), (2, 2), (4, 2)]);
+    Ok(())
+}
+
+#[test]
+fn custom_hasher() -> Result<()> {
+    let entries = vec![(vec![1u8], vec![42u8])];
+    let file = build_file(1, &entries)?;
+    let aqmf_cache = AqmfCache::<RandomState>::with(
+        10,
+        1024 * 1024,
+        Default::default(),
+        Default::default(),
+        Default::default(),
+    );
+    let block_cache = BlockCache::<RandomState>::with(
+        10,
+        1024 * 1024,
+        Default::default(),
+        Default::default(),
+        Default::default(),
+    );
+    let key = vec![1u8];
+    let LookupResult::Slice { value } = file.lookup(
+        0,
+        hash_key(&key),
+        &key,
+        &aqmf_cache,
+        &block_cache,
+        &block_cache,
+    )?
+    else {
+        panic!("Value not found");
+    };
+    assert_eq!(&*value, &[42]);
+    Ok(())
+}
+
+#[test]
+fn content_hash() -> Result<()> {
+    let entries = numbered_entries(1000);
+    let caches = Caches::new();
+    let content_hash =
+        |file: &StaticSortedFile| file.content_hash(&caches.key_blocks, &caches.value_blocks);
+    let hash = content_hash(&build_file(1, &entries)?)?;
+    assert_eq!(content_hash(&build_file(2, &entries)?)?, hash);
+
+    // A different encoding of the same entries hashes equally.
+    let file = build_file_with_options(
+        3,
+        &entries,
+        &StaticSortedFileBuilderOptions {
+            little_endian_offsets: true,
+            shared_key_prefixes: true,
+            ..Default::default()
+        },
+    )?;
+    assert_eq!(content_hash(&file)?, hash);
+
+    let mut changed = entries.clone();
+    changed[500].1[0] ^= 1;
+    assert_ne!(content_hash(&build_file(4, &changed)?)?, hash);
+    assert_ne!(content_hash(&build_file(5, &entries[1..])?)?, hash);
+    Ok(())
+}
+
+#[test]
+fn dump() -> Result<()> {
+    let entries = (0..100u32)
+        .map(|i| (i.to_be_bytes().to_vec(), vec![i as u8; 4]))
+        .collect::<Vec<_>>();
+    let file = build_file(7, &entries)?;
+    assert_eq!(file.sequence_number(), 7);
+    let caches = Caches::new();
+    let dump = file.dump(true, &caches.key_blocks, &caches.value_blocks)?;
+    assert_eq!(dump.len(), entries.len());
+    for (key, entry) in dump {
+        let DumpEntry::Small {
+            size,
+            value: Some(value),
+            ..
+        } = entry
+        else {
+            panic!("Expected a resolved small value");
+        };
+        assert_eq!(size, 4);
+        assert_eq!(&*value, &[key[3]; 4]);
+    }
+    let dump = file.dump(false, &caches.key_blocks, &caches.value_blocks)?;
+    assert!(dump
+        .iter()
+        .all(|(_, entry)| matches!(entry, DumpEntry::Small { value: None, .. })));
+    Ok(())
+}
+
+#[test]
+fn keys_for_value_block() -> Result<()> {
+    let entries = numbered_entries(1000);
+    let file = build_file(1, &entries)?;
+    let caches = Caches::new();
+    let dump = file.dump(false, &caches.key_blocks, &caches.value_blocks)?;
+    let DumpEntry::Small { block, .. } = dump[0].1 else {
+        panic!("Expected a small value");
+    };
+    let expected = dump
+        .iter()
+        .filter(|(_, entry)| matches!(entry, DumpEntry::Small { block: b, .. } if *b == block))
+        .map(|(key, _)| key.clone())
+        .collect::<Vec<_>>();
+    let keys = file.keys_for_value_block(block, &caches.key_blocks, &caches.value_blocks)?;
+    assert!(!keys.is_empty());
+    assert_eq!(keys, expected);
+    // Value blocks are not read.
+    assert_eq!(caches.value_blocks.len(), 0);
+
+    let root = file.block_count()? as u16 - 1;
+    assert!(file
+        .keys_for_value_block(root, &caches.key_blocks, &caches.value_blocks)?
+        .is_empty());
+    assert!(file
+        .keys_for_value_block(root + 1, &caches.key_blocks, &caches.value_blocks)
+        .is_err());
+    Ok(())
+}
+
+#[test]
+fn open_from_bytes() -> Result<()> {
+    let entries = numbered_entries(1000);
+    let file = build_file(1, &entries)?;
+    let caches = Caches::new();
+    for (key, value) in entries.iter() {
+        let found = caches.lookup_value(&file, key)?;
+        assert_eq!(&*found, &value[..]);
+    }
+    let missing = 1000u32.to_be_bytes().to_vec();
+    assert!(!matches!(
+        caches.lookup(&file, &missing)?,
+        LookupResult::Slice { .. }
+    ));
+    Ok(())
+}
+
+#[test]
+fn lookup_many() -> Result<()> {
+    let entries = numbered_entries(1000);
+    let file = build_file(1, &entries)?;
+    let keys = (0..1100u32)
+        .rev()
+        .map(|i| {
+            let key = i.to_be_bytes().to_vec();
+            (hash_key(&key), key)
+        })
+        .collect::<Vec<_>>();
+    for (prefetch_value_blocks, prefetch_key_blocks) in
+        [(false, false), (true, false), (false, true), (true, true)]
+    {
+        let caches = Caches::new();
+        let results = file.lookup_many(
+            0,
+            &keys,
+            &caches.aqmf,
+            &caches.key_blocks,
+            &caches.value_blocks,
+            &LookupOptions {
+                prefetch_value_blocks,
+                prefetch_key_blocks,
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(results.len(), keys.len());
+        for ((_, key), result) in keys.iter().zip(results) {
+            let i = u32::from_be_bytes(key[..].try_into()?);
+            match result {
+                LookupResult::Slice { value } => {
+                    assert!(i < 1000);
+                    assert_eq!(&*value, &[i as u8; 10][..]);
+                }
+                _ => assert!(i >= 1000),
+            }
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn memory_estimate() -> Result<()> {
+    let entries = numbered_entries(1000);
+    let file = build_file(1, &entries)?;
+    let estimate = file.memory_estimate()?;
+    // Loading all blocks into the caches should weight exactly the estimated size.
+    let caches = Caches::new();
+    file.dump(true, &caches.key_blocks, &caches.value_blocks)?;
+    assert_eq!(
+        estimate.decompressed_blocks,
+        caches.key_blocks.weight() + caches.value_blocks.weight()
+    );
+    assert!(estimate.mmapped > 0);
+    assert!(estimate.filter > 1000);
+    Ok(())
+}
+
+#[test]
+fn scan_prefix() -> Result<()> {
+    let entries = (0..20u8)
+        .flat_map(|tenant| (0..50u8).map(move |entity| (vec![tenant, entity], vec![entity])))
+        .collect::<Vec<_>>();
+    let file = build_file(1, &entries)?;
+    let caches = Caches::new();
+    let mut found = file
+        .scan_prefix(&[7], &caches.key_blocks, &caches.value_blocks)?
+        .map(|entry| {
+            let entry = entry?;
+            let LookupValue::Slice { value } = entry.value else {
+                panic!("Expected a small value");
+            };
+            assert_eq!(&*value, &entry.key[1..]);
+            Ok(entry.key.to_vec())
+        })
+        .collect::<Result<Vec<_>>>()?;
+    found.sort();
+    let expected = (0..50u8).map(|entity| vec![7, entity]).collect::<Vec<_>>();
+    assert_eq!(found, expected);
+    assert_eq!(
+        file.scan_prefix(&[], &caches.key_blocks, &caches.value_blocks)?
+            .count(),
+        entries.len()
+    );
+    assert_eq!(
+        file.scan_prefix(&[0xff], &caches.key_blocks, &caches.value_blocks)?
+            .count(),
+        0
+    );
+    Ok(())
+}
+
+#[test]
+fn scan_filtered() -> Result<()> {
+    // Keys with 4 to 7 bytes.
+    let entries = (0..1000u32)
+        .map(|i| {
+            let mut key = vec![0; i as usize % 4];
+            key.extend_from_slice(&i.to_be_bytes());
+            (key, vec![i as u8; 10])
+        })
+        .collect::<Vec<_>>();
+    let file = build_file(1, &entries)?;
+    let caches = Caches::new();
+    let found = file
+        .scan_filtered(
+            |key| key.len() == 6,
+            &caches.key_blocks,
+            &caches.value_blocks,
+        )?
+        .map(|entry| Ok(entry?.key.to_vec()))
+        .collect::<Result<HashSet<_>>>()?;
+    let expected = entries
+        .iter()
+        .filter(|(key, _)| key.len() == 6)
+        .map(|(key, _)| key.clone())
+        .collect::<HashSet<_>>();
+    assert_eq!(expected.len(), 250);
+    assert_eq!(found, expected);
+
+    // Entries that don't match don't read their value blocks.
+    let caches = Caches::new();
+    assert_eq!(
+        file.scan_filtered(|_| false, &caches.key_blocks, &caches.value_blocks)?
+            .count(),
+        0
+    );
+    assert_eq!(caches.value_blocks.len(), 0);
+    Ok(())
+}
+
+#[test]
+fn large_medium_value() -> Result<()> {
+    // Values that don't fit into a small value are stored in their own value block, which has
+    // a 32 bit length.
+    let large = (0..20 * 1024 * 1024u32)
+        .map(|i| (i % 251) as u8)
+        .collect::<Vec<_>>();
+    let file = build_file_with_values(
+        1,
+        vec![
+            (
+                b"large".to_vec(),
+                CollectorEntryValue::Medium {
+                    value: large.clone(),
+                },
+            ),
+            (
+                b"small".to_vec(),
+                CollectorEntryValue::Small {
+                    value: b"value".to_vec(),
+                },
+            ),
+        ],
+    )?;
+    let caches = Caches::new();
+    let value = caches.lookup_value(&file, b"large")?;
+    assert_eq!(value.len(), large.len());
+    assert!(*value == large[..]);
+    Ok(())
+}
+
+#[test]
+fn empty_and_single_entry_file() -> Result<()> {
+    let caches = Caches::new();
+    let empty = build_file(1, &[])?;
+    assert!(matches!(
+        caches.lookup(&empty, b"key")?,
+        LookupResult::RangeMiss
+    ));
+    assert_eq!(
+        empty
+            .iter(&caches.key_blocks, &caches.value_blocks)?
+            .count(),
+        0
+    );
+
+    let single = build_file(2, &[(b"key".to_vec(), b"value".to_vec())])?;
+    let value = caches.lookup_value(&single, b"key")?;
+    assert_eq!(&*value, b"value");
+    assert_eq!(
+        single
+            .iter(&caches.key_blocks, &caches.value_blocks)?
+            .count(),
+        1
+    );
+    Ok(())
+}
+
+#[test]
+fn partitioned_block_cache() -> Result<()> {
+    let cache = |_| {
+        BlockCache::with(
+            100,
+            1024 * 1024,
+            Default::default(),
+            Default::default(),
+            Default::default(),
+        )
+    };
+    let key_block_cache = PartitionedBlockCache::new((0..2).map(cache).collect());
+    let value_block_cache = PartitionedBlockCache::new((0..2).map(cache).collect());
+    let caches = Caches::new();
+    let entries = vec![(b"key".to_vec(), b"value".to_vec())];
+    let file = build_file(3, &entries)?;
+    let result = file.lookup(
+        0,
+        hash_key(b"key"),
+        b"key",
+        &caches.aqmf,
+        key_block_cache.partition(file.sequence_number()),
+        value_block_cache.partition(file.sequence_number()),
+    )?;
+    assert!(matches!(result, LookupResult::Slice { .. }));
+    assert!(key_block_cache.partitions()[0].is_empty());
+    assert!(!key_block_cache.partitions()[1].is_empty());
+    assert!(value_block_cache.partitions()[0].is_empty());
+    assert!(!value_block_cache.partitions()[1].is_empty());
+    Ok(())
+}
+
+#[test]
+fn lookup_entry() -> Result<()> {
+    let entries = numbered_entries(100);
+    let file = build_file(1, &entries)?;
+    let caches = Caches::new();
+    for (key, value) in entries.iter() {
+        let entry = file
+            .lookup_entry(
+                0,
+                hash_key(key),
+                key,
+                &caches.aqmf,
+                &caches.key_blocks,
+                &caches.value_blocks,
+                &LookupOptions::default(),
+            )?
+            .expect("Entry not found");
+        assert_eq!(&*entry.key, &key[..]);
+        let LookupValue::Slice { value: found } = entry.value else {
+            panic!("Expected a small value");
+        };
+        assert_eq!(&*found, &value[..]);
+    }
+    let missing = 100u32.to_be_bytes();
+    assert!(file
+        .lookup_entry(
+            0,
+            hash_key(&missing),
+            &missing,
+            &caches.aqmf,
+            &caches.key_blocks,
+            &caches.value_blocks,
+            &LookupOptions::default(),
+        )?
+        .is_none());
+    Ok(())
+}
+
+/// Entries `0..count` with small values. Entry `i` is written by write `i`.
+fn sequenced_entries(count: u32) -> Vec<CollectorEntry<Vec<u8>>> {
+    let mut entries = (0..count)
+        .map(|i| {
+            let key = i.to_be_bytes().to_vec();
+            CollectorEntry {
+                key: EntryKey {
+                    hash: hash_key(&key),
+                    data: key,
+                },
+                value: CollectorEntryValue::Small {
+                    value: vec![i as u8; 10],
+                },
+                write_sequence_number: Some(i),
+            }
+        })
+        .collect::<Vec<_>>();
+    entries.sort_unstable_by(|a, b| a.key.cmp(&b.key));
+    entries
+}
+
+#[test]
+fn lookup_as_of() -> Result<()> {
+    let entries = sequenced_entries(1000);
+    let builder = StaticSortedFileBuilder::new(0, &entries, 4000, 10000)?;
+    let mut buffer = Vec::new();
+    builder.write_to(&mut buffer)?;
+    let file = StaticSortedFile::open_from_bytes(1, Arc::from(buffer));
+    let caches = Caches::new();
+    for i in 0..1000u32 {
+        let key = i.to_be_bytes();
+        let result = file.lookup_as_of(
+            0,
+            hash_key(&key),
+            &key,
+            500,
+            &caches.aqmf,
+            &caches.key_blocks,
+            &caches.value_blocks,
+        )?;
+        if i <= 500 {
+            let LookupResult::Slice { value } = result else {
+                panic!("Value not found");
+            };
+            assert_eq!(&*value, &[i as u8; 10][..]);
+        } else {
+            assert!(matches!(result, LookupResult::KeyMiss));
+        }
+        assert!(matches!(
+            caches.lookup(&file, &key)?,
+            LookupResult::Slice { .. }
+        ));
+    }
+    assert_eq!(
+        file.iter(&caches.key_blocks, &caches.value_blocks)?.count(),
+        1000
+    );
+    Ok(())
+}
+
+#[test]
+fn compression_dictionaries() -> Result<()> {
+    let small = numbered_entries(10);
+    let large = (0..20000u32)
+        .map(|i| {
+            let key = format!("key-{i:08}-{}", i % 7).into_bytes();
+            let value = format!("value-{}-{}-{}", i, i % 13, i * 31).into_bytes();
+            (key, value)
+        })
+        .collect::<Vec<_>>();
+    for (entries, has_dictionaries) in [(small, false), (large, true)] {
+        let file = build_file(1, &entries)?;
+        let header = &file.header()?;
+        assert_eq!(
+            header.key_compression_dictionary.end > header.key_compression_dictionary.start,
+            has_dictionaries
+        );
+        assert_eq!(
+            header.value_compression_dictionary.end > header.value_compression_dictionary.start,
+            has_dictionaries
+        );
+        let caches = Caches::new();
+        for (key, value) in entries.iter() {
+            let found = caches.lookup_value(&file, key)?;
+            assert_eq!(&*found, &value[..]);
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn verify_parallel() -> Result<()> {
+    let entries = (0..10000u32)
+        .map(|i| (i.to_be_bytes().to_vec(), vec![i as u8; 100]))
+        .collect::<Vec<_>>();
+    let file = build_file(1, &entries)?;
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(4).build()?;
+    file.verify_parallel(&pool)?;
+
+    let mut buffer = Vec::new();
+    let mut collector_entries = entries
+        .iter()
+        .map(|(key, value)| CollectorEntry {
+            key: EntryKey {
+                hash: hash_key(key),
+                data: key.clone(),
+            },
+            value: CollectorEntryValue::Small {
+                value: value.clone(),
+            },
+            write_sequence_number: None,
+        })
+        .collect::<Vec<_>>();
+    collector_entries.sort_unstable_by(|a, b| a.key.cmp(&b.key));
+    StaticSortedFileBuilder::new(0, &collector_entries, 40000, 1000000)?.write_to(&mut buffer)?;
+    // Corrupt the end of the file, which contains the compressed index block.
+    let len = buffer.len();
+    buffer[len - 10..].fill(0xff);
+    let file = StaticSortedFile::open_from_bytes(1, Arc::from(buffer));
+    assert!(file.verify_parallel(&pool).is_err());
+    Ok(())
+}
+
+#[test]
+fn bypass_caches() -> Result<()> {
+    let entries = numbered_entries(1000);
+    let file = build_file(1, &entries)?;
+    let caches = Caches::new();
+    let options = LookupOptions {
+        bypass_caches: true,
+        ..Default::default()
+    };
+    for (key, value) in entries.iter() {
+        let LookupResult::Slice { value: found } = file.lookup_with_options(
+            0,
+            hash_key(key),
+            key,
+            &caches.aqmf,
+            &caches.key_blocks,
+            &caches.value_blocks,
+            &options,
+        )?
+        else {
+            panic!("Value not found");
+        };
+        assert_eq!(&*found, &value[..]);
+    }
+    assert!(caches.aqmf.is_empty());
+    assert!(caches.key_blocks.is_empty());
+    assert!(caches.value_blocks.is_empty());
+    Ok(())
+}
+
+#[test]
+fn bypass_value_block_cache() -> Result<()> {
+    let entries = numbered_entries(1000);
+    let file = build_file(1, &entries)?;
+    let caches = Caches::new();
+    let options = LookupOptions {
+        bypass_value_block_cache: true,
+        ..Default::default()
+    };
+    for (key, value) in entries.iter() {
+        let LookupResult::Slice { value: found } = file.lookup_with_options(
+            0,
+            hash_key(key),
+            key,
+            &caches.aqmf,
+            &caches.key_blocks,
+            &caches.value_blocks,
+            &options,
+        )?
+        else {
+            panic!("Value not found");
+        };
+        assert_eq!(&*found, &value[..]);
+    }
+    assert!(!caches.key_blocks.is_empty());
+    assert!(caches.value_blocks.is_empty());
+    Ok(())
+}
+
+#[test]
+fn entries() -> Result<()> {
+    let file = build_file_with_values(
+        1,
+        vec![
+            (
+                b"live".to_vec(),
+                CollectorEntryValue::Small {
+                    value: b"value".to_vec(),
+                },
+            ),
+            (b"deleted".to_vec(), CollectorEntryValue::Deleted),
+            (b"blob".to_vec(), CollectorEntryValue::Large { blob: 42 }),
+        ],
+    )?;
+    let caches = Caches::new();
+    let entries = file
+        .entries(&caches.key_blocks, &caches.value_blocks)?
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(entries.len(), 1);
+    assert_eq!(&*entries[0].0, b"live");
+    assert_eq!(&*entries[0].1, b"value");
+    Ok(())
+}
+
+#[test]
+fn format_version() -> Result<()> {
+    let entries = numbered_entries(100);
+    let file = build_file(1, &entries)?;
+    assert_eq!(file.format_version()?, FORMAT_VERSION_1);
+
+    let mut buffer = file.mapping().backing.as_slice().unwrap().to_vec();
+    buffer[3] = 9;
+    let file = StaticSortedFile::open_from_bytes(1, Arc::from(buffer.clone()));
+    let err = file.format_version().unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<StaticSortedFileError>(),
+        Some(StaticSortedFileError::UnsupportedVersion(9))
+    ));
+
+    buffer[0] = 0;
+    let file = StaticSortedFile::open_from_bytes(1, Arc::from(buffer));
+    let err = file.format_version().unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<StaticSortedFileError>(),
+        Some(StaticSortedFileError::InvalidMagic)
+    ));
+    Ok(())
+}
+
+#[test]
+fn entry_count() -> Result<()> {
+    for count in [0, 1, 100, 10000u32] {
+        let entries = numbered_entries(count);
+        let file = build_file(1, &entries)?;
+        assert_eq!(file.entry_count()?, count as usize);
+    }
+    Ok(())
+}
+
+#[test]
+fn self_referential_index_block() -> Result<()> {
+    let entries = numbered_entries(1000);
+    let file = build_file(1, &entries)?;
+    let caches = Caches::new();
+    // Replace the root index block with one that only refers to itself.
+    let root = file.block_count()? as u16 - 1;
+    let mut block = vec![BLOCK_TYPE_INDEX];
+    block.extend_from_slice(&root.to_be_bytes());
+    caches.key_blocks.insert(
+        file.mapping().block_cache_key(root),
+        ArcSlice::from(Arc::<[u8]>::from(block)),
+    );
+
+    let is_cycle = |err: &anyhow::Error| {
+        matches!(
+            err.downcast_ref::<StaticSortedFileError>(),
+            Some(StaticSortedFileError::CorruptIndexCycle { .. })
+        )
+    };
+    let key = 0u32.to_be_bytes().to_vec();
+    let result = caches.lookup(&file, &key);
+    assert!(result.is_err_and(|err| is_cycle(&err)));
+    let err = file
+        .iter(&caches.key_blocks, &caches.value_blocks)?
+        .find_map(|entry| entry.err())
+        .unwrap();
+    assert!(is_cycle(&err));
+    Ok(())
+}
+
+#[test]
+fn scaled_block_weighter() -> Result<()> {
+    let entries = numbered_entries(1000);
+    let file = build_file(1, &entries)?;
+    let caches = Caches::new();
+    file.dump(true, &caches.key_blocks, &caches.value_blocks)?;
+    let scaled_value_block_cache = BlockCache::with(
+        100,
+        1024 * 1024 * 1024,
+        BlockWeighter::scaled(100),
+        Default::default(),
+        Default::default(),
+    );
+    file.dump(true, &caches.key_blocks, &scaled_value_block_cache)?;
+    assert!(caches.value_blocks.weight() > 0);
+    assert_eq!(
+        scaled_value_block_cache.weight(),
+        caches.value_blocks.weight() * 100
+    );
+    Ok(())
+}
+
+#[cfg(feature = "ffi")]
+#[test]
+fn ffi_lookup() -> Result<()> {
+    use std::ptr;
+
+    use crate::ffi::{
+        turbo_persistence_lookup, FFI_LOOKUP_BLOB, FFI_LOOKUP_BUFFER_TOO_SMALL, FFI_LOOKUP_DELETED,
+        FFI_LOOKUP_ERROR, FFI_LOOKUP_FOUND, FFI_LOOKUP_MISS,
+    };
+
+    let file = build_file_with_values(
+        1,
+        vec![
+            (
+                b"key".to_vec(),
+                CollectorEntryValue::Small {
+                    value: b"value".to_vec(),
+                },
+            ),
+            (b"deleted".to_vec(), CollectorEntryValue::Deleted),
+            (
+                b"blob".to_vec(),
+                CollectorEntryValue::Large { blob: 1 << 20 },
+            ),
+        ],
+    )?;
+    let corrupt = StaticSortedFile::open_from_bytes(2, Arc::from(vec![0; 64]));
+    let caches = Caches::new();
+    let lookup_in = |file: *const StaticSortedFile, key: &[u8], buffer: &mut [u8]| {
+        let mut value_len = 0;
+        let mut blob_sequence_number = 0;
+        let result = unsafe {
+            turbo_persistence_lookup(
+                file,
+                0,
+                key.as_ptr(),
+                key.len(),
+                &caches.aqmf,
+                &caches.key_blocks,
+                &caches.value_blocks,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut value_len,
+                &mut blob_sequence_number,
+            )
+        };
+        (result, value_len, blob_sequence_number)
+    };
+    let lookup = |key: &[u8], buffer: &mut [u8]| {
+        let (result, value_len, _) = lookup_in(&file, key, buffer);
+        (result, value_len)
+    };
+    let mut buffer = [0; 16];
+    assert_eq!(lookup(b"key", &mut buffer), (FFI_LOOKUP_FOUND, 5));
+    assert_eq!(&buffer[..5], b"value");
+    assert_eq!(
+        lookup(b"key", &mut buffer[..2]),
+        (FFI_LOOKUP_BUFFER_TOO_SMALL, 5)
+    );
+    assert_eq!(lookup(b"deleted", &mut buffer).0, FFI_LOOKUP_DELETED);
+    assert_eq!(lookup(b"missing", &mut buffer).0, FFI_LOOKUP_MISS);
+    assert_eq!(
+        lookup_in(&file, b"blob", &mut buffer),
+        (FFI_LOOKUP_BLOB, 0, 1 << 20)
+    );
+
+    assert_eq!(lookup_in(&corrupt, b"key", &mut buffer).0, FFI_LOOKUP_ERROR);
+    assert_eq!(
+        lookup_in(ptr::null(), b"key", &mut buffer).0,
+        FFI_LOOKUP_ERROR
+    );
+    let mut value_len = 0;
+    let mut blob_sequence_number = 0;
+    let result = unsafe {
+        turbo_persistence_lookup(
+            &file,
+            0,
+            b"key".as_ptr(),
+            3,
+            &caches.aqmf,
+            ptr::null(),
+            &caches.value_blocks,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut value_len,
+            &mut blob_sequence_number,
+        )
+    };
+    assert_eq!(result, FFI_LOOKUP_ERROR);
+    let result = unsafe {
+        turbo_persistence_lookup(
+            &file,
+            0,
+            b"key".as_ptr(),
+            3,
+            &caches.aqmf,
+            &caches.key_blocks,
+            &caches.value_blocks,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            ptr::null_mut(),
+            ptr::null_mut(),
+        )
+    };
+    assert_eq!(result, FFI_LOOKUP_ERROR);
+
+    // A panic doesn't unwind into the caller.
+    struct Panicking;
+
+    impl BlockObserver for Panicking {
+        fn on_decompress(&self, _: u16, _: usize, _: usize, _: Duration) {
+            panic!("Observer failed");
+        }
+    }
+
+    let file = build_file(3, &[(b"key".to_vec(), b"value".to_vec())])?;
+    let bytes = Arc::from(file.mapping().backing.as_slice().unwrap());
+    let panicking =
+        StaticSortedFile::open_from_bytes(3, bytes).with_block_observer(Arc::new(Panicking));
+    assert_eq!(
+        lookup_in(&panicking, b"key", &mut buffer).0,
+        FFI_LOOKUP_ERROR
+    );
+    Ok(())
+}
+
+#[test]
+fn lookup_into() -> Result<()> {
+    let medium = (0..100_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    let file = build_file_with_values(
+        1,
+        vec![
+            (
+                b"small".to_vec(),
+                CollectorEntryValue::Small {
+                    value: b"value".to_vec(),
+                },
+            ),
+            (
+                b"medium".to_vec(),
+                CollectorEntryValue::Medium {
+                    value: medium.clone(),
+                },
+            ),
+            (b"deleted".to_vec(), CollectorEntryValue::Deleted),
+            (b"blob".to_vec(), CollectorEntryValue::Large { blob: 42 }),
+        ],
+    )?;
+    let caches = Caches::new();
+    let mut out = b"prefix".to_vec();
+    let lookup_into = |key: &[u8], out: &mut Vec<u8>| {
+        file.lookup_into(
+            0,
+            hash_key(&key),
+            &key,
+            out,
+            &caches.aqmf,
+            &caches.key_blocks,
+            &caches.value_blocks,
+            &LookupOptions::default(),
+        )
+    };
+    assert_eq!(lookup_into(b"small", &mut out)?, LookupKind::Slice);
+    assert_eq!(&out[..], b"prefixvalue");
+    out.clear();
+    assert_eq!(lookup_into(b"medium", &mut out)?, LookupKind::Slice);
+    assert_eq!(out, medium);
+    out.clear();
+    assert_eq!(lookup_into(b"deleted", &mut out)?, LookupKind::Deleted);
+    assert_eq!(
+        lookup_into(b"blob", &mut out)?,
+        LookupKind::Blob {
+            sequence_number: 42
+        }
+    );
+    assert!(lookup_into(b"missing", &mut out)?.is_miss());
+    assert!(out.is_empty());
+    Ok(())
+}
+
+#[test]
+fn lookup_into_bypass_value_block_cache() -> Result<()> {
+    let medium = vec![7u8; 100_000];
+    let file = build_file_with_values(
+        1,
+        vec![
+            (
+                b"small".to_vec(),
+                CollectorEntryValue::Small {
+                    value: b"value".to_vec(),
+                },
+            ),
+            (
+                b"medium".to_vec(),
+                CollectorEntryValue::Medium {
+                    value: medium.clone(),
+                },
+            ),
+        ],
+    )?;
+    let caches = Caches::new();
+    let options = LookupOptions {
+        bypass_value_block_cache: true,
+        ..Default::default()
+    };
+    for _ in 0..2 {
+        let mut out = Vec::new();
+        for (key, value) in [(&b"small"[..], &b"value"[..]), (b"medium", &medium)] {
+            out.clear();
+            let kind = file.lookup_into(
+                0,
+                hash_key(&key),
+                &key,
+                &mut out,
+                &caches.aqmf,
+                &caches.key_blocks,
+                &caches.value_blocks,
+                &options,
+            )?;
+            assert_eq!(kind, LookupKind::Slice);
+            assert_eq!(out, value);
+        }
+    }
+    assert!(!caches.key_blocks.is_empty());
+    assert!(caches.value_blocks.is_empty());
+    Ok(())
+}
+
+#[test]
+fn lookup_traced() -> Result<()> {
+    let entries = numbered_entries(10000);
+    let file = build_file(1, &entries)?;
+    let caches = Caches::new();
+    let mut key_blocks = HashSet::new();
+    for (key, _) in entries.iter() {
+        let (result, trace) = file.lookup_traced(
+            0,
+            hash_key(key),
+            key,
+            &caches.aqmf,
+            &caches.key_blocks,
+            &caches.value_blocks,
+            &LookupOptions::default(),
+        )?;
+        assert!(matches!(result, LookupResult::Slice { .. }));
+        let key_block = trace.key_block.expect("Lookup should end in a key block");
+        assert_eq!(
+            trace.index_blocks.last().map(|(_, next)| *next),
+            Some(key_block)
+        );
+        key_blocks.insert(key_block);
+    }
+    assert!(key_blocks.len() > 1);
+    Ok(())
+}
+
+#[test]
+fn corrupted_key_entry_offsets() {
+    // Two small value entries where the second one starts before the first one ends.
+    let mut offsets = Vec::new();
+    offsets.extend_from_slice(&[KEY_BLOCK_ENTRY_TYPE_SMALL, 0, 0, 20]);
+    offsets.extend_from_slice(&[KEY_BLOCK_ENTRY_TYPE_SMALL, 0, 0, 4]);
+    let entries = vec![0u8; 40];
+    assert!(get_key_entry(&offsets, &entries, 2, 0, KeyBlockFormat::default()).is_err());
+    assert!(get_key_entry(&offsets, &entries, 2, 1, KeyBlockFormat::default()).is_ok());
+    // The entry is too short for the hash and the value.
+    let offsets = [KEY_BLOCK_ENTRY_TYPE_SMALL, 0, 0, 30];
+    assert!(get_key_entry(&offsets, &entries, 1, 0, KeyBlockFormat::default()).is_err());
+    // The entry starts after the end of the block.
+    let offsets = [KEY_BLOCK_ENTRY_TYPE_DELETED, 0, 0, 50];
+    assert!(get_key_entry(&offsets, &entries, 1, 0, KeyBlockFormat::default()).is_err());
+}
+
+#[test]
+fn block_sizes() -> Result<()> {
+    // Few entries, so no compression dictionaries are used and all blocks can be read the
+    // same way.
+    let entries = (0..100u32)
+        .map(|i| (i.to_be_bytes().to_vec(), vec![i as u8; 5]))
+        .collect::<Vec<_>>();
+    let file = build_file(1, &entries)?;
+    let block_count = file.block_count()?;
+    assert!(block_count > 1);
+    let header = &file.header()?;
+    for block_index in 0..block_count as u16 {
+        assert_eq!(
+            file.block_uncompressed_len(block_index)?,
+            file.read_key_block(header, block_index)?.len()
+        );
+    }
+    assert!(file.block_uncompressed_len(block_count as u16).is_err());
+    Ok(())
+}
+
+#[test]
+fn little_endian_offsets() -> Result<()> {
+    let file = build_file_with_options(
+        1,
+        &numbered_entries(10000),
+        &StaticSortedFileBuilderOptions {
+            little_endian_offsets: true,
+            ..Default::default()
+        },
+    )?;
+    assert_eq!(file.format_version()?, FORMAT_VERSION_2);
+    assert!(&file.header()?.little_endian_offsets);
+    let caches = Caches::new();
+    for i in 0..10000u32 {
+        let key = i.to_be_bytes();
+        let value = caches.lookup_value(&file, &key)?;
+        assert_eq!(&*value, &[i as u8; 10][..]);
+    }
+    assert_eq!(
+        file.iter(&caches.key_blocks, &caches.value_blocks)?.count(),
+        10000
+    );
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(2).build()?;
+    file.verify_parallel(&pool)?;
+    Ok(())
+}
+
+#[test]
+fn shared_key_prefixes() -> Result<()> {
+    let keys = (0..10000u32)
+        .map(|i| format!("/app/config/{i:08}").into_bytes())
+        .collect::<Vec<_>>();
+    let entries = keys
+        .iter()
+        .enumerate()
+        .map(|(i, key)| (key.clone(), vec![i as u8; 10]))
+        .collect::<Vec<_>>();
+    let build = |shared_key_prefixes| {
+        build_file_with_options(
+            1,
+            &entries,
+            &StaticSortedFileBuilderOptions {
+                shared_key_prefixes,
+                ..Default::default()
+            },
+        )
+    };
+    let file = build(true)?;
+    assert!(file.mapping().backing.size() < build(false)?.mapping().backing.size());
+    assert!(&file.header()?.shared_key_prefixes);
+    let caches = Caches::new();
+    for (i, key) in keys.iter().enumerate() {
+        let entry = file
+            .lookup_entry(
+                0,
+                hash_key(key),
+                key,
+                &caches.aqmf,
+                &caches.key_blocks,
+                &caches.value_blocks,
+                &LookupOptions::default(),
+            )?
+            .unwrap();
+        assert_eq!(&*entry.key, &key[..]);
+        let LookupValue::Slice { value } = entry.value else {
+            panic!("Value not found");
+        };
+        assert_eq!(&*value, &[i as u8; 10][..]);
+    }
+    let missing = b"/app/config/missing".to_vec();
+    assert!(file
+        .lookup(
+            0,
+            hash_key(&missing),
+            &missing,
+            &caches.aqmf,
+            &caches.key_blocks,
+            &caches.value_blocks,
+        )?
+        .is_miss());
+    let mut iterated = file
+        .iter(&caches.key_blocks, &caches.value_blocks)?
+        .map(|entry| Ok(entry?.key.to_vec()))
+        .collect::<Result<Vec<_>>>()?;
+    iterated.sort();
+    assert_eq!(iterated, keys);
+    Ok(())
+}
+
+#[test]
+fn pin_block() -> Result<()> {
+    let entries = numbered_entries(10000);
+    let file = build_file(1, &entries)?;
+    let caches = Caches::new();
+    let index_block = file.block_count()? as u16 - 1;
+    file.pin_block(index_block, &caches.key_blocks)?;
+    caches.key_blocks.clear();
+    let key = 42u32.to_be_bytes();
+    let (result, trace) = file.lookup_traced(
+        0,
+        hash_key(&key),
+        &key,
+        &caches.aqmf,
+        &caches.key_blocks,
+        &caches.value_blocks,
+        &LookupOptions::default(),
+    )?;
+    assert!(matches!(result, LookupResult::Slice { .. }));
+    // The pinned index block is used without going through the cache.
+    assert!(caches
+        .key_blocks
+        .get(&file.mapping().block_cache_key(index_block))
+        .is_none());
+    let key_block = trace.key_block.expect("Lookup should end in a key block");
+    assert!(caches
+        .key_blocks
+        .get(&file.mapping().block_cache_key(key_block))
+        .is_some());
+    assert!(file.pin_block(index_block + 1, &caches.key_blocks).is_err());
+    Ok(())
+}
+
+#[test]
+fn lookup_result_helpers() -> Result<()> {
+    let entries = vec![(b"key".to_vec(), b"value".to_vec())];
+    let file = build_file(1, &entries)?;
+    let caches = Caches::new();
+    let found = caches.lookup(&file, b"key")?;
+    assert!(!found.is_miss());
+    assert_eq!(found.found_value().map(|v| &v[..]), Some(&b"value"[..]));
+    let missing = caches.lookup(&file, b"missing")?;
+    assert!(missing.is_miss());
+    assert!(missing.found_value().is_none());
+    assert!(!LookupResult::Deleted.is_miss());
+    assert!(LookupResult::Blob { sequence_number: 1 }
+        .found_value()
+        .is_none());
+    Ok(())
+}
+
+#[test]
+fn shared_dictionaries() -> Result<()> {
+    let entries = (0..20000u32)
+        .map(|i| {
+            let key = format!("key-{i:08}-{}", i % 7).into_bytes();
+            let value = format!("value-{}-{}-{}", i, i % 13, i * 31).into_bytes();
+            (key, value)
+        })
+        .collect::<Vec<_>>();
+    // Take the dictionaries of a regular file and share them.
+    let file = build_file(1, &entries)?;
+    let header = &file.header()?;
+    let (key_dictionary, value_dictionary) = file.file_dictionaries(header)?;
+    let dictionaries = Arc::new(SharedDictionaries {
+        id: 7,
+        key: Arc::from(key_dictionary),
+        value: Arc::from(value_dictionary),
+    });
+    assert!(!dictionaries.key.is_empty() && !dictionaries.value.is_empty());
+
+    let buffer: Arc<[u8]> = Arc::from(
+        build_file_with_options(
+            2,
+            &entries,
+            &StaticSortedFileBuilderOptions {
+                shared_dictionaries: Some(dictionaries.clone()),
+                ..Default::default()
+            },
+        )?
+        .mapping()
+        .backing
+        .as_slice()
+        .unwrap(),
+    );
+    assert!(buffer.len() < file.mapping().backing.size() as usize - dictionaries.key.len());
+
+    let file =
+        StaticSortedFile::open_from_bytes(2, buffer.clone()).with_shared_dictionaries(dictionaries);
+    let caches = Caches::new();
+    for (key, value) in entries.iter() {
+        let found = caches.lookup_value(&file, key)?;
+        assert_eq!(&*found, &value[..]);
+    }
+
+    // Without the shared dictionaries the file can't be read.
+    let file = StaticSortedFile::open_from_bytes(3, buffer);
+    let (key, _) = &entries[0];
+    assert!(file
+        .lookup(
+            0,
+            hash_key(key),
+            key,
+            &caches.aqmf,
+            &caches.key_blocks,
+            &caches.value_blocks,
+        )
+        .is_err());
+    Ok(())
+}
+
+#[test]
+fn open_without_mmap() -> Result<()> {
+    let entries = (0..10000u32)
+        .map(|i| {
+            let key = format!("key-{i:08}").into_bytes();
+            let value = format!("value-{}-{}", i, i % 13).into_bytes();
+            (key, value)
+        })
+        .collect::<Vec<_>>();
+    let file = build_file(1, &entries)?;
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("00000001.sst");
+    std::fs::write(&path, file.mapping().backing.as_slice().unwrap())?;
+
+    let file = StaticSortedFile::open_without_mmap(1, path)?;
+    assert!(file.mapping().backing.as_slice().is_none());
+    let caches = Caches::new();
+    for (key, value) in entries.iter() {
+        let found = caches.lookup_value(&file, key)?;
+        assert_eq!(&*found, &value[..]);
+    }
+    let count = file.iter(&caches.key_blocks, &caches.value_blocks)?.count();
+    assert_eq!(count, entries.len());
+    Ok(())
+}
+
+#[test]
+fn iter_skipping_corrupt_blocks() -> Result<()> {
+    let entries = numbered_entries(10000);
+    let file = build_file(1, &entries)?;
+    let header = &file.header()?;
+    let root = file.read_key_block(header, header.block_count - 1)?;
+    assert_eq!(root[0], BLOCK_TYPE_INDEX);
+    let key_block = (&root[1..]).read_u16::<BE>()?;
+    let location = file.block_location(header, key_block)?;
+    // Claim a smaller uncompressed length so decompression fails.
+    let mut buffer = file.mapping().backing.as_slice().unwrap().to_vec();
+    let length = (&buffer[location.start..]).read_u32::<BE>()?;
+    buffer[location.start..location.start + 4].copy_from_slice(&(length - 1).to_be_bytes());
+    let file = StaticSortedFile::open_from_bytes(1, Arc::from(buffer));
+
+    let caches = Caches::new();
+    assert!(file
+        .iter(&caches.key_blocks, &caches.value_blocks)?
+        .any(|entry| entry.is_err()));
+
+    let mut iter = file.iter_skipping_corrupt_blocks(&caches.key_blocks, &caches.value_blocks)?;
+    let count = iter.by_ref().collect::<Result<Vec<_>>>()?.len();
+    assert!(count > 0 && count < entries.len());
+    let corrupt_blocks = iter.into_corrupt_blocks();
+    assert_eq!(corrupt_blocks.len(), 1);
+    assert_eq!(corrupt_blocks[0].block_index, key_block);
+    Ok(())
+}
+
+#[test]
+fn lookup_value_prefix() -> Result<()> {
+    let large = (0..1024 * 1024u32)
+        .map(|i| (i % 251) as u8)
+        .collect::<Vec<_>>();
+    let file = build_file_with_values(
+        1,
+        vec![
+            (
+                b"large".to_vec(),
+                CollectorEntryValue::Medium {
+                    value: large.clone(),
+                },
+            ),
+            (
+                b"small".to_vec(),
+                CollectorEntryValue::Small {
+                    value: b"value".to_vec(),
+                },
+            ),
+        ],
+    )?;
+    let caches = Caches::new();
+    let lookup_prefix = |key: &[u8], max_len| {
+        let result = file.lookup_value_prefix(
+            0,
+            hash_key(&key),
+            &key,
+            max_len,
+            &caches.aqmf,
+            &caches.key_blocks,
+            &caches.value_blocks,
+            &LookupOptions::default(),
+        )?;
+        let LookupResult::Slice { value } = result else {
+            panic!("Value not found");
+        };
+        anyhow::Ok(value)
+    };
+    assert_eq!(&*lookup_prefix(b"large", 300)?, &large[..300]);
+    assert_eq!(lookup_prefix(b"large", usize::MAX)?.len(), large.len());
+    assert_eq!(&*lookup_prefix(b"small", 3)?, b"val");
+    assert_eq!(&*lookup_prefix(b"small", 100)?, b"value");
+    Ok(())
+}
+
+#[test]
+fn skip_aqmf() -> Result<()> {
+    let entries = numbered_entries(1000);
+    let file = build_file(1, &entries)?;
+    let caches = Caches::new();
+    let options = LookupOptions {
+        skip_aqmf: true,
+        ..Default::default()
+    };
+    for (key, value) in entries.iter() {
+        let LookupResult::Slice { value: found } = file.lookup_with_options(
+            0,
+            hash_key(key),
+            key,
+            &caches.aqmf,
+            &caches.key_blocks,
+            &caches.value_blocks,
+            &options,
+        )?
+        else {
+            panic!("Value not found");
+        };
+        assert_eq!(&*found, &value[..]);
+    }
+    let missing = 1000u32.to_be_bytes().to_vec();
+    let result = file.lookup_with_options(
+        0,
+        hash_key(&missing),
+        &missing,
+        &caches.aqmf,
+        &caches.key_blocks,
+        &caches.value_blocks,
+        &options,
+    )?;
+    assert!(matches!(
+        result,
+        LookupResult::KeyMiss | LookupResult::RangeMiss
+    ));
+    assert!(caches.aqmf.is_empty());
+    Ok(())
+}
+
+#[test]
+fn open_eager() -> Result<()> {
+    let entries = numbered_entries(100);
+    let file = build_file(1, &entries)?;
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("00000001.sst");
+    std::fs::write(&path, file.mapping().backing.as_slice().unwrap())?;
+    let file = StaticSortedFile::open_eager(1, path)?;
+    assert!(file
+        .mapping()
+        .header
+        .get()
+        .is_some_and(|header| header.is_ok()));
+
+    let path = dir.path().join("00000002.sst");
+    std::fs::write(&path, [0; 64])?;
+    assert!(StaticSortedFile::open_eager(2, path.clone()).is_err());
+    assert!(StaticSortedFile::open(2, path).is_ok());
+    Ok(())
+}
+
+#[test]
+fn aqmf_bytes() -> Result<()> {
+    let entries = numbered_entries(1000);
+    let file = build_file(1, &entries)?;
+    let bytes = file.aqmf_bytes()?;
+    assert_eq!(
+        bytes.as_ptr(),
+        file.mapping().backing.as_slice().unwrap()[file.header()?.aqmf.start..].as_ptr()
+    );
+    let aqmf: qfilter::Filter = pot::from_slice(&bytes)?;
+    for (key, _) in entries.iter() {
+        assert!(aqmf.contains_fingerprint(hash_key(key)));
+    }
+    Ok(())
+}
+
+#[test]
+fn omit_aqmf() -> Result<()> {
+    let file = build_file_with_options(
+        1,
+        &numbered_entries(10),
+        &StaticSortedFileBuilderOptions {
+            omit_aqmf: true,
+            ..Default::default()
+        },
+    )?;
+    assert!(file.aqmf_bytes()?.is_empty());
+    assert_eq!(file.memory_estimate()?.filter, 0);
+    let caches = Caches::new();
+    for i in 0..20u32 {
+        let key = i.to_be_bytes();
+        let result = caches.lookup(&file, &key)?;
+        if i < 10 {
+            let LookupResult::Slice { value } = result else {
+                panic!("Value not found");
+            };
+            assert_eq!(&*value, &[i as u8; 10][..]);
+        } else {
+            assert!(matches!(
+                result,
+                LookupResult::KeyMiss | LookupResult::RangeMiss
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn corrupt_header_is_cached() -> Result<()> {
+    let file = StaticSortedFile::open_from_bytes(1, Arc::from(vec![0; 64]));
+    let first = file.block_count().unwrap_err().to_string();
+    assert!(file
+        .mapping()
+        .header
+        .get()
+        .is_some_and(|header| header.is_err()));
+    let err = file.block_count().unwrap_err();
+    assert_eq!(err.to_string(), first);
+    assert!(matches!(
+        err.downcast_ref::<StaticSortedFileError>(),
+        Some(StaticSortedFileError::InvalidMagic)
+    ));
+
+    let mut buffer = vec![0; 64];
+    buffer[..3].copy_from_slice(&MAGIC.to_be_bytes()[1..]);
+    buffer[3] = 9;
+    let file = StaticSortedFile::open_from_bytes(1, Arc::from(buffer));
+    for _ in 0..2 {
+        let err = file.format_version().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<StaticSortedFileError>(),
+            Some(StaticSortedFileError::UnsupportedVersion(9))
+        ));
+    }
+    Ok(())
+}
+
+/// An in-memory file whose first reads fail.
+struct FailingBacking {
+    bytes: Arc<[u8]>,
+    failures: AtomicUsize,
+}
+
+impl FileBacking for FailingBacking {
+    fn size(&self) -> u64 {
+        self.bytes.len() as u64
+    }
+
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+        if self
+            .failures
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .is_ok()
+        {
+            return Err(std::io::ErrorKind::Interrupted.into());
+        }
+        self.bytes.read_exact_at(buf, offset)
+    }
+}
+
+#[test]
+fn header_read_error_is_not_cached() -> Result<()> {
+    let file = StaticSortedFile::from_backing(
+        1,
+        Box::new(FailingBacking {
+            bytes: Arc::from(
+                build_file(1, &numbered_entries(10))?
+                    .mapping()
+                    .backing
+                    .as_slice()
+                    .unwrap(),
+            ),
+            failures: AtomicUsize::new(1),
+        }),
+    );
+    let err = file.block_count().unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<std::io::Error>().map(|err| err.kind()),
+        Some(std::io::ErrorKind::Interrupted)
+    );
+    assert!(file.mapping().header.get().is_none());
+    assert!(file.block_count()? > 0);
+    Ok(())
+}
+
+#[test]
+fn dictionary_accessors() -> Result<()> {
+    let entries = (0..20000u32)
+        .map(|i| {
+            let key = format!("key-{i:08}-{}", i % 7).into_bytes();
+            let value = format!("value-{}-{}-{}", i, i % 13, i * 31).into_bytes();
+            (key, value)
+        })
+        .collect::<Vec<_>>();
+    let file = build_file(1, &entries)?;
+    let header = &file.header()?;
+    let mapping = file.mapping();
+    let bytes = mapping.backing.as_slice().unwrap();
+    let key_dictionary = file.key_dictionary()?;
+    let value_dictionary = file.value_dictionary()?;
+    assert!(!key_dictionary.is_empty() && !value_dictionary.is_empty());
+    assert_eq!(
+        key_dictionary,
+        &bytes[header.key_compression_dictionary.start..header.key_compression_dictionary.end]
+    );
+    assert_eq!(
+        value_dictionary,
+        &bytes[header.value_compression_dictionary.start..header.value_compression_dictionary.end]
+    );
+
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("00000001.sst");
+    std::fs::write(&path, bytes)?;
+    let pread_file = StaticSortedFile::open_without_mmap(1, path)?;
+    assert_eq!(pread_file.key_dictionary()?, key_dictionary);
+    assert_eq!(pread_file.value_dictionary()?, value_dictionary);
+
+    let small = build_file(1, &[(b"key".to_vec(), b"value".to_vec())])?;
+    assert!(small.key_dictionary()?.is_empty());
+    assert!(small.value_dictionary()?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn index_block_beyond_block_count() -> Result<()> {
+    let entries = numbered_entries(1000);
+    let file = build_file(1, &entries)?;
+    let caches = Caches::new();
+    // Replace the root index block with one that refers to a block that doesn't exist.
+    let block_count = file.block_count()? as u16;
+    let mut block = vec![BLOCK_TYPE_INDEX];
+    block.extend_from_slice(&(block_count + 5).to_be_bytes());
+    caches.key_blocks.insert(
+        file.mapping().block_cache_key(block_count - 1),
+        ArcSlice::from(Arc::<[u8]>::from(block)),
+    );
+
+    let is_corrupt = |err: &anyhow::Error| {
+        matches!(
+            err.downcast_ref::<StaticSortedFileError>(),
+            Some(StaticSortedFileError::CorruptIndexBlock { next_block, .. })
+                if *next_block == block_count + 5
+        )
+    };
+    let key = 0u32.to_be_bytes().to_vec();
+    let result = caches.lookup(&file, &key);
+    assert!(result.is_err_and(|err| is_corrupt(&err)));
+    let err = file
+        .iter(&caches.key_blocks, &caches.value_blocks)?
+        .find_map(|entry| entry.err())
+        .unwrap();
+    assert!(is_corrupt(&err));
+    Ok(())
+}
+
+#[test]
+fn mmap_block_threshold() -> Result<()> {
+    let entries = numbered_entries(10000);
+    let file = build_file(1, &entries)?;
+    let bytes: Arc<[u8]> = Arc::from(file.mapping().backing.as_slice().unwrap());
+    for threshold in [1, 4096] {
+        let file = StaticSortedFile::open_from_bytes(1, bytes.clone())
+            .with_mmap_block_threshold(threshold);
+        let caches = Caches::new();
+        for (key, value) in entries.iter() {
+            let found = caches.lookup_value(&file, key)?;
+            assert_eq!(&*found, &value[..]);
+        }
+        let keys = file
+            .iter(&caches.key_blocks, &caches.value_blocks)?
+            .map(|entry| Ok(entry?.key.to_vec()))
+            .collect::<Result<HashSet<_>>>()?;
+        assert_eq!(keys.len(), entries.len());
+    }
+    Ok(())
+}
+
+#[test]
+fn max_block_size() -> Result<()> {
+    let medium = vec![7; 100000];
+    let file = build_file_with_values(
+        1,
+        vec![(
+            vec![1],
+            CollectorEntryValue::Medium {
+                value: medium.clone(),
+            },
+        )],
+    )?;
+    let bytes: Arc<[u8]> = Arc::from(file.mapping().backing.as_slice().unwrap());
+    let caches = Caches::new();
+    let value = caches.lookup_value(&file, &[1u8])?;
+    assert_eq!(&*value, &medium[..]);
+
+    let file = StaticSortedFile::open_from_bytes(2, bytes).with_max_block_size(1000);
+    let err = caches.lookup(&file, &[1u8]).err().unwrap();
+    assert!(matches!(
+        err.downcast_ref::<StaticSortedFileError>(),
+        Some(StaticSortedFileError::BlockTooLarge {
+            uncompressed_length: 100000,
+            max_block_size: 1000,
+            ..
+        })
+    ));
+    Ok(())
+}
+
+#[test]
+fn scan() -> Result<()> {
+    let file = build_file_with_values(
+        1,
+        vec![
+            (
+                b"live".to_vec(),
+                CollectorEntryValue::Small {
+                    value: b"value".to_vec(),
+                },
+            ),
+            (b"deleted".to_vec(), CollectorEntryValue::Deleted),
+            (b"blob".to_vec(), CollectorEntryValue::Large { blob: 42 }),
+        ],
+    )?;
+    let caches = Caches::new();
+    let entries = file
+        .scan(&caches.key_blocks, &caches.value_blocks)?
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(entries.len(), 3);
+    assert_eq!(caches.value_blocks.len(), 0);
+    for entry in entries.iter() {
+        match (&*entry.key, entry.kind()) {
+            (b"live", ScanEntryKind::Value) => {
+                let LookupValue::Slice { value } = entry.value()? else {
+                    panic!("Value not found");
+                };
+                assert_eq!(&*value, b"value");
+            }
+            (b"deleted", ScanEntryKind::Deleted) => {
+                assert!(matches!(entry.value()?, LookupValue::Deleted));
+            }
+            (
+                b"blob",
+                ScanEntryKind::Blob {
+                    sequence_number: 42,
+                },
+            ) => {
+                assert!(matches!(
+                    entry.value()?,
+                    LookupValue::Blob {
+                        sequence_number: 42
+                    }
+                ));
+            }
+            (key, kind) => panic!("Unexpected entry {key:?} {kind:?}"),
+        }
+    }
+    assert_eq!(caches.value_blocks.len(), 1);
+    Ok(())
+}
+
+#[test]
+fn value_size() -> Result<()> {
+    let file = build_file_with_values(
+        1,
+        vec![
+            (
+                b"small".to_vec(),
+                CollectorEntryValue::Small {
+                    value: vec![1; 100],
+                },
+            ),
+            (
+                b"medium".to_vec(),
+                CollectorEntryValue::Medium {
+                    value: vec![2; 100000],
+                },
+            ),
+            (b"deleted".to_vec(), CollectorEntryValue::Deleted),
+            (b"blob".to_vec(), CollectorEntryValue::Large { blob: 42 }),
+        ],
+    )?;
+    let caches = Caches::new();
+    let value_size = |key: &[u8]| {
+        file.value_size(
+            0,
+            hash_key(&key),
+            &key,
+            &caches.aqmf,
+            &caches.key_blocks,
+            &LookupOptions::default(),
+        )
+    };
+    assert_eq!(value_size(b"small")?, Some(100));
+    assert_eq!(value_size(b"medium")?, Some(100000));
+    assert_eq!(value_size(b"deleted")?, None);
+    assert_eq!(value_size(b"blob")?, None);
+    assert_eq!(value_size(b"missing")?, None);
+    assert_eq!(caches.value_blocks.len(), 0);
+    Ok(())
+}
+
+#[test]
+fn entry_type() -> Result<()> {
+    let file = build_file_with_values(
+        1,
+        vec![
+            (
+                b"small".to_vec(),
+                CollectorEntryValue::Small { value: vec![1; 10] },
+            ),
+            (
+                b"medium".to_vec(),
+                CollectorEntryValue::Medium {
+                    value: vec![2; 100000],
+                },
+            ),
+            (b"deleted".to_vec(), CollectorEntryValue::Deleted),
+            (b"blob".to_vec(), CollectorEntryValue::Large { blob: 42 }),
+        ],
+    )?;
+    let caches = Caches::new();
+    let entry_type = |key: &[u8]| {
+        file.entry_type(
+            0,
+            hash_key(&key),
+            &key,
+            &caches.aqmf,
+            &caches.key_blocks,
+            &LookupOptions::default(),
+        )
+    };
+    assert_eq!(entry_type(b"small")?, Some(KEY_BLOCK_ENTRY_TYPE_SMALL));
+    assert_eq!(entry_type(b"medium")?, Some(KEY_BLOCK_ENTRY_TYPE_MEDIUM));
+    assert_eq!(entry_type(b"deleted")?, Some(KEY_BLOCK_ENTRY_TYPE_DELETED));
+    assert_eq!(entry_type(b"blob")?, Some(KEY_BLOCK_ENTRY_TYPE_BLOB));
+    assert_eq!(entry_type(b"missing")?, None);
+    assert_eq!(caches.value_blocks.len(), 0);
+    Ok(())
+}
+
+#[test]
+fn remap() -> Result<()> {
+    let old_entries = numbered_entries(100);
+    let new_entries = (0..1000u32)
+        .map(|i| (i.to_be_bytes().to_vec(), vec![i as u8 ^ 0xff; 10]))
+        .collect::<Vec<_>>();
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("00000001.sst");
+    std::fs::write(
+        &path,
+        build_file(1, &old_entries)?
+            .mapping()
+            .backing
+            .as_slice()
+            .unwrap(),
+    )?;
+    let file = StaticSortedFile::open(1, path.clone())?;
+    assert_eq!(file.entry_count()?, 100);
+
+    // Replace the file instead of overwriting it, since it's still mapped.
+    let tmp_path = dir.path().join("00000001.sst.tmp");
+    std::fs::write(
+        &tmp_path,
+        build_file(1, &new_entries)?
+            .mapping()
+            .backing
+            .as_slice()
+            .unwrap(),
+    )?;
+    std::fs::rename(&tmp_path, &path)?;
+    assert_eq!(file.entry_count()?, 100);
+    file.remap(path)?;
+    assert_eq!(file.entry_count()?, 1000);
+    let caches = Caches::new();
+    let key = 500u32.to_be_bytes();
+    let value = caches.lookup_value(&file, &key)?;
+    assert_eq!(&*value, &[500u32 as u8 ^ 0xff; 10][..]);
+    Ok(())
+}
+
+#[test]
+fn remap_releases_replaced_mapping() -> Result<()> {
+    let entries = numbered_entries(100);
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("00000001.sst");
+    let bytes = build_file(1, &entries)?
+        .mapping()
+        .backing
+        .as_slice()
+        .unwrap()
+        .to_vec();
+    std::fs::write(&path, &bytes)?;
+    let file = StaticSortedFile::open(1, path.clone())?;
+    let caches = Caches::new();
+    let old_mapping = Arc::downgrade(&file.mapping());
+
+    // An iterator that started before the remap keeps the replaced mapping alive.
+    let mut iter = file.iter(&caches.key_blocks, &caches.value_blocks)?;
+    let first = iter.next().unwrap()?;
+    file.remap(path)?;
+    assert!(old_mapping.upgrade().is_some());
+    assert!(entries.iter().any(|(key, _)| key[..] == first.key[..]));
+    assert_eq!(iter.count() + 1, entries.len());
+    assert!(old_mapping.upgrade().is_none());
+    Ok(())
+}
+
+#[test]
+fn remap_with_shared_caches() -> Result<()> {
+    let old_entries = numbered_entries(100);
+    let new_entries = (0..1000u32)
+        .map(|i| (i.to_be_bytes().to_vec(), vec![i as u8 ^ 0xff; 10]))
+        .collect::<Vec<_>>();
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("00000001.sst");
+    std::fs::write(
+        &path,
+        build_file(1, &old_entries)?
+            .mapping()
+            .backing
+            .as_slice()
+            .unwrap(),
+    )?;
+    let file = StaticSortedFile::open(1, path.clone())?;
+    let caches = Caches::new();
+    let lookup = |file: &StaticSortedFile, key: u32| {
+        let key = key.to_be_bytes();
+        caches.lookup(file, &key)
+    };
+    assert_eq!(lookup(&file, 50)?.found_value().unwrap()[..], [50; 10]);
+
+    let tmp_path = dir.path().join("00000001.sst.tmp");
+    std::fs::write(
+        &tmp_path,
+        build_file(1, &new_entries)?
+            .mapping()
+            .backing
+            .as_slice()
+            .unwrap(),
+    )?;
+    std::fs::rename(&tmp_path, &path)?;
+    file.remap(path)?;
+    // The blocks and the filter of the old version are still cached, but not returned.
+    assert_eq!(
+        lookup(&file, 50)?.found_value().unwrap()[..],
+        [50 ^ 0xff; 10]
+    );
+    assert_eq!(
+        lookup(&file, 500)?.found_value().unwrap()[..],
+        [500u32 as u8 ^ 0xff; 10]
+    );
+    Ok(())
+}
+
+#[test]
+fn reopen_with_shared_caches() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("00000001.sst");
+    let write = |entries: &[(Vec<u8>, Vec<u8>)]| -> Result<()> {
+        let tmp_path = dir.path().join("00000001.sst.tmp");
+        std::fs::write(
+            &tmp_path,
+            build_file(1, entries)?
+                .mapping()
+                .backing
+                .as_slice()
+                .unwrap(),
+        )?;
+        std::fs::rename(&tmp_path, &path)?;
+        Ok(())
+    };
+    let caches = Caches::new();
+    let lookup = |file: &StaticSortedFile, key: u32| caches.lookup(file, &key.to_be_bytes());
+
+    write(&numbered_entries(100))?;
+    let file = StaticSortedFile::open(1, path.clone())?;
+    assert_eq!(lookup(&file, 50)?.found_value().unwrap()[..], [50; 10]);
+    assert!(lookup(&file, 500)?.is_miss());
+
+    // A second instance of the new version doesn't see the blocks that were cached for the first
+    // instance, neither before nor after its remap.
+    write(
+        &(0..1000u32)
+            .map(|i| (i.to_be_bytes().to_vec(), vec![i as u8 ^ 0xff; 10]))
+            .collect::<Vec<_>>(),
+    )?;
+    file.remap(path.clone())?;
+    let reopened = StaticSortedFile::open(1, path)?;
+    assert_eq!(
+        lookup(&reopened, 50)?.found_value().unwrap()[..],
+        [50 ^ 0xff; 10]
+    );
+    assert_eq!(
+        lookup(&reopened, 500)?.found_value().unwrap()[..],
+        [500u32 as u8 ^ 0xff; 10]
+    );
+    assert_eq!(
+        lookup(&file, 50)?.found_value().unwrap()[..],
+        [50 ^ 0xff; 10]
+    );
+    Ok(())
+}
+
+#[test]
+fn read_blocks_decompressed() -> Result<()> {
+    let entries = numbered_entries(1000);
+    let file = build_file(1, &entries)?;
+    let caches = Caches::new();
+    let block_count = file.block_count()? as u16;
+    let root = file.read_key_block_decompressed(block_count - 1, &caches.key_blocks)?;
+    assert_eq!(root[0], BLOCK_TYPE_INDEX);
+    let mut children = &root[1..];
+    let first_key_block = children.read_u16::<BE>()?;
+    let key_block = file.read_key_block_decompressed(first_key_block, &caches.key_blocks)?;
+    assert_eq!(key_block[0], BLOCK_TYPE_KEY);
+    assert_eq!(caches.key_blocks.len(), 2);
+
+    let value_block = file.read_value_block_decompressed(0, &caches.value_blocks)?;
+    assert_eq!(value_block.len(), file.block_uncompressed_len(0)?);
+    assert!(file
+        .read_key_block_decompressed(block_count, &caches.key_blocks)
+        .is_err());
+    Ok(())
+}
+
+#[test]
+fn read_block_round_trip() -> Result<()> {
+    let entries = numbered_entries(1000);
+    let file = build_file(1, &entries)?;
+    let bytes: Arc<[u8]> = Arc::from(file.mapping().backing.as_slice().unwrap());
+    let mmap_file = StaticSortedFile::open_from_bytes(1, bytes).with_mmap_block_threshold(1);
+    let header = &file.header()?;
+    let root = &file.index_structure()?[0];
+    for block_index in 0..header.block_count {
+        let dictionary = if block_index == root.block_index || root.children.contains(&block_index)
+        {
+            file.key_compression_dictionary(header)?
+        } else {
+            file.value_compression_dictionary(header)?
+        };
+        // The heap, the mmap and the `Vec` decompression paths return the same bytes.
+        let block = file.read_block(header, block_index, dictionary, usize::MAX)?;
+        let mmap_block =
+            mmap_file.read_block(&mmap_file.header()?, block_index, dictionary, usize::MAX)?;
+        let mut out = vec![42];
+        file.read_block_into(header, block_index, dictionary, &mut out)?;
+        assert_eq!(block.len(), file.block_uncompressed_len(block_index)?);
+        assert_eq!(&*block, &*mmap_block);
+        assert_eq!(&block[..], &out[1..]);
+        let prefix = file.read_block(header, block_index, dictionary, 5)?;
+        assert_eq!(&prefix[..], &block[..block.len().min(5)]);
+    }
+    Ok(())
+}
+
+#[test]
+fn block_observer() -> Result<()> {
+    #[derive(Default)]
+    struct Samples(Mutex<Vec<(u16, usize, usize)>>);
+
+    impl BlockObserver for Samples {
+        fn on_decompress(
+            &self,
+            block_index: u16,
+            compressed_len: usize,
+            uncompressed_len: usize,
+            _duration: Duration,
+        ) {
+            self.0
+                .lock()
+                .unwrap()
+                .push((block_index, compressed_len, uncompressed_len));
+        }
+    }
+
+    let entries = numbered_entries(1000);
+    let samples = Arc::new(Samples::default());
+    let file = build_file(1, &entries)?.with_block_observer(samples.clone());
+    let caches = Caches::new();
+    let (key, _) = &entries[500];
+    let lookup = || caches.lookup(&file, key);
+    assert!(lookup()?.found_value().is_some());
+    // The root index block, the key block and the value block.
+    let observed = samples.0.lock().unwrap().clone();
+    assert_eq!(observed.len(), 3);
+    for &(block_index, _, uncompressed_len) in observed.iter() {
+        assert_eq!(uncompressed_len, file.block_uncompressed_len(block_index)?);
+    }
+
+    // Cached blocks are not decompressed again.
+    lookup()?;
+    assert_eq!(samples.0.lock().unwrap().len(), 3);
+    Ok(())
+}
+
+#[test]
+fn small_values_share_block() -> Result<()> {
+    let entries = numbered_entries(100);
+    let file = build_file(1, &entries)?;
+    let caches = Caches::new();
+    let values = entries
+        .iter()
+        .map(|(key, _)| {
+            let value = caches.lookup_value(&file, key)?;
+            Ok(value)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    // All values are slices of the one cached value block.
+    assert_eq!(caches.value_blocks.len(), 1);
+    assert!(values[1..]
+        .iter()
+        .all(|value| value.shares_allocation(&values[0])));
+    let other = ArcSlice::from(Arc::<[u8]>::from(values[0].to_vec()));
+    assert!(!other.shares_allocation(&values[0]));
+    Ok(())
+}
+
+#[test]
+fn negative_cache() -> Result<()> {
+    let entries = (0..1000u32)
+        .map(|i| ((i * 2).to_be_bytes().to_vec(), vec![i as u8; 10]))
+        .collect::<Vec<_>>();
+    let negative_cache = Arc::new(NegativeCache::with(
+        100,
+        100,
+        Default::default(),
+        Default::default(),
+        Default::default(),
+    ));
+    let file = build_file(1, &entries)?.with_negative_cache(negative_cache.clone());
+    // The filter is skipped, so absent keys are searched in the key blocks.
+    let options = LookupOptions {
+        skip_aqmf: true,
+        ..Default::default()
+    };
+    let lookup = |key: u32, caches: &Caches| {
+        let key = key.to_be_bytes();
+        file.lookup_with_options(
+            0,
+            hash_key(&key),
+            &key,
+            &caches.aqmf,
+            &caches.key_blocks,
+            &caches.value_blocks,
+            &options,
+        )
+    };
+
+    let caches = Caches::new();
+    assert!(lookup(500, &caches)?.found_value().is_some());
+    assert_eq!(negative_cache.len(), 0);
+    assert!(matches!(lookup(501, &caches)?, LookupResult::KeyMiss));
+    assert_eq!(negative_cache.len(), 1);
+
+    // The repeated miss doesn't read any key blocks.
+    let caches = Caches::new();
+    assert!(matches!(lookup(501, &caches)?, LookupResult::KeyMiss));
+    assert_eq!(caches.key_blocks.len(), 0);
+    assert!(lookup(500, &caches)?.found_value().is_some());
+    Ok(())
+}
+
+#[test]
+fn negative_cache_after_reopen() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("00000001.sst");
+    let negative_cache = Arc::new(NegativeCache::with(
+        100,
+        100,
+        Default::default(),
+        Default::default(),
+        Default::default(),
+    ));
+    let caches = Caches::new();
+    // The filter is skipped, so misses are recorded in the negative cache.
+    let options = LookupOptions {
+        skip_aqmf: true,
+        ..Default::default()
+    };
+    let lookup = |file: &StaticSortedFile, key: u32| {
+        let key = key.to_be_bytes();
+        file.lookup_with_options(
+            0,
+            hash_key(&key),
+            &key,
+            &caches.aqmf,
+            &caches.key_blocks,
+            &caches.value_blocks,
+            &options,
+        )
+    };
+
+    std::fs::write(
+        &path,
+        build_file(1, &numbered_entries(100))?
+            .mapping()
+            .backing
+            .as_slice()
+            .unwrap(),
+    )?;
+    let file = StaticSortedFile::open(1, path.clone())?.with_negative_cache(negative_cache.clone());
+    assert!(lookup(&file, 500)?.is_miss());
+    assert_eq!(negative_cache.len(), 1);
+    drop(file);
+
+    // A new instance for a new version of the file doesn't see the misses of the old one.
+    std::fs::write(
+        &path,
+        build_file(1, &numbered_entries(1000))?
+            .mapping()
+            .backing
+            .as_slice()
+            .unwrap(),
+    )?;
+    let file = StaticSortedFile::open(1, path)?.with_negative_cache(negative_cache);
+    assert_eq!(
+        lookup(&file, 500)?.found_value().unwrap()[..],
+        [500u32 as u8; 10]
+    );
+    Ok(())
+}
+
+#[test]
+fn content_block_cache() -> Result<()> {
+    let entries = numbered_entries(100);
+    let content_block_cache = Arc::new(ContentBlockCache::with(
+        100,
+        1024 * 1024,
+        Default::default(),
+        Default::default(),
+        Default::default(),
+    ));
+    // Two files with identical value blocks, e. g. from a deduplicating writer.
+    let files = [build_file(1, &entries)?, build_file(2, &entries)?]
+        .map(|file| file.with_content_block_cache(content_block_cache.clone()));
+    let caches = Caches::new();
+    let (key, value) = &entries[0];
+    let values = files
+        .iter()
+        .map(|file| caches.lookup_value(file, key))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(&*values[0], &value[..]);
+    assert!(values[0].shares_allocation(&values[1]));
+    assert_eq!(content_block_cache.len(), 1);
+    assert_eq!(caches.value_blocks.len(), 0);
+    Ok(())
+}
+
+/// An in-memory file that is only accessible through reads, which are counted.
+struct CountingBacking {
+    bytes: Arc<[u8]>,
+    reads: Arc<AtomicUsize>,
+}
+
+impl FileBacking for CountingBacking {
+    fn size(&self) -> u64 {
+        self.bytes.len() as u64
+    }
+
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+        self.reads.fetch_add(1, Ordering::SeqCst);
+        self.bytes.read_exact_at(buf, offset)
+    }
+}
+
+#[test]
+fn content_block_cache_hit_without_read() -> Result<()> {
+    let entries = numbered_entries(100);
+    let reads = Arc::new(AtomicUsize::new(0));
+    let file = StaticSortedFile::from_backing(
+        1,
+        Box::new(CountingBacking {
+            bytes: Arc::from(
+                build_file(1, &entries)?
+                    .mapping()
+                    .backing
+                    .as_slice()
+                    .unwrap(),
+            ),
+            reads: reads.clone(),
+        }),
+    )
+    .with_content_block_cache(Arc::new(ContentBlockCache::with(
+        100,
+        1024 * 1024,
+        Default::default(),
+        Default::default(),
+        Default::default(),
+    )));
+    let caches = Caches::new();
+    let (key, value) = &entries[0];
+    assert_eq!(&*caches.lookup_value(&file, key)?, &value[..]);
+    // The content key of the block is known, so a cache hit doesn't read the compressed block.
+    let reads_before = reads.load(Ordering::SeqCst);
+    assert_eq!(&*caches.lookup_value(&file, key)?, &value[..]);
+    assert_eq!(reads.load(Ordering::SeqCst), reads_before);
+    Ok(())
+}
+
+#[test]
+fn duplicate_keys() -> Result<()> {
+    // Simulate a buggy writer that emits every third key twice.
+    let mut entries = Vec::new();
+    for i in 0..300u32 {
+        let copies = if i % 3 == 0 { 2 } else { 1 };
+        for copy in 0..copies {
+            entries.push((i.to_be_bytes().to_vec(), vec![copy; 10]));
+        }
+    }
+    let file = build_file(1, &entries)?;
+    let caches = Caches::new();
+
+    let all = file
+        .iter(&caches.key_blocks, &caches.value_blocks)?
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(all.len(), 400);
+
+    let deduplicated = file
+        .iter(&caches.key_blocks, &caches.value_blocks)?
+        .with_duplicate_keys(DuplicateKeys::KeepLast)
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(deduplicated.len(), 300);
+    for entry in deduplicated.iter() {
+        let i = u32::from_be_bytes(entry.key[..].try_into()?);
+        let LookupValue::Slice { value } = &entry.value else {
+            panic!("Value not found");
+        };
+        assert_eq!(value[0], if i % 3 == 0 { 1 } else { 0 });
+    }
+
+    let err = file
+        .iter(&caches.key_blocks, &caches.value_blocks)?
+        .with_duplicate_keys(DuplicateKeys::Error)
+        .find_map(|entry| entry.err())
+        .unwrap();
+    assert!(matches!(
+        err.downcast_ref::<StaticSortedFileError>(),
+        Some(StaticSortedFileError::DuplicateKey { .. })
+    ));
+
+    // Lookups of a duplicate key always return the same entry.
+    let key = 0u32.to_be_bytes();
+    let lookup = || -> Result<u8> {
+        let value = caches.lookup_value(&file, &key)?;
+        Ok(value[0])
+    };
+    let first = lookup()?;
+    for _ in 0..10 {
+        assert_eq!(lookup()?, first);
+    }
+    Ok(())
+}
+
+#[test]
+fn corrupt_bytes_dont_panic() -> Result<()> {
+    use rand::{Rng, SeedableRng};
+
+    let entries = (0..300u32)
+        .map(|i| {
+            (
+                i.to_be_bytes().to_vec(),
+                vec![i as u8; 10 + i as usize % 50],
+            )
+        })
+        .collect::<Vec<_>>();
+    let file = build_file(1, &entries)?;
+    let bytes = file.mapping().backing.as_slice().unwrap().to_vec();
+    let mut rnd = rand::rngs::SmallRng::from_seed([0; 32]);
+    for _ in 0..2000 {
+        let mut bytes = bytes.clone();
+        match rnd.gen_range(0..3) {
+            0 => bytes.truncate(rnd.gen_range(0..bytes.len())),
+            _ => {
+                for _ in 0..rnd.gen_range(1..8) {
+                    let index = rnd.gen_range(0..bytes.len());
+                    bytes[index] = rnd.gen();
+                }
+            }
+        }
+        let file = StaticSortedFile::from_bytes_for_fuzz(bytes);
+        let caches = Caches::new();
+        for (key, _) in entries.iter().step_by(37) {
+            let _ = caches.lookup(&file, key);
+        }
+        if let Ok(iter) = file.iter(&caches.key_blocks, &caches.value_blocks) {
+            for entry in iter.take(1000) {
+                if entry.is_err() {
+                    break;
+                }
+            }
+        }
+        for block in 0..file.block_count().unwrap_or(0) {
+            if let Ok(mut reader) = file.value_block_reader(block as u16) {
+                let _ = std::io::copy(&mut reader, &mut std::io::sink());
+            }
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn lookup_traced_read_amplification() -> Result<()> {
+    let entries = numbered_entries(10000);
+    let file = build_file(1, &entries)?;
+    let caches = Caches::new();
+    let key = 1234u32.to_be_bytes();
+    let lookup = || {
+        file.lookup_traced(
+            0,
+            hash_key(&key),
+            &key,
+            &caches.aqmf,
+            &caches.key_blocks,
+            &caches.value_blocks,
+            &LookupOptions::default(),
+        )
+    };
+    let (_, cold) = lookup()?;
+    // All index blocks, the key block and the value block.
+    assert_eq!(cold.blocks_read, cold.index_blocks.len() + 2);
+    let mut key_blocks_size = 0;
+    for (block, _) in cold.index_blocks.iter() {
+        key_blocks_size += file.block_uncompressed_len(*block)? as u64;
+    }
+    key_blocks_size += file.block_uncompressed_len(cold.key_block.unwrap())? as u64;
+    assert!(cold.bytes_decompressed > key_blocks_size);
+
+    let (_, warm) = lookup()?;
+    assert_eq!(warm.blocks_read, cold.blocks_read);
+    assert_eq!(warm.bytes_decompressed, 0);
+    Ok(())
+}
+
+#[test]
+fn open_from_file() -> Result<()> {
+    let entries = numbered_entries(1000);
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("00000001.sst");
+    std::fs::write(
+        &path,
+        build_file(1, &entries)?
+            .mapping()
+            .backing
+            .as_slice()
+            .unwrap(),
+    )?;
+    let file = StaticSortedFile::open_from_file(1, &std::fs::File::open(&path)?)?;
+    assert_eq!(file.entry_count()?, entries.len());
+    let caches = Caches::new();
+    let (key, value) = &entries[500];
+    let found = caches.lookup_value(&file, key)?;
+    assert_eq!(&*found, &value[..]);
+    Ok(())
+}
+
+#[cfg(feature = "stats")]
+#[test]
+fn aqmf_statistics() -> Result<()> {
+    let entries = numbered_entries(1000);
+    let file = build_file(1, &entries)?;
+    let caches = Caches::new();
+    let mut quick_filter_misses = 0;
+    for i in 0..2000u32 {
+        let key = i.to_be_bytes();
+        let result = caches.lookup(&file, &key)?;
+        if matches!(result, LookupResult::QuickFilterMiss) {
+            quick_filter_misses += 1;
+        }
+    }
+    let stats = file.aqmf_statistics();
+    assert_eq!(stats.true_positives, 1000);
+    // Every absent key is either rejected by the filter or a false positive, unless it's out of
+    // the hash range of the file.
+    assert!(stats.false_positives + quick_filter_misses <= 1000);
+    assert!(stats.false_positives < 100);
+    Ok(())
+}
+
+#[test]
+fn iter_readahead() -> Result<()> {
+    let entries = numbered_entries(10000);
+    let file = build_file(1, &entries)?;
+    let caches = Caches::new();
+    let expected = file
+        .iter(&caches.key_blocks, &caches.value_blocks)?
+        .map(|entry| Ok(entry?.key.to_vec()))
+        .collect::<Result<Vec<_>>>()?;
+    for window in [0, 1, 2, 7, 1000] {
+        let caches = Caches::new();
+        let keys = file
+            .iter(&caches.key_blocks, &caches.value_blocks)?
+            .with_readahead(window)
+            .map(|entry| Ok(entry?.key.to_vec()))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(keys, expected);
+    }
+
+    // Reading ahead fills the cache with the key blocks before they are reached.
+    let caches = Caches::new();
+    let mut iter = file
+        .iter(&caches.key_blocks, &caches.value_blocks)?
+        .with_readahead(1000);
+    iter.next().unwrap()?;
+    assert!(caches.key_blocks.len() > 2);
+
+    // Nothing is read ahead when the cache is full.
+    let small_cache = BlockCache::with(
+        10,
+        1,
+        Default::default(),
+        Default::default(),
+        Default::default(),
+    );
+    let mut iter = file
+        .iter(&small_cache, &caches.value_blocks)?
+        .with_readahead(1000);
+    iter.next().unwrap()?;
+    assert!(small_cache.len() <= 2);
+    Ok(())
+}
+
+#[test]
+fn estimate_count_in_range() -> Result<()> {
+    let entries = numbered_entries(100000);
+    let file = build_file(1, &entries)?;
+    let mut hashes = entries
+        .iter()
+        .map(|(key, _)| hash_key(key))
+        .collect::<Vec<_>>();
+    hashes.sort_unstable();
+    let caches = Caches::new();
+    let estimate =
+        |start: u64, end: u64| file.estimate_count_in_range(start, end, &caches.key_blocks);
+    assert_eq!(estimate(0, u64::MAX)?, hashes.len());
+    assert_eq!(estimate(10, 10)?, 0);
+    assert_eq!(estimate(u64::MAX - 1, 0)?, 0);
+    for (start, end) in [(0, 10), (100, 50000), (1234, 99999), (50000, 50001)] {
+        let exact = end - start;
+        let estimated = estimate(hashes[start], hashes[end])?;
+        assert!(
+            estimated.abs_diff(exact) <= exact / 10 + 1,
+            "{start}..{end}: estimated {estimated}, exact {exact}"
+        );
+    }
+    // Only the index block and the key blocks at the bounds are read.
+    assert!(caches.key_blocks.len() < 20);
+    Ok(())
+}
+
+#[test]
+fn estimate_count_in_range_with_two_index_levels() -> Result<()> {
+    let entries = numbered_entries(100000);
+    let file = build_file(1, &entries)?;
+    let mut hashes = entries
+        .iter()
+        .map(|(key, _)| hash_key(key))
+        .collect::<Vec<_>>();
+    hashes.sort_unstable();
+    let caches = Caches::new();
+
+    // Split the root index block into two index blocks below a new root. Children must have
+    // smaller block indices than their parents, so each new index block takes the place of the
+    // last key block of its half, and that key block moves to the place of a value block. The
+    // blocks are replaced in the key block cache.
+    let header = &file.header()?;
+    let root_index = header.block_count - 1;
+    let root = file.read_key_block(header, root_index)?;
+    let mut children = vec![(0, u16::from_be_bytes([root[1], root[2]]))];
+    for entry in root[3..].chunks(10) {
+        children.push((
+            u64::from_be_bytes(entry[..8].try_into()?),
+            u16::from_be_bytes(entry[8..].try_into()?),
+        ));
+    }
+    assert!(children.len() > 10);
+    let index_block = |children: &[(u64, u16)]| {
+        let mut block = vec![BLOCK_TYPE_INDEX];
+        block.extend_from_slice(&children[0].1.to_be_bytes());
+        for (hash, child) in &children[1..] {
+            block.extend_from_slice(&hash.to_be_bytes());
+            block.extend_from_slice(&child.to_be_bytes());
+        }
+        ArcSlice::from(Arc::<[u8]>::from(block))
+    };
+    let mut value_blocks =
+        (0..root_index).filter(|block| children.iter().all(|(_, child)| child != block));
+    let mut new_root = Vec::new();
+    let (left, right) = children.split_at(children.len() / 2);
+    for half in [left, right] {
+        let mut half = half.to_vec();
+        let (_, last) = half.iter_mut().max_by_key(|(_, block)| *block).unwrap();
+        let index_block_index = *last;
+        *last = value_blocks.next().unwrap();
+        caches.key_blocks.insert(
+            file.mapping().block_cache_key(*last),
+            file.read_key_block(header, index_block_index)?,
+        );
+        caches.key_blocks.insert(
+            file.mapping().block_cache_key(index_block_index),
+            index_block(&half),
+        );
+        new_root.push((half[0].0, index_block_index));
+    }
+    caches.key_blocks.insert(
+        file.mapping().block_cache_key(root_index),
+        index_block(&new_root),
+    );
+
+    let estimate =
+        |start: u64, end: u64| file.estimate_count_in_range(start, end, &caches.key_blocks);
+    for (start, end) in [(0, 10), (100, 50000), (1234, 99999), (50000, 50001)] {
+        let exact = end - start;
+        let estimated = estimate(hashes[start], hashes[end])?;
+        assert!(
+            estimated.abs_diff(exact) <= exact / 10 + 1,
+            "{start}..{end}: estimated {estimated}, exact {exact}"
+        );
+    }
+    let estimated = estimate(0, u64::MAX)?;
+    assert!(estimated.abs_diff(hashes.len()) <= hashes.len() / 10);
+    Ok(())
+}
+
+#[test]
+fn prewarm() -> Result<()> {
+    let entries = (0..10000u32)
+        .map(|i| (i.to_be_bytes().to_vec(), vec![i as u8; 100]))
+        .collect::<Vec<_>>();
+    let file = build_file(1, &entries)?;
+    let caches = Caches::new();
+    file.prewarm(
+        PrewarmBlocks::Keys,
+        &caches.key_blocks,
+        &caches.value_blocks,
+    )?;
+    let key_blocks = caches.key_blocks.len();
+    assert!(key_blocks > 1);
+    assert_eq!(caches.value_blocks.len(), 0);
+    file.prewarm(PrewarmBlocks::All, &caches.key_blocks, &caches.value_blocks)?;
+    assert_eq!(caches.key_blocks.len(), key_blocks);
+    assert_eq!(key_blocks + caches.value_blocks.len(), file.block_count()?);
+    Ok(())
+}
+
+#[test]
+fn warm_blocks() -> Result<()> {
+    let entries = (0..10000u32)
+        .map(|i| (i.to_be_bytes().to_vec(), vec![i as u8; 100]))
+        .collect::<Vec<_>>();
+    let file = build_file(1, &entries)?;
+    let root = &file.index_structure()?[0];
+    let key_block = root.children[0];
+    let value_block = (0..root.block_index)
+        .find(|block| !root.children.contains(block))
+        .unwrap();
+    let caches = Caches::new();
+    file.warm_blocks(
+        &[value_block, root.block_index, key_block],
+        &caches.key_blocks,
+        &caches.value_blocks,
+    )?;
+    assert_eq!(caches.key_blocks.len(), 2);
+    assert!(caches
+        .key_blocks
+        .get(&file.mapping().block_cache_key(root.block_index))
+        .is_some());
+    assert!(caches
+        .key_blocks
+        .get(&file.mapping().block_cache_key(key_block))
+        .is_some());
+    assert_eq!(caches.value_blocks.len(), 1);
+    assert!(caches
+        .value_blocks
+        .get(&file.mapping().block_cache_key(value_block))
+        .is_some());
+    assert!(file
+        .warm_blocks(
+            &[root.block_index + 1],
+            &caches.key_blocks,
+            &caches.value_blocks
+        )
+        .is_err());
+    Ok(())
+}
+
+#[test]
+fn warm_blocks_classifies_blocks_once() -> Result<()> {
+    let entries = numbered_entries(10000);
+    let reads = Arc::new(AtomicUsize::new(0));
+    let file = StaticSortedFile::from_backing(
+        1,
+        Box::new(CountingBacking {
+            bytes: Arc::from(
+                build_file(1, &entries)?
+                    .mapping()
+                    .backing
+                    .as_slice()
+                    .unwrap(),
+            ),
+            reads: reads.clone(),
+        }),
+    );
+    let key_block = file.index_structure()?[0].children[0];
+    let caches = Caches::new();
+    file.warm_blocks(&[key_block], &caches.key_blocks, &caches.value_blocks)?;
+    // The block is cached and the index is not walked again.
+    let reads_before = reads.load(Ordering::SeqCst);
+    file.warm_blocks(&[key_block], &caches.key_blocks, &caches.value_blocks)?;
+    assert_eq!(reads.load(Ordering::SeqCst), reads_before);
+    Ok(())
+}
+
+/// Polls `future` on the current thread until it completes.
+#[cfg(feature = "async")]
+fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    use std::{
+        pin::pin,
+        task::{Context, Poll, Wake, Waker},
+        thread::Thread,
+    };
+
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let mut future = pin!(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(std::thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(result) => return result,
+            Poll::Pending => std::thread::park(),
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn prewarm_async() -> Result<()> {
+    let entries = (0..10000u32)
+        .map(|i| (i.to_be_bytes().to_vec(), vec![i as u8; 100]))
+        .collect::<Vec<_>>();
+    let file = Arc::new(build_file(1, &entries)?);
+    let caches = Caches::new();
+    let key_block_cache = Arc::new(caches.key_blocks);
+    let value_block_cache = Arc::new(caches.value_blocks);
+    block_on(file.clone().prewarm_async(
+        PrewarmBlocks::All,
+        key_block_cache.clone(),
+        value_block_cache.clone(),
+    ))?;
+    assert_eq!(
+        key_block_cache.len() + value_block_cache.len(),
+        file.block_count()?
+    );
+    Ok(())
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn blocking_task_panic() {
+    use super::BlockingTask;
+
+    let panic = std::panic::catch_unwind(|| {
+        block_on(BlockingTask::<()>::spawn(|| panic!("prewarm failed")))
+    })
+    .unwrap_err();
+    assert_eq!(panic.downcast_ref::<&str>(), Some(&"prewarm failed"));
+}
+
+#[test]
+fn value_ref_beyond_block_count() -> Result<()> {
+    let entries = numbered_entries(1000);
+    let file = build_file(1, &entries)?;
+    let caches = Caches::new();
+    let header = &file.header()?;
+    let block = header.block_count + 5;
+    let is_corrupt = |err: anyhow::Error| {
+        matches!(
+            err.downcast_ref::<StaticSortedFileError>(),
+            Some(StaticSortedFileError::CorruptValueRef { block: b, block_count })
+                if *b == block && *block_count == header.block_count
+        )
+    };
+    for value_ref in [
+        ValueRef::Small {
+            block,
+            size: 10,
+            position: 0,
+        },
+        ValueRef::Medium { block },
+    ] {
+        let result = file.resolve_value(
+            value_ref,
+            header,
+            &caches.value_blocks,
+            &LookupOptions::default(),
+            None,
+        );
+        assert!(is_corrupt(result.err().unwrap()));
+    }
+    Ok(())
+}
+
+#[test]
+fn header_checksum() -> Result<()> {
+    let entries = numbered_entries(1000);
+    let write = |header_checksum: bool| -> Result<Vec<u8>> {
+        let file = build_file_with_options(
+            1,
+            &entries,
+            &StaticSortedFileBuilderOptions {
+                header_checksum,
+                ..Default::default()
+            },
+        )?;
+        Ok(file.mapping().backing.as_slice().unwrap().to_vec())
+    };
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("00000001.sst");
+
+    let bytes = write(true)?;
+    std::fs::write(&path, &bytes)?;
+    let file = StaticSortedFile::open_validated(1, path.clone())?;
+    assert_eq!(file.format_version()?, FORMAT_VERSION_2);
+
+    // Corrupt the last block offset, which is covered by the checksum.
+    let header = &file.header()?;
+    let mut corrupt = bytes.clone();
+    corrupt[header.blocks_start - 1] ^= 1;
+    drop(file);
+    std::fs::write(&path, &corrupt)?;
+    let err = StaticSortedFile::open_validated(1, path.clone())
+        .err()
+        .unwrap();
+    assert!(matches!(
+        err.downcast_ref::<StaticSortedFileError>(),
+        Some(StaticSortedFileError::HeaderChecksumMismatch { .. })
+    ));
+
+    // Files without a checksum are not checked.
+    let bytes = write(false)?;
+    std::fs::write(&path, &bytes)?;
+    let file = StaticSortedFile::open_validated(1, path)?;
+    assert_eq!(file.format_version()?, FORMAT_VERSION_1);
+    Ok(())
+}
+
+#[test]
+fn index_shape() -> Result<()> {
+    let entries = numbered_entries(100000);
+    let file = build_file(1, &entries)?;
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("00000001.sst");
+    std::fs::write(&path, file.mapping().backing.as_slice().unwrap())?;
+    let (file, shape) = StaticSortedFile::open_validated_with_shape(1, path)?;
+    let root_children = file.index_structure()?[0].children.len();
+    assert!(root_children > 1);
+    assert_eq!(
+        shape,
+        IndexShape {
+            depth: 1,
+            index_blocks: 1,
+            key_blocks: root_children,
+            max_fanout: root_children,
+        }
+    );
+    assert_eq!(build_file(1, &[])?.index_shape()?, IndexShape::default());
+    Ok(())
+}
+
+#[test]
+fn blob_references() -> Result<()> {
+    let entries = (0..10000u32)
+        .map(|i| {
+            let value = match i % 3 {
+                0 => CollectorEntryValue::Large { blob: i % 100 },
+                1 => CollectorEntryValue::Small {
+                    value: vec![i as u8; 10],
+                },
+                _ => CollectorEntryValue::Deleted,
+            };
+            (i.to_be_bytes().to_vec(), value)
+        })
+        .collect::<Vec<_>>();
+    let file = build_file_with_values(1, entries)?;
+    assert!(file.block_count()? > 2);
+    assert_eq!(file.blob_references()?, (0..100).collect::<Vec<_>>());
+
+    let file = build_file(1, &[(b"key".to_vec(), b"value".to_vec())])?;
+    assert_eq!(file.blob_references()?, Vec::<u32>::new());
+    Ok(())
+}
+
+#[test]
+fn overlaps() -> Result<()> {
+    let file = |sequence_number: u32, keys: std::ops::Range<u32>| {
+        let entries = keys.map(numbered_entry).collect::<Vec<_>>();
+        build_file(sequence_number, &entries)
+    };
+    let hashes = |keys: std::ops::Range<u32>| {
+        let mut hashes = keys.map(|i| hash_key(&i.to_be_bytes())).collect::<Vec<_>>();
+        hashes.sort_unstable();
+        hashes
+    };
+    let all = file(1, 0..1000)?;
+    let half = file(2, 0..500)?;
+    let empty = file(3, 0..0)?;
+    assert!(all.overlaps(&half)?);
+    assert!(half.overlaps(&all)?);
+    assert!(!all.overlaps(&empty)?);
+    assert!(!empty.overlaps(&empty)?);
+
+    // Files with adjacent hash ranges don't overlap, unless they share a boundary.
+    let hashes = hashes(0..1000);
+    let range = |min_hash: u64, max_hash: u64| StaticSortedFileRange {
+        family: 0,
+        min_hash,
+        max_hash,
+    };
+    assert!(!range(hashes[0], hashes[10]).overlaps(&range(hashes[11], hashes[20])));
+    assert!(range(hashes[0], hashes[10]).overlaps(&range(hashes[10], hashes[20])));
+    assert!(!range(0, 10).overlaps(&StaticSortedFileRange {
+        family: 1,
+        ..range(0, 10)
+    }));
+    Ok(())
+}
+
+#[test]
+fn open_with_options() -> Result<()> {
+    let entries = numbered_entries(1000);
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("00000001.sst");
+    std::fs::write(
+        &path,
+        build_file(1, &entries)?
+            .mapping()
+            .backing
+            .as_slice()
+            .unwrap(),
+    )?;
+    let file = StaticSortedFile::open_with_options(
+        1,
+        path,
+        &MmapFileOptions {
+            populate: true,
+            ..Default::default()
+        },
+    )?;
+    assert_eq!(file.entry_count()?, entries.len());
+    Ok(())
+}
+
+#[test]
+fn index_structure() -> Result<()> {
+    let entries = numbered_entries(100000);
+    let file = build_file(1, &entries)?;
+    let index_blocks = file.index_structure()?;
+    assert_eq!(index_blocks.len(), 1);
+    let root = &index_blocks[0];
+    assert_eq!(root.block_index as usize, file.block_count()? - 1);
+    assert!(root.children.len() > 1);
+    assert_eq!(root.separator_hashes.len(), root.children.len() - 1);
+    assert!(root.separator_hashes.is_sorted());
+    // Each separator is the smallest hash in the child that follows it.
+    let header = &file.header()?;
+    for (&child, &separator) in root.children[1..].iter().zip(&root.separator_hashes) {
+        let block = file.read_key_block(header, child)?;
+        let format = KeyBlockFormat::new(header, block[0]);
+        let layout = key_block_layout(&block, format)?;
+        let first = get_key_entry(
+            &block[layout.offsets],
+            &block[layout.entries],
+            layout.entry_count,
+            0,
+            format,
+        )?;
+        assert_eq!(first.hash, separator);
+    }
+    assert_eq!(build_file(1, &[])?.index_structure()?, Vec::new());
+    Ok(())
+}
+
+#[test]
+fn key_block_keys() -> Result<()> {
+    let entries = numbered_entries(100000);
+    let file = build_file(1, &entries)?;
+    let caches = Caches::new();
+    let root = &file.index_structure()?[0];
+    let mut keys = Vec::new();
+    for &child in root.children.iter() {
+        let block_keys = file.key_block_keys(child, &caches.key_blocks)?;
+        assert!(block_keys.iter().map(|key| hash_key(&&key[..])).is_sorted());
+        keys.extend(block_keys.iter().map(|key| key.to_vec()));
+    }
+    keys.sort_unstable();
+    let expected = entries.into_iter().map(|(key, _)| key).collect::<Vec<_>>();
+    assert_eq!(keys, expected);
+    assert!(file
+        .key_block_keys(root.block_index, &caches.key_blocks)
+        .is_err());
+    Ok(())
+}
+
+#[test]
+fn check_sorted() -> Result<()> {
+    let write = |entries: &[CollectorEntry<Vec<u8>>]| -> Result<StaticSortedFile> {
+        let mut buffer = Vec::new();
+        StaticSortedFileBuilder::new(0, entries, 0, 0)?.write_to(&mut buffer)?;
+        Ok(StaticSortedFile::open_from_bytes(1, Arc::from(buffer)))
+    };
+    let entry = |i: u32| CollectorEntry {
+        key: EntryKey {
+            hash: i as u64,
+            data: i.to_be_bytes().to_vec(),
+        },
+        value: CollectorEntryValue::Small {
+            value: vec![i as u8; 10],
+        },
+        write_sequence_number: None,
+    };
+    let mut entries = (0..100u32).map(entry).collect::<Vec<_>>();
+    write(&entries)?.check_sorted(DuplicateKeys::Error)?;
+
+    // Duplicate keys are sorted, but can be rejected.
+    let file = write(
+        &(0..100u32)
+            .map(|i| entry(if i == 51 { 50 } else { i }))
+            .collect::<Vec<_>>(),
+    )?;
+    file.check_sorted(DuplicateKeys::Yield)?;
+    let err = file.check_sorted(DuplicateKeys::Error).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<StaticSortedFileError>(),
+        Some(StaticSortedFileError::DuplicateKey { hash: 50 })
+    ));
+
+    // Equal hashes with keys in descending order, as written by a buggy writer.
+    entries[51].key.hash = 50;
+    entries.swap(50, 51);
+    let err = write(&entries)?
+        .check_sorted(DuplicateKeys::Yield)
+        .unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<StaticSortedFileError>(),
+        Some(StaticSortedFileError::UnsortedBlock {
+            entry_index: 51,
+            ..
+        })
+    ));
+    Ok(())
+}
+
+#[test]
+fn head_and_tail() -> Result<()> {
+    let entries = numbered_entries(100000);
+    let file = build_file(1, &entries)?;
+    let caches = Caches::new();
+    let keys = |entries: Vec<(ArcSlice<u8>, LookupResult)>| {
+        entries
+            .into_iter()
+            .map(|(key, value)| {
+                assert_eq!(value.found_value().unwrap()[..], [key[3]; 10]);
+                key.to_vec()
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let head = keys(file.head(5, &caches.key_blocks, &caches.value_blocks)?);
+    // Only the path to the leftmost key block is read.
+    assert!(caches.key_blocks.len() <= file.index_shape()?.depth + 1);
+    let all = file
+        .iter(&caches.key_blocks, &caches.value_blocks)?
+        .map(|entry| Ok(entry?.key.to_vec()))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(head, all[..5]);
+    let tail = keys(file.tail(5, &caches.key_blocks, &caches.value_blocks)?);
+    assert_eq!(tail, all[all.len() - 5..]);
+
+    // Larger counts cross key block boundaries.
+    let head = keys(file.head(10000, &caches.key_blocks, &caches.value_blocks)?);
+    assert_eq!(head, all[..10000]);
+    let tail = keys(file.tail(10000, &caches.key_blocks, &caches.value_blocks)?);
+    assert_eq!(tail, all[all.len() - 10000..]);
+
+    assert_eq!(
+        file.head(200000, &caches.key_blocks, &caches.value_blocks)?
+            .len(),
+        entries.len()
+    );
+    assert!(file
+        .tail(0, &caches.key_blocks, &caches.value_blocks)?
+        .is_empty());
+    Ok(())
+}
+
+#[test]
+fn decompression_length_mismatch() -> Result<()> {
+    let data = (0..100u8).collect::<Vec<_>>();
+    let mut compressed = Vec::new();
+    lzzzz::lz4::compress_to_vec(&data, &mut compressed, lzzzz::lz4::ACC_LEVEL_DEFAULT)?;
+
+    let mut dst = vec![0; 100];
+    decompress_block(0, &compressed, &mut dst, 100, &[])?;
+    assert_eq!(dst, data);
+
+    // A stored length that is too large would leave a zeroed tail.
+    let mut dst = vec![0; 200];
+    let err = decompress_block(0, &compressed, &mut dst, 200, &[])
+        .err()
+        .unwrap();
+    assert!(matches!(
+        err.downcast_ref::<StaticSortedFileError>(),
+        Some(StaticSortedFileError::DecompressionLengthMismatch {
+            expected: 200,
+            actual: 100
+        })
+    ));
+    Ok(())
+}
+
+#[test]
+fn filter_contains_many() -> Result<()> {
+    let entries = numbered_entries(1000);
+    let file = build_file(1, &entries)?;
+    let caches = Caches::new();
+    let present = entries
+        .iter()
+        .map(|(key, _)| hash_key(key))
+        .collect::<Vec<_>>();
+    assert!(file
+        .filter_contains_many(0, &present, &caches.aqmf)?
+        .into_iter()
+        .all(|contains| contains));
+    assert!(file
+        .filter_contains_many(1, &present, &caches.aqmf)?
+        .into_iter()
+        .all(|contains| !contains));
+
+    // Only a few keys that are not in the file pass the filter.
+    let absent = (1000..11000u32)
+        .map(|i| hash_key(&i.to_be_bytes()))
+        .collect::<Vec<_>>();
+    let results = file.filter_contains_many(0, &absent, &caches.aqmf)?;
+    assert_eq!(results.len(), absent.len());
+    assert!(results.iter().filter(|&&contains| contains).count() < absent.len() / 20);
+    Ok(())
+}
+
+#[test]
+fn read_value_at() -> Result<()> {
+    let entries = numbered_entries(1000);
+    let file = build_file(1, &entries)?;
+    let caches = Caches::new();
+    let dump = file.dump(false, &caches.key_blocks, &caches.value_blocks)?;
+    for (key, entry) in dump {
+        let DumpEntry::Small {
+            block,
+            position,
+            size,
+            ..
+        } = entry
+        else {
+            panic!("Expected a small value");
+        };
+        let value = file.read_value_at(block, position, size, &caches.value_blocks)?;
+        assert_eq!(&*value, &[key[3]; 10]);
+    }
+    assert!(file
+        .read_value_at(0, u32::MAX - 5, 10, &caches.value_blocks)
+        .is_err());
+    Ok(())
+}
+
+#[test]
+fn decompression_error_context() -> Result<()> {
+    let data = (0..100u8).collect::<Vec<_>>();
+    let mut compressed = Vec::new();
+    lzzzz::lz4::compress_to_vec(&data, &mut compressed, lzzzz::lz4::ACC_LEVEL_DEFAULT)?;
+    compressed.truncate(compressed.len() / 2);
+
+    let mut dst = vec![0; 100];
+    let err = decompress_block(7, &compressed, &mut dst, 100, &[])
+        .err()
+        .unwrap();
+    let Some(StaticSortedFileError::Decompression {
+        block_index,
+        compressed_length,
+        uncompressed_length,
+        with_dictionary,
+        ..
+    }) = err.downcast_ref::<StaticSortedFileError>()
+    else {
+        panic!("Expected a decompression error, got {err:?}");
+    };
+    assert_eq!(*block_index, 7);
+    assert_eq!(*compressed_length, compressed.len());
+    assert_eq!(*uncompressed_length, 100);
+    assert!(!with_dictionary);
+    assert!(err.to_string().contains("block 7"));
+    Ok(())
+}
+
+#[test]
+fn iter_seek() -> Result<()> {
+    let entries = numbered_entries(100000);
+    let file = build_file(1, &entries)?;
+    let caches = Caches::new();
+    let mut hashes = entries
+        .iter()
+        .map(|(key, _)| hash_key(key))
+        .collect::<Vec<_>>();
+    hashes.sort_unstable();
+
+    let mut iter = file.iter(&caches.key_blocks, &caches.value_blocks)?;
+    let expect_at = |iter: &mut StaticSortedFileIter<'_, _>, position: usize| {
+        let entry = iter.next().transpose()?;
+        assert_eq!(entry.map(|entry| entry.hash), hashes.get(position).copied());
+        anyhow::Ok(())
+    };
+    // Forward within the current key block, forward across blocks, and backward.
+    for position in [10, 11, 20, 50000, 50001, 99999, 5, 0, 70000] {
+        iter.seek(hashes[position])?;
+        expect_at(&mut iter, position)?;
+    }
+    // A hash between two entries seeks to the later one.
+    iter.seek(hashes[300] + 1)?;
+    expect_at(&mut iter, 301)?;
+    iter.seek(hashes[99999] + 1)?;
+    expect_at(&mut iter, 100000)?;
+    iter.seek(0)?;
+    expect_at(&mut iter, 0)?;
+    expect_at(&mut iter, 1)?;
+
+    // A separator hash is the first hash of its child, so seeking to it enters that child.
+    for index_block in file.index_structure()? {
+        for separator in index_block.separator_hashes {
+            let position = hashes.partition_point(|&hash| hash < separator);
+            let mut iter = file.iter(&caches.key_blocks, &caches.value_blocks)?;
+            iter.seek(separator)?;
+            expect_at(&mut iter, position)?;
+            iter.seek(hashes[position - 1])?;
+            expect_at(&mut iter, position - 1)?;
+        }
+    }
+
+    let mut iter = file
+        .iter(&caches.key_blocks, &caches.value_blocks)?
+        .with_duplicate_keys(DuplicateKeys::KeepLast);
+    iter.next().transpose()?;
+    iter.seek(hashes[1234])?;
+    expect_at(&mut iter, 1234)?;
+    Ok(())
+}
+
+#[cfg(feature = "leak_check")]
+#[test]
+fn outstanding_blocks() -> Result<()> {
+    let entries = numbered_entries(1000);
+    let file = build_file(1, &entries)?;
+    let caches = Caches::new();
+    let (key, _) = &entries[500];
+    let value = caches.lookup_value(&file, key)?;
+    assert!(!file.outstanding_blocks().is_empty());
+    drop(caches.key_blocks);
+    drop(caches.value_blocks);
+    // Only the value block is still referenced by the value.
+    assert_eq!(file.outstanding_blocks().len(), 1);
+    drop(value);
+    assert!(file.outstanding_blocks().is_empty());
+    Ok(())
+}
+
+#[derive(Debug)]
+struct ExactFilterBuilder;
+
+/// A filter without false positives that stores all key hashes.
+struct ExactFilter(Vec<u64>);
+
+impl MembershipFilterBuilder for ExactFilterBuilder {
+    fn filter_type(&self) -> u8 {
+        7
+    }
+
+    fn build(&self, key_hashes: &[u64]) -> Result<Vec<u8>> {
+        Ok(key_hashes
+            .iter()
+            .flat_map(|hash| hash.to_be_bytes())
+            .collect())
+    }
+}
+
+impl MembershipFilter for ExactFilter {
+    fn contains(&self, key_hash: u64) -> bool {
+        self.0.binary_search(&key_hash).is_ok()
+    }
+
+    fn weight(&self) -> u64 {
+        self.0.len() as u64 * 8
+    }
+}
+
+fn decode_exact_filter(bytes: &[u8]) -> Result<Arc<dyn MembershipFilter>> {
+    Ok(Arc::new(ExactFilter(
+        bytes
+            .chunks_exact(8)
+            .map(|mut hash| hash.read_u64::<BE>())
+            .collect::<std::io::Result<_>>()?,
+    )))
+}
+
+#[test]
+fn custom_filter() -> Result<()> {
+    let entries = numbered_entries(1000);
+    let file = build_file_with_options(
+        1,
+        &entries,
+        &StaticSortedFileBuilderOptions {
+            filter_builder: Some(Arc::new(ExactFilterBuilder)),
+            ..Default::default()
+        },
+    )?
+    .with_filter_decoder(7, decode_exact_filter);
+    let caches = Caches::new();
+    for (key, value) in entries.iter() {
+        let found = caches.lookup_value(&file, key)?;
+        assert_eq!(&*found, &value[..]);
+    }
+    // The exact filter has no false positives.
+    let absent = (1000..2000u32)
+        .map(|i| hash_key(&i.to_be_bytes()))
+        .collect::<Vec<_>>();
+    assert!(file
+        .filter_contains_many(0, &absent, &caches.aqmf)?
+        .into_iter()
+        .all(|contains| !contains));
+    assert_eq!(file.memory_estimate()?.filter, 8000);
+
+    // Files with an unknown filter type can't be used for lookups.
+    let file =
+        StaticSortedFile::open_from_bytes(2, Arc::from(file.mapping().backing.as_slice().unwrap()));
+    let (key, _) = &entries[0];
+    let err = file
+        .lookup(
+            0,
+            hash_key(key),
+            key,
+            &caches.aqmf,
+            &caches.key_blocks,
+            &caches.value_blocks,
+        )
+        .err()
+        .unwrap();
+    assert!(matches!(
+        err.downcast_ref::<StaticSortedFileError>(),
+        Some(StaticSortedFileError::UnsupportedFilterType(7))
+    ));
+    Ok(())
+}
+
+#[test]
+fn concurrent_filter_load() -> Result<()> {
+    static DECODE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+    /// Decodes slowly, so all threads ask for the filter while it's loading.
+    fn counting_decoder(bytes: &[u8]) -> Result<Arc<dyn MembershipFilter>> {
+        DECODE_COUNT.fetch_add(1, Ordering::SeqCst);
+        std::thread::sleep(Duration::from_millis(100));
+        decode_exact_filter(bytes)
+    }
+
+    let file = build_file_with_options(
+        1,
+        &numbered_entries(1000),
+        &StaticSortedFileBuilderOptions {
+            filter_builder: Some(Arc::new(ExactFilterBuilder)),
+            ..Default::default()
+        },
+    )?
+    .with_filter_decoder(7, counting_decoder);
+    let caches = Caches::new();
+    // Only the thread that holds the cache guard decodes the filter. The others wait for it.
+    std::thread::scope(|scope| {
+        let threads = (0..16u32)
+            .map(|i| {
+                let (file, caches) = (&file, &caches);
+                scope.spawn(move || caches.lookup(file, &(i * 50).to_be_bytes()))
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            assert!(matches!(
+                thread.join().unwrap(),
+                Ok(LookupResult::Slice { .. })
+            ));
+        }
+    });
+    assert_eq!(DECODE_COUNT.load(Ordering::SeqCst), 1);
+    Ok(())
+}
+
+#[test]
+fn value_block_reader() -> Result<()> {
+    let medium = (0..500_000u32)
+        .flat_map(|i| format!("value {} ", i % 7919).into_bytes())
+        .collect::<Vec<_>>();
+    let mut entries = (0..100u32)
+        .map(|i| {
+            (
+                i.to_be_bytes().to_vec(),
+                CollectorEntryValue::Small {
+                    value: format!("value {i}").into_bytes(),
+                },
+            )
+        })
+        .collect::<Vec<_>>();
+    entries.push((
+        b"medium".to_vec(),
+        CollectorEntryValue::Medium {
+            value: medium.clone(),
+        },
+    ));
+    let file = build_file_with_values(1, entries)?;
+    let caches = Caches::new();
+    let block = file
+        .dump(false, &caches.key_blocks, &caches.value_blocks)?
+        .into_iter()
+        .find_map(|(key, entry)| match entry {
+            DumpEntry::Medium { block } if &*key == b"medium" => Some(block),
+            _ => None,
+        })
+        .unwrap();
+
+    let mut decompressed = Vec::new();
+    std::io::copy(&mut file.value_block_reader(block)?, &mut decompressed)?;
+    assert_eq!(decompressed, medium);
+    assert_eq!(caches.value_blocks.len(), 0);
+
+    // Small reads return the same bytes.
+    let mut reader = file.value_block_reader(block)?;
+    let mut buf = [0; 777];
+    let mut decompressed = Vec::new();
+    loop {
+        let len = std::io::Read::read(&mut reader, &mut buf)?;
+        if len == 0 {
+            break;
+        }
+        decompressed.extend_from_slice(&buf[..len]);
+    }
+    assert_eq!(decompressed, medium);
+
+    assert!(file.value_block_reader(u16::MAX).is_err());
+    Ok(())
+}
+
+#[test]
+fn block_reader_corrupt_input() -> Result<()> {
+    use std::io::Read;
+
+    use rand::{Rng, SeedableRng};
+
+    use crate::block_reader::BlockReader;
+
+    let decompress = |input: &[u8], uncompressed_length: usize, dictionary: &[u8]| {
+        let mut out = Vec::new();
+        BlockReader::new(0, input, 0, uncompressed_length, dictionary)
+            .read_to_end(&mut out)
+            .map(|_| out)
+    };
+
+    let data = (0..200_000u32)
+        .flat_map(|i| format!("value {} ", i % 7919).into_bytes())
+        .collect::<Vec<_>>();
+    let mut compressed = Vec::new();
+    lzzzz::lz4::compress_to_vec(&data, &mut compressed, lzzzz::lz4::ACC_LEVEL_DEFAULT)?;
+    assert_eq!(decompress(&compressed, data.len(), &[])?, data);
+    for len in [0, 1, 2, compressed.len() / 2, compressed.len() - 1] {
+        assert!(decompress(&compressed[..len], data.len(), &[]).is_err());
+    }
+    assert!(decompress(&compressed, data.len() - 1, &[]).is_err());
+    assert!(decompress(&compressed, data.len() + 1, &[]).is_err());
+
+    // One literal "a" followed by a match of 4 bytes at the given offset, and five trailing
+    // literals.
+    let sequence = |offset: u16| {
+        let mut input = vec![0x10, b'a'];
+        input.extend_from_slice(&offset.to_le_bytes());
+        input.extend_from_slice(&[0x50, b'b', b'c', b'd', b'e', b'f']);
+        input
+    };
+    assert_eq!(decompress(&sequence(1), 10, &[])?, b"aaaaabcdef");
+    assert!(decompress(&sequence(0), 10, &[]).is_err());
+    assert!(decompress(&sequence(2), 10, &[]).is_err());
+    assert_eq!(decompress(&sequence(3), 10, b"xy")?, b"axyaxbcdef");
+    assert!(decompress(&sequence(4), 10, b"xy").is_err());
+    assert!(decompress(&sequence(u16::MAX), 10, b"xy").is_err());
+
+    // Literal and match lengths that continue past the end of the input or the block.
+    assert!(decompress(&[0xf0, 255, 255, 255], 1000, &[]).is_err());
+    assert!(decompress(&[0xf0, 255, 255, 255, 0, b'a'], 1000, &[]).is_err());
+    let mut long_match = vec![0x1f, b'a', 1, 0];
+    long_match.extend_from_slice(&[255; 100]);
+    long_match.extend_from_slice(&[0, 0x00]);
+    assert!(decompress(&long_match, 100, &[]).is_err());
+
+    let mut rnd = rand::rngs::SmallRng::from_seed([0; 32]);
+    for _ in 0..10000 {
+        let mut input = compressed[..rnd.gen_range(0..200)].to_vec();
+        for _ in 0..rnd.gen_range(1..8) {
+            let index = rnd.gen_range(0..input.len().max(1));
+            if let Some(byte) = input.get_mut(index) {
+                *byte = rnd.gen();
+            }
+        }
+        let _ = decompress(&input, rnd.gen_range(0..100_000), b"dictionary");
+    }
+    Ok(())
+}
+
+#[test]
+fn file_len() -> Result<()> {
+    let file = build_file(1, &[(b"key".to_vec(), b"value".to_vec())])?;
+    let len = file.mapping().backing.as_slice().unwrap().len();
+    assert_eq!(file.file_len(), len);
+    assert!(file.mapping().in_bounds(&(0..len)));
+    assert!(!file.mapping().in_bounds(&(0..len + 1)));
+    assert!(file.mapping().read(len - 4..len + 4).is_err());
+    Ok(())
+}
+
+#[test]
+fn remap_during_lookups() -> Result<()> {
+    let versions = [0u8, 0xff].map(|xor| {
+        (0..1000u32)
+            .map(|i| (i.to_be_bytes().to_vec(), vec![i as u8 ^ xor; 10]))
+            .collect::<Vec<_>>()
+    });
+    let bytes = versions
+        .iter()
+        .map(|entries| {
+            anyhow::Ok(
+                build_file(1, entries)?
+                    .mapping()
+                    .backing
+                    .as_slice()
+                    .unwrap()
+                    .to_vec(),
+            )
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("00000001.sst");
+    let tmp_path = dir.path().join("00000001.sst.tmp");
+    std::fs::write(&path, &bytes[0])?;
+    let file = Arc::new(StaticSortedFile::open(1, path.clone())?);
+    let caches = Caches::new();
+    let done = AtomicBool::new(false);
+    std::thread::scope(|scope| {
+        let reader = scope.spawn(|| {
+            let mut lookups = 0;
+            while !done.load(Ordering::SeqCst) || lookups < 1000 {
+                let (key, old) = &versions[0][lookups % 1000];
+                let value = caches.lookup_value(&file, key)?;
+                assert!(*value == **old || *value == *versions[1][lookups % 1000].1);
+                lookups += 1;
+            }
+            anyhow::Ok(())
+        });
+        // An iterator that starts before the remaps reads the old version until it's done.
+        let mut iter = file.iter(&caches.key_blocks, &caches.value_blocks)?;
+        let mut count = usize::from(iter.next().transpose()?.is_some());
+        for i in 1..=20 {
+            std::fs::write(&tmp_path, &bytes[i % 2])?;
+            std::fs::rename(&tmp_path, &path)?;
+            file.remap(path.clone())?;
+        }
+        done.store(true, Ordering::SeqCst);
+        reader.join().unwrap()?;
+        for entry in iter {
+            let entry = entry?;
+            let LookupValue::Slice { value } = entry.value else {
+                panic!("Expected a small value");
+            };
+            let i = u32::from_be_bytes(entry.key[..].try_into()?);
+            assert_eq!(&*value, &versions[0][i as usize].1[..]);
+            count += 1;
+        }
+        assert_eq!(count, 1000);
+        anyhow::Ok(())
+    })?;
+    // The last remap mapped the first version again.
+    for (key, value) in versions[0].iter().step_by(37) {
+        assert_eq!(&*caches.lookup_value(&file, key)?, &value[..]);
+    }
+    Ok(())
+}
+
+#[test]
+fn remap_keeps_unchanged_header() -> Result<()> {
+    let entries = numbered_entries(100);
+    let bytes = build_file(1, &entries)?
+        .mapping()
+        .backing
+        .as_slice()
+        .unwrap()
+        .to_vec();
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("00000001.sst");
+    std::fs::write(&path, &bytes)?;
+    let file = StaticSortedFile::open(1, path.clone())?;
+    assert_eq!(file.entry_count()?, 100);
+
+    // An identical new version keeps the parsed header.
+    let tmp_path = dir.path().join("00000001.sst.tmp");
+    std::fs::write(&tmp_path, &bytes)?;
+    std::fs::rename(&tmp_path, &path)?;
+    file.remap(path.clone())?;
+    assert!(file.mapping().header.get().is_some());
+    assert_eq!(file.entry_count()?, 100);
+
+    // A different version is parsed again.
+    std::fs::write(
+        &tmp_path,
+        build_file(1, &entries[..50])?
+            .mapping()
+            .backing
+            .as_slice()
+            .unwrap(),
+    )?;
+    std::fs::rename(&tmp_path, &path)?;
+    file.remap(path)?;
+    assert!(file.mapping().header.get().is_none());
+    assert_eq!(file.entry_count()?, 50);
+    Ok(())
+}
+
+#[test]
+fn index_separator_boundaries() -> Result<()> {
+    let entries = numbered_entries(10000);
+    let file = build_file(1, &entries)?;
+    let caches = Caches::new();
+    let [root] = &file.index_structure()?[..] else {
+        panic!("Expected a single index block");
+    };
+    assert!(root.children.len() > 2);
+    let root_block = file.read_key_block_decompressed(root.block_index, &caches.key_blocks)?;
+    let index = &root_block[1..];
+    for (i, &separator) in root.separator_hashes.iter().enumerate() {
+        // A hash equal to a separator is in the child that starts with it.
+        assert_eq!(
+            file.lookup_index_block(index, separator)?,
+            root.children[i + 1]
+        );
+        assert_eq!(
+            file.lookup_index_block(index, separator - 1)?,
+            root.children[i]
+        );
+    }
+    assert_eq!(file.lookup_index_block(index, 0)?, root.children[0]);
+    assert_eq!(
+        file.lookup_index_block(index, u64::MAX)?,
+        *root.children.last().unwrap()
+    );
+    assert!(file
+        .lookup_index_block(&index[..index.len() - 1], 0)
+        .is_err());
+
+    for (key, _) in entries.iter() {
+        assert!(file.can_contain(0, hash_key(key), &caches.aqmf)?);
+        assert!(!file.can_contain(1, hash_key(key), &caches.aqmf)?);
+    }
+    let false_positives = (10000..20000u32)
+        .filter(|i| {
+            file.can_contain(0, hash_key(&i.to_be_bytes()), &caches.aqmf)
+                .unwrap()
+        })
+        .count();
+    assert!(false_positives < 500);
+    Ok(())
+}
+
+#[test]
+fn disable_caches() -> Result<()> {
+    let entries = numbered_entries(1000);
+    let file = build_file(1, &entries)?;
+    let caches = Caches::new();
+    let lookup = |key: &[u8], options: &LookupOptions| {
+        let (result, trace) = file.lookup_traced(
+            0,
+            hash_key(&key),
+            &key,
+            &caches.aqmf,
+            &caches.key_blocks,
+            &caches.value_blocks,
+            options,
+        )?;
+        assert!(matches!(result, LookupResult::Slice { .. }));
+        anyhow::Ok(trace)
+    };
+    let (key, _) = &entries[0];
+    lookup(key, &LookupOptions::default())?;
+    assert_eq!(
+        lookup(key, &LookupOptions::default())?.bytes_decompressed,
+        0
+    );
+    let aqmf_count = caches.aqmf.len();
+    let key_block_count = caches.key_blocks.len();
+    let value_block_count = caches.value_blocks.len();
+
+    // Cached blocks are ignored and nothing new is cached.
+    let options = LookupOptions {
+        disable_caches: true,
+        ..Default::default()
+    };
+    for (key, _) in entries.iter() {
+        let trace = lookup(key, &options)?;
+        assert_eq!(trace.blocks_read, 3);
+        assert!(trace.bytes_decompressed > 0);
+    }
+    assert_eq!(caches.aqmf.len(), aqmf_count);
+    assert_eq!(caches.key_blocks.len(), key_block_count);
+    assert_eq!(caches.value_blocks.len(), value_block_count);
+    Ok(())
+}
+
+#[test]
+fn lookup_owned() -> Result<()> {
+    let medium = (0..100000u32).map(|i| i as u8).collect::<Vec<_>>();
+    let file = build_file_with_values(
+        1,
+        vec![
+            (
+                b"small".to_vec(),
+                CollectorEntryValue::Small {
+                    value: vec![1; 100],
+                },
+            ),
+            (
+                b"medium".to_vec(),
+                CollectorEntryValue::Medium {
+                    value: medium.clone(),
+                },
+            ),
+            (b"deleted".to_vec(), CollectorEntryValue::Deleted),
+            (b"blob".to_vec(), CollectorEntryValue::Large { blob: 42 }),
+        ],
+    )?;
+    let caches = Caches::new();
+    let lookup_owned = |key: &[u8]| {
+        file.lookup_owned(
+            0,
+            hash_key(&key),
+            &key,
+            &caches.aqmf,
+            &caches.key_blocks,
+            &caches.value_blocks,
+        )
+    };
+    let mut small = lookup_owned(b"small")?.unwrap();
+    small[0] = 2;
+    assert_eq!(lookup_owned(b"small")?.unwrap(), vec![1; 100]);
+
+    // Uncached medium values are decompressed without going through the cache.
+    let cached_blocks = caches.value_blocks.len();
+    assert_eq!(lookup_owned(b"medium")?.unwrap(), medium);
+    assert_eq!(caches.value_blocks.len(), cached_blocks);
+    caches.lookup(&file, b"medium")?;
+    assert_eq!(lookup_owned(b"medium")?.unwrap(), medium);
+
+    assert_eq!(lookup_owned(b"deleted")?, None);
+    assert_eq!(lookup_owned(b"blob")?, None);
+    assert_eq!(lookup_owned(b"missing")?, None);
+    Ok(())
+}
+
+#[test]
+fn block_ratios() -> Result<()> {
+    let entries = numbered_entries(1000);
+    let file = build_file(1, &entries)?;
+    let ratios = file.block_ratios()?;
+    assert_eq!(ratios.len(), file.block_count()?);
+    for (block_index, &(compressed, uncompressed)) in ratios.iter().enumerate() {
+        assert!(compressed > 0);
+        assert_eq!(
+            uncompressed,
+            file.block_uncompressed_len(block_index as u16)?
+        );
+    }
+    // The blocks are stored at the end of the file.
+    let blocks_size = ratios
+        .iter()
+        .map(|(compressed, _)| compressed + 4)
+        .sum::<usize>();
+    assert_eq!(blocks_size, file.file_len() - file.header()?.blocks_start);
+    Ok(())
+}
+
+/// Compacts `inputs` with at most `max_entries_per_file` entries per output file and reads the
+/// outputs back.
+fn compact_into_files(
+    inputs: &[&StaticSortedFile],
+    gc_sequence_number: u32,
+    options: &StaticSortedFileBuilderOptions,
+    max_entries_per_file: usize,
+) -> Result<Vec<StaticSortedFile>> {
+    let dir = tempfile::tempdir()?;
+    let caches = Caches::new();
+    let mut paths = Vec::new();
+    compact_with_limits(
+        inputs,
+        gc_sequence_number,
+        options,
+        || {
+            let path = dir.path().join(format!("{:08}.sst", paths.len()));
+            let file = File::create(&path)?;
+            paths.push(path);
+            Ok(file)
+        },
+        &caches.key_blocks,
+        &caches.value_blocks,
+        max_entries_per_file,
+        DATA_THRESHOLD_PER_COMPACTED_FILE,
+    )?;
+    paths
+        .iter()
+        .enumerate()
+        .map(|(i, path)| {
+            Ok(StaticSortedFile::open_from_bytes(
+                100 + i as u32,
+                Arc::from(std::fs::read(path)?),
+            ))
+        })
+        .collect()
+}
+
+#[test]
+fn compact_files() -> Result<()> {
+    let small = |value: u8| CollectorEntryValue::Small { value: vec![value] };
+    let medium = vec![7; 100000];
+    let old = build_file_with_values(
+        1,
+        vec![
+            (vec![1], small(1)),
+            (vec![2], small(1)),
+            (vec![3], small(1)),
+            (vec![5], small(1)),
+            (
+                vec![6],
+                CollectorEntryValue::Medium {
+                    value: medium.clone(),
+                },
+            ),
+        ],
+    )?;
+    let middle = build_file_with_values(
+        2,
+        vec![(vec![2], small(2)), (vec![3], CollectorEntryValue::Deleted)],
+    )?;
+    let new = build_file_with_values(
+        3,
+        vec![(vec![4], small(3)), (vec![5], CollectorEntryValue::Deleted)],
+    )?;
+    let caches = Caches::new();
+    // Only the tombstone of the newest file is kept.
+    let mut files = compact_into_files(
+        &[&old, &middle, &new],
+        2,
+        &StaticSortedFileBuilderOptions::default(),
+        usize::MAX,
+    )?;
+    assert_eq!(files.len(), 1);
+    let file = files.pop().unwrap();
+    let mut result = file
+        .iter(&caches.key_blocks, &caches.value_blocks)?
+        .map(|entry| {
+            let entry = entry?;
+            Ok((entry.key.to_vec(), entry.value))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    result.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+    let keys = result.iter().map(|(key, _)| key[0]).collect::<Vec<_>>();
+    assert_eq!(keys, vec![1, 2, 4, 5, 6]);
+    for (key, value) in result {
+        match (key[0], value) {
+            (5, LookupValue::Deleted) => {}
+            (6, LookupValue::Slice { value }) => assert_eq!(&*value, &medium[..]),
+            (key, LookupValue::Slice { value }) => {
+                assert_eq!(value[0], [0, 1, 2, 1, 3][key as usize])
+            }
+            (key, _) => panic!("Unexpected value for key {key}"),
+        }
+    }
+    for key in [1u8, 2, 4, 6] {
+        assert!(file.can_contain(0, hash_key(&[key]), &caches.aqmf)?);
+    }
+    Ok(())
+}
+
+#[test]
+fn compact_into_multiple_files() -> Result<()> {
+    let old = build_file(1, &numbered_entries(1000))?;
+    let new = build_file(
+        2,
+        &(500..1500u32)
+            .map(|i| (i.to_be_bytes().to_vec(), vec![42; 10]))
+            .collect::<Vec<_>>(),
+    )?;
+    let files = compact_into_files(
+        &[&old, &new],
+        0,
+        &StaticSortedFileBuilderOptions::default(),
+        300,
+    )?;
+    assert_eq!(files.len(), 5);
+    let caches = Caches::new();
+    let mut keys = Vec::new();
+    for (i, file) in files.iter().enumerate() {
+        // The files cover consecutive hash ranges.
+        if i > 0 {
+            assert!(files[i - 1].range()?.max_hash < file.range()?.min_hash);
+        }
+        let entries = file
+            .iter(&caches.key_blocks, &caches.value_blocks)?
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(entries.len(), 300);
+        for entry in entries {
+            let key = u32::from_be_bytes(entry.key[..].try_into()?);
+            let LookupValue::Slice { value } = entry.value else {
+                panic!("Unexpected value for key {key}");
+            };
+            assert_eq!(value[0], if key >= 500 { 42 } else { key as u8 });
+            keys.push(key);
+        }
+    }
+    keys.sort_unstable();
+    assert_eq!(keys, (0..1500).collect::<Vec<_>>());
+    Ok(())
+}
+
+#[test]
+fn compact_keeps_write_sequence_numbers() -> Result<()> {
+    let entries = sequenced_entries(1000);
+    let mut buffer = Vec::new();
+    StaticSortedFileBuilder::new(0, &entries, 4000, 10000)?.write_to(&mut buffer)?;
+    let file = StaticSortedFile::open_from_bytes(1, Arc::from(buffer));
+    let options = StaticSortedFileBuilderOptions {
+        little_endian_offsets: true,
+        shared_key_prefixes: true,
+        ..Default::default()
+    };
+    let compacted = compact_into_files(&[&file], 0, &options, usize::MAX)?;
+    assert_eq!(compacted.len(), 1);
+    let header = compacted[0].header()?;
+    assert!(header.little_endian_offsets && header.shared_key_prefixes);
+
+    let caches = Caches::new();
+    for file in [&file, &compacted[0]] {
+        for i in 0..1000u32 {
+            let key = i.to_be_bytes();
+            let result = file.lookup_as_of(
+                0,
+                hash_key(&key),
+                &key,
+                500,
+                &caches.aqmf,
+                &caches.key_blocks,
+                &caches.value_blocks,
+            )?;
+            assert_eq!(matches!(result, LookupResult::Slice { .. }), i <= 500);
+        }
+    }
+    Ok(())
+}
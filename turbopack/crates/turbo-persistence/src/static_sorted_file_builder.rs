@@ -3,15 +3,23 @@ use std::{
     fs::File,
     io::{self, BufWriter, Write},
     path::Path,
+    sync::Arc,
 };
 
 use anyhow::{Context, Result};
-use byteorder::{ByteOrder, WriteBytesExt, BE};
+use byteorder::{ByteOrder, WriteBytesExt, BE, LE};
 use lzzzz::lz4::{max_compressed_size, ACC_LEVEL_DEFAULT};
 
-use crate::static_sorted_file::{
-    BLOCK_TYPE_INDEX, BLOCK_TYPE_KEY, KEY_BLOCK_ENTRY_TYPE_BLOB, KEY_BLOCK_ENTRY_TYPE_DELETED,
-    KEY_BLOCK_ENTRY_TYPE_MEDIUM, KEY_BLOCK_ENTRY_TYPE_SMALL,
+use crate::{
+    membership_filter::MembershipFilterBuilder,
+    static_sorted_file::{
+        header_checksum, SharedDictionaries, BLOCK_TYPE_INDEX, BLOCK_TYPE_KEY,
+        BLOCK_TYPE_KEY_WITH_SEQUENCE_NUMBERS, FORMAT_VERSION_1, FORMAT_VERSION_2,
+        HEADER_FLAG_FILTER_TYPE, HEADER_FLAG_HEADER_CHECKSUM, HEADER_FLAG_LITTLE_ENDIAN_OFFSETS,
+        HEADER_FLAG_SHARED_DICTIONARIES, HEADER_FLAG_SHARED_KEY_PREFIXES,
+        KEY_BLOCK_ENTRY_TYPE_BLOB, KEY_BLOCK_ENTRY_TYPE_DELETED, KEY_BLOCK_ENTRY_TYPE_MEDIUM,
+        KEY_BLOCK_ENTRY_TYPE_SMALL, MAGIC,
+    },
 };
 
 /// The maximum number of entries that should go into a single key block
@@ -56,6 +64,11 @@ pub trait Entry {
 
     /// Returns the value
     fn value(&self) -> EntryValue<'_>;
+
+    /// Returns the sequence number of the write that created this entry, if it's tracked
+    fn write_sequence_number(&self) -> Option<u32> {
+        None
+    }
 }
 
 /// Reference to a value
@@ -71,6 +84,29 @@ pub enum EntryValue<'l> {
     Deleted,
 }
 
+/// Options that change the encoding of a SST file.
+#[derive(Debug, Clone, Default)]
+pub struct StaticSortedFileBuilderOptions {
+    /// Store block offsets and key block entry headers in little endian byte order. This avoids
+    /// byte swapping on little endian machines, but files are written in a newer format version.
+    pub little_endian_offsets: bool,
+    /// Compress with these shared dictionaries instead of computing dictionaries for this file.
+    /// The file only stores the id of the dictionaries.
+    pub shared_dictionaries: Option<Arc<SharedDictionaries>>,
+    /// Store the common prefix of the keys in a key block once and only the remaining suffix per
+    /// entry. This saves space when keys share long prefixes.
+    pub shared_key_prefixes: bool,
+    /// Don't write an AQMF filter. Lookups search the index directly. This is useful for tiny
+    /// files where the filter costs more than it saves.
+    pub omit_aqmf: bool,
+    /// Store a checksum of the header, the AQMF filter, the compression dictionaries and the block
+    /// offsets. `StaticSortedFile::open_validated` verifies it.
+    pub header_checksum: bool,
+    /// Store a custom filter instead of the AQMF filter. Readers need a decoder for its filter
+    /// type. Ignored when `omit_aqmf` is set.
+    pub filter_builder: Option<Arc<dyn MembershipFilterBuilder>>,
+}
+
 #[derive(Debug, Default)]
 pub struct StaticSortedFileBuilder {
     family: u32,
@@ -80,6 +116,12 @@ pub struct StaticSortedFileBuilder {
     blocks: Vec<(u32, Vec<u8>)>,
     min_hash: u64,
     max_hash: u64,
+    little_endian_offsets: bool,
+    shared_dictionaries_id: Option<u32>,
+    shared_key_prefixes: bool,
+    header_checksum: bool,
+    /// The filter type of a custom filter, `None` for the AQMF filter.
+    filter_type: Option<u8>,
 }
 
 impl StaticSortedFileBuilder {
@@ -88,16 +130,49 @@ impl StaticSortedFileBuilder {
         entries: &[E],
         total_key_size: usize,
         total_value_size: usize,
+    ) -> Result<Self> {
+        Self::new_with_options(
+            family,
+            entries,
+            total_key_size,
+            total_value_size,
+            &Default::default(),
+        )
+    }
+
+    pub fn new_with_options<E: Entry>(
+        family: u32,
+        entries: &[E],
+        total_key_size: usize,
+        total_value_size: usize,
+        options: &StaticSortedFileBuilderOptions,
     ) -> Result<Self> {
         debug_assert!(entries.iter().map(|e| e.key_hash()).is_sorted());
         let mut builder = Self {
             family,
+            little_endian_offsets: options.little_endian_offsets,
+            shared_key_prefixes: options.shared_key_prefixes,
+            header_checksum: options.header_checksum,
             min_hash: entries.first().map(|e| e.key_hash()).unwrap_or(u64::MAX),
             max_hash: entries.last().map(|e| e.key_hash()).unwrap_or(0),
             ..Default::default()
         };
-        builder.compute_aqmf(entries);
-        builder.compute_compression_dictionary(entries, total_key_size, total_value_size)?;
+        if options.omit_aqmf {
+            // No filter
+        } else if let Some(filter_builder) = &options.filter_builder {
+            let key_hashes = entries.iter().map(|e| e.key_hash()).collect::<Vec<_>>();
+            builder.aqmf = filter_builder.build(&key_hashes)?;
+            builder.filter_type = Some(filter_builder.filter_type());
+        } else {
+            builder.compute_aqmf(entries);
+        }
+        if let Some(dictionaries) = &options.shared_dictionaries {
+            builder.key_compression_dictionary = dictionaries.key.to_vec();
+            builder.value_compression_dictionary = dictionaries.value.to_vec();
+            builder.shared_dictionaries_id = Some(dictionaries.id);
+        } else {
+            builder.compute_compression_dictionary(entries, total_key_size, total_value_size)?;
+        }
         builder.compute_blocks(entries);
         Ok(builder)
     }
@@ -290,6 +365,24 @@ impl StaticSortedFileBuilder {
                 }
             }
         }
+        let sequence_numbers = entries.iter().any(|e| e.write_sequence_number().is_some());
+        let little_endian_offsets = self.little_endian_offsets;
+        let shared_key_prefixes = self.shared_key_prefixes;
+        let new_key_block = |block_entries: &[E]| {
+            let entry_count = block_entries.len() as u32;
+            let mut block = if sequence_numbers {
+                KeyBlockBuilder::with_sequence_numbers(entry_count)
+            } else {
+                KeyBlockBuilder::new(entry_count)
+            };
+            if little_endian_offsets {
+                block = block.with_little_endian_offsets();
+            }
+            if shared_key_prefixes {
+                block = block.with_shared_prefix(&common_key_prefix(block_entries));
+            }
+            block
+        };
         let mut current_block_start = 0;
         let mut current_block_size = 0;
         for (i, entry) in entries.iter().enumerate() {
@@ -300,7 +393,7 @@ impl StaticSortedFileBuilder {
                     // avoid breaking the block in the middle of a hash conflict
                     entries[i - 1].key_hash() != entry.key_hash()
             {
-                let mut block = KeyBlockBuilder::new((i - current_block_start) as u32);
+                let mut block = new_key_block(&entries[current_block_start..i]);
                 for j in current_block_start..i {
                     let entry = &entries[j];
                     let value_location = &value_locations[j];
@@ -315,7 +408,7 @@ impl StaticSortedFileBuilder {
             current_block_size += entry.key_len() + KEY_BLOCK_ENTRY_META_OVERHEAD;
         }
         if current_block_size > 0 {
-            let mut block = KeyBlockBuilder::new((entries.len() - current_block_start) as u32);
+            let mut block = new_key_block(&entries[current_block_start..]);
             for j in current_block_start..entries.len() {
                 let entry = &entries[j];
                 let value_location = &value_locations[j];
@@ -325,6 +418,11 @@ impl StaticSortedFileBuilder {
             self.blocks.push(self.compress_key_block(&block.finish()));
         }
 
+        // A file without entries has no key blocks and no index block
+        if key_block_boundaries.is_empty() {
+            return;
+        }
+
         // Compute the index
         let mut index_block = IndexBlockBuilder::new(
             key_block_boundaries.len() as u16,
@@ -364,52 +462,150 @@ impl StaticSortedFileBuilder {
     /// Writes the SST file.
     pub fn write(&self, file: &Path) -> io::Result<File> {
         let mut file = BufWriter::new(File::create(file)?);
-        // magic number and version
-        file.write_u32::<BE>(0x53535401)?;
+        self.write_to(&mut file)?;
+        Ok(file.into_inner()?)
+    }
+
+    /// Writes the content of the SST file to a writer.
+    pub fn write_to(&self, file: &mut impl Write) -> io::Result<()> {
+        let mut flags = 0;
+        if self.little_endian_offsets {
+            flags |= HEADER_FLAG_LITTLE_ENDIAN_OFFSETS;
+        }
+        if self.shared_dictionaries_id.is_some() {
+            flags |= HEADER_FLAG_SHARED_DICTIONARIES;
+        }
+        if self.shared_key_prefixes {
+            flags |= HEADER_FLAG_SHARED_KEY_PREFIXES;
+        }
+        if self.header_checksum {
+            flags |= HEADER_FLAG_HEADER_CHECKSUM;
+        }
+        if self.filter_type.is_some() {
+            flags |= HEADER_FLAG_FILTER_TYPE;
+        }
+        // Shared dictionaries are not stored in the file
+        let (key_compression_dictionary, value_compression_dictionary) =
+            if self.shared_dictionaries_id.is_some() {
+                (&[][..], &[][..])
+            } else {
+                (
+                    &self.key_compression_dictionary[..],
+                    &self.value_compression_dictionary[..],
+                )
+            };
+        // The header and the block offsets are collected first, since the header checksum covers
+        // them
+        let mut header = Vec::new();
+        // magic number
+        header.write_u24::<BE>(MAGIC)?;
+        // format version, files without flags keep the original format
+        header.write_u8(if flags != 0 {
+            FORMAT_VERSION_2
+        } else {
+            FORMAT_VERSION_1
+        })?;
         // family
-        file.write_u32::<BE>(self.family)?;
+        header.write_u32::<BE>(self.family)?;
         // min hash
-        file.write_u64::<BE>(self.min_hash)?;
+        header.write_u64::<BE>(self.min_hash)?;
         // max hash
-        file.write_u64::<BE>(self.max_hash)?;
+        header.write_u64::<BE>(self.max_hash)?;
         // AQMF length
-        file.write_u24::<BE>(self.aqmf.len().try_into().unwrap())?;
+        header.write_u24::<BE>(self.aqmf.len().try_into().unwrap())?;
         // Key compression dictionary length
-        file.write_u16::<BE>(self.key_compression_dictionary.len().try_into().unwrap())?;
+        header.write_u16::<BE>(key_compression_dictionary.len().try_into().unwrap())?;
         // Value compression dictionary length
-        file.write_u16::<BE>(self.value_compression_dictionary.len().try_into().unwrap())?;
+        header.write_u16::<BE>(value_compression_dictionary.len().try_into().unwrap())?;
         // Number of blocks
-        file.write_u16::<BE>(self.blocks.len().try_into().unwrap())?;
-
-        // Write the AQMF
-        file.write_all(&self.aqmf)?;
-        // Write the key compression dictionary
-        file.write_all(&self.key_compression_dictionary)?;
-        // Write the value compression dictionary
-        file.write_all(&self.value_compression_dictionary)?;
+        header.write_u16::<BE>(self.blocks.len().try_into().unwrap())?;
+        if flags != 0 {
+            // Flags
+            header.write_u8(flags)?;
+        }
+        if let Some(id) = self.shared_dictionaries_id {
+            // Shared dictionaries id
+            header.write_u32::<BE>(id)?;
+        }
+        if let Some(filter_type) = self.filter_type {
+            // Filter type
+            header.write_u8(filter_type)?;
+        }
 
-        // Write the blocks
+        // Compute the block offsets
+        let mut block_offsets = Vec::with_capacity(self.blocks.len() * 4);
         let mut offset = 0;
         for (_, block) in &self.blocks {
             // Block length (including the uncompressed length field)
             let len = block.len() + 4;
             offset += len;
-            file.write_u32::<BE>(offset.try_into().unwrap())?;
+            if self.little_endian_offsets {
+                block_offsets.write_u32::<LE>(offset.try_into().unwrap())?;
+            } else {
+                block_offsets.write_u32::<BE>(offset.try_into().unwrap())?;
+            }
         }
+
+        file.write_all(&header)?;
+        if self.header_checksum {
+            // Header checksum
+            file.write_u64::<BE>(header_checksum(&[
+                &header,
+                &self.aqmf,
+                key_compression_dictionary,
+                value_compression_dictionary,
+                &block_offsets,
+            ]))?;
+        }
+        // Write the AQMF
+        file.write_all(&self.aqmf)?;
+        // Write the key compression dictionary
+        file.write_all(key_compression_dictionary)?;
+        // Write the value compression dictionary
+        file.write_all(value_compression_dictionary)?;
+
+        // Write the blocks
+        file.write_all(&block_offsets)?;
         for (uncompressed_size, block) in &self.blocks {
             // Uncompressed size
             file.write_u32::<BE>(*uncompressed_size)?;
             // Compressed block
             file.write_all(block)?;
         }
-        Ok(file.into_inner()?)
+        Ok(())
+    }
+}
+
+/// Computes the longest common prefix of the keys of the entries. It's limited to the size that a
+/// key block can store.
+fn common_key_prefix<E: Entry>(entries: &[E]) -> Vec<u8> {
+    let mut prefix = Vec::new();
+    let Some((first, rest)) = entries.split_first() else {
+        return prefix;
+    };
+    first.write_key_to(&mut prefix);
+    prefix.truncate(u16::MAX as usize);
+    let mut key = Vec::new();
+    for entry in rest {
+        if prefix.is_empty() {
+            break;
+        }
+        key.clear();
+        entry.write_key_to(&mut key);
+        let common = prefix.iter().zip(&key).take_while(|(a, b)| a == b).count();
+        prefix.truncate(common);
     }
+    prefix
 }
 
 /// Builder for a single key block
 pub struct KeyBlockBuilder {
     current_entry: usize,
+    offsets_start: usize,
     header_size: usize,
+    prefix_len: usize,
+    sequence_numbers: bool,
+    little_endian_offsets: bool,
     data: Vec<u8>,
 }
 
@@ -419,22 +615,71 @@ const KEY_BLOCK_HEADER_SIZE: usize = 4;
 impl KeyBlockBuilder {
     /// Creates a new key block builder for the number of entries.
     pub fn new(entry_count: u32) -> Self {
+        Self::with_block_type(entry_count, BLOCK_TYPE_KEY)
+    }
+
+    /// Creates a new key block builder for the number of entries that stores the write sequence
+    /// number of each entry.
+    pub fn with_sequence_numbers(entry_count: u32) -> Self {
+        Self::with_block_type(entry_count, BLOCK_TYPE_KEY_WITH_SEQUENCE_NUMBERS)
+    }
+
+    fn with_block_type(entry_count: u32, block_type: u8) -> Self {
         debug_assert!(entry_count < (1 << 24));
 
         const ESTIMATED_KEY_SIZE: usize = 16;
         let mut data = Vec::with_capacity(entry_count as usize * ESTIMATED_KEY_SIZE);
-        data.write_u8(BLOCK_TYPE_KEY).unwrap();
+        data.write_u8(block_type).unwrap();
         data.write_u24::<BE>(entry_count).unwrap();
         for _ in 0..entry_count {
             data.write_u32::<BE>(0).unwrap();
         }
         Self {
             current_entry: 0,
+            offsets_start: KEY_BLOCK_HEADER_SIZE,
             header_size: data.len(),
+            prefix_len: 0,
+            sequence_numbers: block_type == BLOCK_TYPE_KEY_WITH_SEQUENCE_NUMBERS,
+            little_endian_offsets: false,
             data,
         }
     }
 
+    /// Stores the entry headers in little endian byte order.
+    pub fn with_little_endian_offsets(mut self) -> Self {
+        self.little_endian_offsets = true;
+        self
+    }
+
+    /// Stores `prefix` once in the block. All keys of the block must start with it and only the
+    /// remaining suffix is stored per entry. Must be called before any entries are added.
+    pub fn with_shared_prefix(mut self, prefix: &[u8]) -> Self {
+        debug_assert_eq!(self.current_entry, 0);
+        let offsets = self.data.split_off(KEY_BLOCK_HEADER_SIZE);
+        self.data
+            .write_u16::<BE>(prefix.len().try_into().unwrap())
+            .unwrap();
+        self.data.extend_from_slice(prefix);
+        self.offsets_start = self.data.len();
+        self.data.extend_from_slice(&offsets);
+        self.header_size = self.data.len();
+        self.prefix_len = prefix.len();
+        self
+    }
+
+    /// Writes the header of the current entry, which points to the end of the buffer.
+    fn write_entry_header(&mut self, ty: u8) {
+        let pos = self.data.len() - self.header_size;
+        let header_offset = self.offsets_start + self.current_entry * 4;
+        let header = (pos as u32) | ((ty as u32) << 24);
+        let buf = &mut self.data[header_offset..header_offset + 4];
+        if self.little_endian_offsets {
+            LE::write_u32(buf, header);
+        } else {
+            BE::write_u32(buf, header);
+        }
+    }
+
     /// Writes a small-sized value to the buffer.
     pub fn put_small<E: Entry>(
         &mut self,
@@ -443,61 +688,71 @@ impl KeyBlockBuilder {
         value_offset: u32,
         value_size: u16,
     ) {
-        let pos = self.data.len() - self.header_size;
-        let header_offset = KEY_BLOCK_HEADER_SIZE + self.current_entry * 4;
-        let header = (pos as u32) | ((KEY_BLOCK_ENTRY_TYPE_SMALL as u32) << 24);
-        BE::write_u32(&mut self.data[header_offset..header_offset + 4], header);
+        self.write_entry_header(KEY_BLOCK_ENTRY_TYPE_SMALL);
 
         self.data.write_u64::<BE>(entry.key_hash()).unwrap();
-        entry.write_key_to(&mut self.data);
+        self.write_key(entry);
         self.data.write_u16::<BE>(value_block).unwrap();
         self.data.write_u16::<BE>(value_size).unwrap();
         self.data.write_u32::<BE>(value_offset).unwrap();
+        self.write_sequence_number(entry);
 
         self.current_entry += 1;
     }
 
     /// Writes a medium-sized value to the buffer.
     pub fn put_medium<E: Entry>(&mut self, entry: &E, value_block: u16) {
-        let pos = self.data.len() - self.header_size;
-        let header_offset = KEY_BLOCK_HEADER_SIZE + self.current_entry * 4;
-        let header = (pos as u32) | ((KEY_BLOCK_ENTRY_TYPE_MEDIUM as u32) << 24);
-        BE::write_u32(&mut self.data[header_offset..header_offset + 4], header);
+        self.write_entry_header(KEY_BLOCK_ENTRY_TYPE_MEDIUM);
 
         self.data.write_u64::<BE>(entry.key_hash()).unwrap();
-        entry.write_key_to(&mut self.data);
+        self.write_key(entry);
         self.data.write_u16::<BE>(value_block).unwrap();
+        self.write_sequence_number(entry);
 
         self.current_entry += 1;
     }
 
     /// Writes a tombstone to the buffer.
     pub fn delete<E: Entry>(&mut self, entry: &E) {
-        let pos = self.data.len() - self.header_size;
-        let header_offset = KEY_BLOCK_HEADER_SIZE + self.current_entry * 4;
-        let header = (pos as u32) | ((KEY_BLOCK_ENTRY_TYPE_DELETED as u32) << 24);
-        BE::write_u32(&mut self.data[header_offset..header_offset + 4], header);
+        self.write_entry_header(KEY_BLOCK_ENTRY_TYPE_DELETED);
 
         self.data.write_u64::<BE>(entry.key_hash()).unwrap();
-        entry.write_key_to(&mut self.data);
+        self.write_key(entry);
+        self.write_sequence_number(entry);
 
         self.current_entry += 1;
     }
 
     /// Writes a blob value to the buffer.
     pub fn put_blob<E: Entry>(&mut self, entry: &E, blob: u32) {
-        let pos = self.data.len() - self.header_size;
-        let header_offset = KEY_BLOCK_HEADER_SIZE + self.current_entry * 4;
-        let header = (pos as u32) | ((KEY_BLOCK_ENTRY_TYPE_BLOB as u32) << 24);
-        BE::write_u32(&mut self.data[header_offset..header_offset + 4], header);
+        self.write_entry_header(KEY_BLOCK_ENTRY_TYPE_BLOB);
 
         self.data.write_u64::<BE>(entry.key_hash()).unwrap();
-        entry.write_key_to(&mut self.data);
+        self.write_key(entry);
         self.data.write_u32::<BE>(blob).unwrap();
+        self.write_sequence_number(entry);
 
         self.current_entry += 1;
     }
 
+    /// Writes the key of an entry to the buffer without the shared prefix of the block.
+    fn write_key<E: Entry>(&mut self, entry: &E) {
+        let start = self.data.len();
+        entry.write_key_to(&mut self.data);
+        if self.prefix_len > 0 {
+            self.data.drain(start..start + self.prefix_len);
+        }
+    }
+
+    /// Writes the write sequence number of an entry to the buffer if the block stores them.
+    fn write_sequence_number<E: Entry>(&mut self, entry: &E) {
+        if self.sequence_numbers {
+            // Entries without a sequence number are visible to all reads
+            let sequence_number = entry.write_sequence_number().unwrap_or(0);
+            self.data.write_u32::<BE>(sequence_number).unwrap();
+        }
+    }
+
     /// Returns the key block buffer
     pub fn finish(self) -> Vec<u8> {
         self.data
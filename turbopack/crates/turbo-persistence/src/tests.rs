@@ -448,3 +448,44 @@ fn persist_changes() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn get_as_of() -> Result<()> {
+    let tempdir = tempfile::tempdir()?;
+    let db = TurboPersistence::open(tempdir.path().to_path_buf())?;
+
+    let b = db.write_batch::<Vec<u8>, 1>()?;
+    for i in 0..100u8 {
+        b.put_with_sequence_number(0, vec![i], vec![1].into(), 1)?;
+    }
+    db.commit_write_batch(b)?;
+    let b = db.write_batch::<Vec<u8>, 1>()?;
+    for i in 0..50u8 {
+        b.put_with_sequence_number(0, vec![i], vec![2].into(), 2)?;
+    }
+    b.put_with_sequence_number(0, vec![200], vec![2].into(), 2)?;
+    b.delete_with_sequence_number(0, vec![99], 2)?;
+    b.put(0, vec![201], vec![0].into())?;
+    db.commit_write_batch(b)?;
+
+    assert_eq!(db.get(0, &[10u8])?.as_deref(), Some(&[2][..]));
+    assert_eq!(db.get_as_of(0, &[10u8], 2)?.as_deref(), Some(&[2][..]));
+    assert_eq!(db.get_as_of(0, &[10u8], 1)?.as_deref(), Some(&[1][..]));
+    assert_eq!(db.get_as_of(0, &[60u8], 1)?.as_deref(), Some(&[1][..]));
+    assert_eq!(db.get(0, &[99u8])?, None);
+    assert_eq!(db.get_as_of(0, &[99u8], 1)?.as_deref(), Some(&[1][..]));
+    assert_eq!(db.get_as_of(0, &[200u8], 1)?, None);
+    assert_eq!(db.get_as_of(0, &[200u8], 2)?.as_deref(), Some(&[2][..]));
+    // Entries without a sequence number are always visible.
+    assert_eq!(db.get_as_of(0, &[201u8], 0)?.as_deref(), Some(&[0][..]));
+
+    // Compaction only keeps the latest entry of a key, but with its sequence number.
+    db.full_compact()?;
+    assert_eq!(db.get_as_of(0, &[10u8], 2)?.as_deref(), Some(&[2][..]));
+    assert_eq!(db.get_as_of(0, &[60u8], 1)?.as_deref(), Some(&[1][..]));
+    assert_eq!(db.get_as_of(0, &[200u8], 1)?, None);
+    assert_eq!(db.get_as_of(0, &[201u8], 0)?.as_deref(), Some(&[0][..]));
+
+    db.shutdown()?;
+    Ok(())
+}
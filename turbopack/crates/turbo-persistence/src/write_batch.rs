@@ -108,13 +108,36 @@ impl<K: StoreKey + Send + Sync, const FAMILIES: usize> WriteBatch<K, FAMILIES> {
 
     /// Puts a key-value pair into the write batch.
     pub fn put(&self, family: usize, key: K, value: Cow<'_, [u8]>) -> Result<()> {
+        self.put_internal(family, key, value, None)
+    }
+
+    /// Puts a key-value pair into the write batch and stores the sequence number of the write
+    /// with it. [`TurboPersistence::get_as_of`](crate::TurboPersistence::get_as_of) doesn't see
+    /// the entry when reading as of an earlier write.
+    pub fn put_with_sequence_number(
+        &self,
+        family: usize,
+        key: K,
+        value: Cow<'_, [u8]>,
+        write_sequence_number: u32,
+    ) -> Result<()> {
+        self.put_internal(family, key, value, Some(write_sequence_number))
+    }
+
+    fn put_internal(
+        &self,
+        family: usize,
+        key: K,
+        value: Cow<'_, [u8]>,
+        write_sequence_number: Option<u32>,
+    ) -> Result<()> {
         let state = self.thread_local_state();
         let collector = self.collector_mut(state, family)?;
         if value.len() <= MAX_MEDIUM_VALUE_SIZE {
-            collector.put(key, value.into_owned());
+            collector.put(key, value.into_owned(), write_sequence_number);
         } else {
             let (blob, file) = self.create_blob(&value)?;
-            collector.put_blob(key, blob);
+            collector.put_blob(key, blob, write_sequence_number);
             state.new_blob_files.push(file);
         }
         Ok(())
@@ -122,9 +145,29 @@ impl<K: StoreKey + Send + Sync, const FAMILIES: usize> WriteBatch<K, FAMILIES> {
 
     /// Puts a delete operation into the write batch.
     pub fn delete(&self, family: usize, key: K) -> Result<()> {
+        self.delete_internal(family, key, None)
+    }
+
+    /// Puts a delete operation into the write batch and stores the sequence number of the write
+    /// with it, see [`WriteBatch::put_with_sequence_number`].
+    pub fn delete_with_sequence_number(
+        &self,
+        family: usize,
+        key: K,
+        write_sequence_number: u32,
+    ) -> Result<()> {
+        self.delete_internal(family, key, Some(write_sequence_number))
+    }
+
+    fn delete_internal(
+        &self,
+        family: usize,
+        key: K,
+        write_sequence_number: Option<u32>,
+    ) -> Result<()> {
         let state = self.thread_local_state();
         let collector = self.collector_mut(state, family)?;
-        collector.delete(key);
+        collector.delete(key, write_sequence_number);
         Ok(())
     }
 